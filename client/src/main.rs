@@ -12,11 +12,14 @@ async fn main() -> anyhow::Result<()> {
     // let result = client.get("abhi").await?;
     // info!("{:?}", result);
 
-    let mut client = ClusterClient::new(&[
-        ("127.0.0.1", 11211),
-        ("127.0.0.2", 11211),
-        ("127.0.0.3", 11211),
-    ]);
+    let client = ClusterClient::new(
+        &[
+            ("127.0.0.1", 11211),
+            ("127.0.0.2", 11211),
+            ("127.0.0.3", 11211),
+        ],
+        1,
+    );
     client.set("abhi", 0, 200, "rust").await?;
     client.set("lilb", 0, 200, ".net").await?;
     client.set("pads", 0, 200, "react").await?;
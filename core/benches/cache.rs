@@ -0,0 +1,125 @@
+//! `LruCache` throughput, on one thread & several sharing the cache.
+//!
+//! Run with `cargo bench -p core --bench cache`.
+
+use bytes::Bytes;
+use core::cache::{LruCache, ShardedLruCache, Store};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+use std::thread;
+
+/// how many keys the cache holds, so the inserts past it evict
+const CAPACITY: usize = 10_000;
+/// how many distinct keys the benches go through
+const KEYS: usize = 20_000;
+/// how many threads share the cache in the multi-threaded benches
+const THREADS: usize = 4;
+/// how many operations each thread carries out per iteration of the multi-threaded benches
+const OPS_PER_THREAD: usize = 1000;
+
+/// value sizes benched: small ones like counters & large ones like rendered pages
+const VALUE_SIZES: [usize; 2] = [16, 4096];
+
+fn keys() -> Vec<String> {
+    (0..KEYS).map(|i| format!("key{}", i)).collect()
+}
+
+/// creates a full cache of values of `size` bytes
+fn filled(keys: &[String], size: usize) -> LruCache<String, Bytes> {
+    let cache = LruCache::new(CAPACITY);
+    let value = Bytes::from(vec![b'x'; size]);
+    for key in &keys[..CAPACITY] {
+        cache.insert(key.clone(), value.clone());
+    }
+    cache
+}
+
+fn single_threaded(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("lru");
+    group.throughput(Throughput::Elements(1));
+    for size in VALUE_SIZES {
+        let value = Bytes::from(vec![b'x'; size]);
+        let cache = filled(&keys, size);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::new("insert", size), &size, |b, _| {
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                black_box(cache.insert(keys[i].clone(), value.clone()));
+            })
+        });
+
+        let cache = filled(&keys, size);
+        group.bench_with_input(BenchmarkId::new("get", size), &size, |b, _| {
+            // half the keys were never stored so half the gets miss
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                black_box(cache.get(&keys[i]));
+            })
+        });
+
+        let cache = filled(&keys, size);
+        group.bench_with_input(BenchmarkId::new("remove", size), &size, |b, _| {
+            // puts back every key removed so there's always something to remove
+            b.iter(|| {
+                i = (i + 1) % CAPACITY;
+                let removed = cache.remove(&keys[i]);
+                cache.insert(keys[i].clone(), black_box(removed).unwrap_or(value.clone()));
+            })
+        });
+    }
+    group.finish();
+}
+
+/// runs `THREADS` threads against `cache` at once, every one doing 4 gets for each set, each
+/// starting at a different key
+fn mixed(cache: &(impl Store<String, Bytes> + Sync), keys: &[String], value: &Bytes) {
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for op in 0..OPS_PER_THREAD {
+                    let key = &keys[(t * OPS_PER_THREAD + op * 7) % KEYS];
+                    if op % 5 == 0 {
+                        black_box(cache.insert(key.clone(), value.clone()));
+                    } else {
+                        black_box(cache.get(key));
+                    }
+                }
+            });
+        }
+    })
+}
+
+fn multi_threaded(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("lru_threaded");
+    group.throughput(Throughput::Elements((THREADS * OPS_PER_THREAD) as u64));
+    for size in VALUE_SIZES {
+        let value = Bytes::from(vec![b'x'; size]);
+        let cache = filled(&keys, size);
+        group.bench_with_input(BenchmarkId::new("mixed", size), &size, |b, _| {
+            b.iter(|| mixed(&cache, &keys, &value))
+        });
+    }
+    group.finish();
+}
+
+fn sharded(c: &mut Criterion) {
+    let keys = keys();
+    let value = Bytes::from(vec![b'x'; VALUE_SIZES[0]]);
+    let mut group = c.benchmark_group("sharded_lru_threaded");
+    group.throughput(Throughput::Elements((THREADS * OPS_PER_THREAD) as u64));
+    for shards in [1, 2, 4, 8, 16] {
+        let cache = ShardedLruCache::new(CAPACITY, shards);
+        for key in &keys[..CAPACITY] {
+            cache.insert(key.clone(), value.clone());
+        }
+        group.bench_with_input(BenchmarkId::new("mixed", shards), &shards, |b, _| {
+            b.iter(|| mixed(&cache, &keys, &value))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, single_threaded, multi_threaded, sharded);
+criterion_main!(benches);
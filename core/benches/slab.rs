@@ -0,0 +1,60 @@
+//! Counts the heap allocations made storing values in a full cache, with & without slabs.
+//!
+//! Run with `cargo bench -p core --bench slab`.
+
+use bytes::Bytes;
+use core::cache::LruCache;
+use core::slab::SlabAllocator;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// the system allocator, counting every allocation
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const CAPACITY: usize = 1000;
+const SETS: usize = 100_000;
+
+/// sets `SETS` values of varying sizes into a cache holding `CAPACITY`, copying each one with
+/// `copy`, & prints the allocations made
+fn churn(name: &str, copy: impl Fn(&[u8]) -> Bytes) {
+    let cache = LruCache::new(CAPACITY);
+    let values: Vec<_> = (0..64).map(|i| vec![b'x'; 32 + i * 50]).collect();
+    let keys: Vec<_> = (0..SETS).map(|i| i % (CAPACITY * 4)).collect();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for (i, key) in keys.into_iter().enumerate() {
+        cache.insert(key, copy(&values[i % values.len()]));
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>8} allocations {:>10.2?}",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        elapsed
+    );
+}
+
+fn main() {
+    churn("system", Bytes::copy_from_slice);
+    let slabs = SlabAllocator::default();
+    churn("slabs", |data| slabs.alloc(data));
+    println!("{:?}", slabs.stats());
+}
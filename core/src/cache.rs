@@ -1,27 +1,74 @@
+use crate::eviction::EvictionPolicy;
+use bytes::Bytes;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+type Link<K, V> = Option<Arc<RwLock<Node<K, V>>>>;
+
+/// called with each entry an `LruCache` evicts to make room, shared by a sharded cache's segments
+type OnEvict<K, V> = Arc<Mutex<Box<dyn FnMut(&K, &V) + Send + Sync>>>;
 
 struct Node<K, V> {
     k: K,
     v: V,
+    /// when the entry expires, if ever
+    deadline: Option<Instant>,
     next: Option<Arc<RwLock<Node<K, V>>>>,
     prev: Option<Arc<RwLock<Node<K, V>>>>,
 }
 
 impl<K, V> Node<K, V> {
-    fn new(k: K, v: V) -> Self {
+    fn new(k: K, v: V, deadline: Option<Instant>) -> Self {
         Self {
             k,
             v,
+            deadline,
             next: None,
             prev: None,
         }
     }
+
+    /// checks if the entry has passed its deadline
+    fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+/// counts the reads of a store that found an entry & those that didn't
+#[derive(Default)]
+struct Lookups {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Lookups {
+    /// counts `v` as a hit if it's there, a miss otherwise, & passes it on
+    fn count<V>(&self, v: Option<V>) -> Option<V> {
+        match v {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        v
+    }
+
+    fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
 }
 
+/// default number of buckets in a `ConcurrentHashMap`
+const DEFAULT_BUCKETS: usize = 16;
+
 /// A thread-safe hash-map that uses lock striping.
 ///
 /// Uses fixed sized buckets list.
@@ -32,39 +79,74 @@ struct ConcurrentHashMap<K, V> {
 impl<K: Hash + Eq, V: Clone> ConcurrentHashMap<K, V> {
     /// returns a new `ConcurrentHashMap` with 16 buckets of hash-maps
     pub fn new() -> Self {
+        Self::with_buckets(DEFAULT_BUCKETS)
+    }
+
+    /// returns a new `ConcurrentHashMap` with `n` buckets of hash-maps, each with its own lock
+    pub fn with_buckets(n: usize) -> Self {
+        assert!(n > 0, "a ConcurrentHashMap needs at least one bucket");
         Self {
-            buckets: vec![Arc::new(RwLock::new(HashMap::new())); 16],
+            buckets: (0..n)
+                .map(|_| Arc::new(RwLock::new(HashMap::new())))
+                .collect(),
         }
     }
 
     /// gets the bucket (hash-map) where key `k` should be inserted
-    pub fn get_bucket(k: &K) -> u64 {
+    pub fn get_bucket(&self, k: &K) -> u64 {
         let mut hasher = std::collections::hash_map::DefaultHasher::new();
         k.hash(&mut hasher);
-        let b = hasher.finish() % 16;
-        b
+        hasher.finish() % self.buckets.len() as u64
     }
 
-    pub fn insert(&self, k: K, v: V) {
-        let b = Self::get_bucket(&k);
-        self.buckets[b as usize].write().unwrap().insert(k, v);
+    /// inserts `v` against `k`, returning the value it replaced
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        let b = self.get_bucket(&k);
+        self.buckets[b as usize].write().unwrap().insert(k, v)
     }
 
     pub fn get(&self, k: &K) -> Option<V> {
-        let b = Self::get_bucket(&k);
+        let b = self.get_bucket(k);
         let g = self.buckets[b as usize].read().unwrap();
-        let v = g.get(&k);
+        let v = g.get(k);
         v.cloned() // should we return a ref or a clone?
     }
 
     pub fn remove(&self, k: &K) -> Option<V> {
-        let b = Self::get_bucket(&k);
-        self.buckets[b as usize].write().unwrap().remove(&k)
+        let b = self.get_bucket(k);
+        self.buckets[b as usize].write().unwrap().remove(k)
+    }
+
+    /// locks the bucket holding key `k` for writing
+    pub fn lock_bucket(&self, k: &K) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        let b = self.get_bucket(k);
+        self.buckets[b as usize].write().unwrap()
     }
 
     pub fn contains_key(&self, k: &K) -> bool {
-        let b = Self::get_bucket(&k);
-        self.buckets[b as usize].read().unwrap().contains_key(&k)
+        let b = self.get_bucket(k);
+        self.buckets[b as usize].read().unwrap().contains_key(k)
+    }
+
+    /// returns all the keys, bucket by bucket
+    pub fn keys(&self) -> Vec<K>
+    where
+        K: Clone,
+    {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.read().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// removes all the entries & returns them, locking every bucket first so none is seen
+    /// emptied before the rest
+    pub fn clear(&self) -> Vec<(K, V)> {
+        let mut buckets: Vec<_> = self.buckets.iter().map(|b| b.write().unwrap()).collect();
+        buckets
+            .iter_mut()
+            .flat_map(|bucket| bucket.drain())
+            .collect()
     }
 }
 
@@ -94,301 +176,2069 @@ impl<K: Eq + Hash + Clone, V: Debug + Clone> ConcurrentLL<K, V> {
     }
 }
 
-pub struct LruCache<K, V> {
-    m: ConcurrentHashMap<K, Option<Arc<RwLock<Node<K, V>>>>>,
-    ll: ConcurrentLL<K, V>,
-    th: usize,
+/// values that can report how many bytes they take up
+pub trait ByteSized {
+    /// returns the size of the value in bytes
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSized for String {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSized for Vec<u8> {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSized for Bytes {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A cache the server can store its items in.
+pub trait Store<K, V> {
+    /// inserts `v` against `k`, returning the entries evicted to make room
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)>;
+
+    /// gets the value for the key `k` if present, marking it as recently used
+    fn get(&self, k: &K) -> Option<V>;
+
+    /// gets the value for the key `k` if present without marking it as recently used
+    fn peek(&self, k: &K) -> Option<V>;
+
+    /// removes the entry for the key `k`, returning its value
+    fn remove(&self, k: &K) -> Option<V>;
+
+    /// returns all the keys
+    fn keys(&self) -> Vec<K>;
+
+    /// removes all the entries, returning them
+    fn clear(&self) -> Vec<(K, V)>;
+
+    /// returns the number of entries
+    fn len(&self) -> usize;
+
+    /// returns `true` if there are no entries
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// returns the number of entries evicted to make room so far
+    fn evictions(&self) -> u64 {
+        0
+    }
+
+    /// returns the number of `get`s that found an entry so far
+    fn hits(&self) -> u64 {
+        0
+    }
+
+    /// returns the number of `get`s that found no entry so far, expired ones included
+    fn misses(&self) -> u64 {
+        0
+    }
+
+    /// changes the number of entries kept to `capacity`, evicting entries right away until they
+    /// fit & returning them
+    ///
+    /// `None` if the store has no limit on its entries to change
+    fn set_capacity(&self, _capacity: usize) -> Option<Vec<(K, V)>> {
+        None
+    }
+
+    /// runs `f` on the value for the key `k`, `None` if it's absent, & then does what `f` returns
+    /// with the entry, with no other change to the entry landing in between; returns the entries
+    /// evicted to make room
+    ///
+    /// `f` is called exactly once, while the store holds the locks keeping the entry to it, so it
+    /// must not use the store itself
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)>;
+}
+
+impl<K, V, S: Store<K, V> + ?Sized> Store<K, V> for Box<S> {
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        (**self).insert(k, v)
+    }
+
+    fn get(&self, k: &K) -> Option<V> {
+        (**self).get(k)
+    }
+
+    fn peek(&self, k: &K) -> Option<V> {
+        (**self).peek(k)
+    }
+
+    fn remove(&self, k: &K) -> Option<V> {
+        (**self).remove(k)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        (**self).keys()
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        (**self).clear()
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn evictions(&self) -> u64 {
+        (**self).evictions()
+    }
+
+    fn hits(&self) -> u64 {
+        (**self).hits()
+    }
+
+    fn misses(&self) -> u64 {
+        (**self).misses()
+    }
+
+    fn set_capacity(&self, capacity: usize) -> Option<Vec<(K, V)>> {
+        (**self).set_capacity(capacity)
+    }
+
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        (**self).with_entry(k, f)
+    }
+}
+
+/// A thread-safe cache that never evicts anything.
+///
+/// Meant for when something other than the cache bounds how much is stored.
+pub struct UnboundedCache<K, V> {
+    m: ConcurrentHashMap<K, V>,
     len: AtomicUsize,
-    insert_lock: Mutex<()>,
+    lookups: Lookups,
 }
 
-impl<K: Eq + Hash + Clone, V: Debug + Clone> LruCache<K, V> {
-    /// creates a new `LruCache` with the given threshold `th`
-    pub fn new(th: usize) -> Self {
+impl<K: Hash + Eq, V: Clone> UnboundedCache<K, V> {
+    /// creates a new empty `UnboundedCache`
+    pub fn new() -> Self {
         Self {
             m: ConcurrentHashMap::new(),
-            ll: ConcurrentLL::new(),
-            th,
             len: AtomicUsize::new(0),
-            insert_lock: Mutex::new(()),
+            lookups: Lookups::default(),
         }
     }
+}
 
-    /// inserts value in `LruCache`, evicting lru entry if necessary
-    pub fn insert(&self, k: K, v: V) {
-        let new_node = Some(Arc::new(RwLock::new(Node::new(k.clone(), v))));
+impl<K: Hash + Eq, V: Clone> Default for UnboundedCache<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let _guard = self.insert_lock.lock().unwrap();
+impl<K: Hash + Eq + Clone, V: Clone> Store<K, V> for UnboundedCache<K, V> {
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        if self.m.insert(k, v).is_none() {
+            self.len.fetch_add(1, Ordering::Release);
+        }
+        vec![]
+    }
 
-        // list empty
-        if self.ll.inner.read().unwrap().head.is_none() {
-            self.ll.inner.write().unwrap().head = new_node.clone();
-            let head = self.ll.inner.read().unwrap().head.clone();
-            self.ll.inner.write().unwrap().tail = head;
-        } else {
-            // check if threshold reached; evict head (lru)
-            if self.len() == self.th {
-                let head_k = self
-                    .ll
-                    .inner
-                    .read()
-                    .unwrap()
-                    .head
-                    .clone()
-                    .unwrap()
-                    .read()
-                    .unwrap()
-                    .k
-                    .clone();
-                // set head to next node
-                let head_next = self
-                    .ll
-                    .inner
-                    .read()
-                    .unwrap()
-                    .head
-                    .clone()
-                    .unwrap()
-                    .read()
-                    .unwrap()
-                    .next
-                    .clone();
-                self.ll.inner.write().unwrap().head = head_next;
-                // remove from map
-                self.m.remove(&head_k);
-                self.len.fetch_sub(1, Ordering::Release);
-            }
-            self.ll
-                .inner
-                .write()
-                .unwrap()
-                .tail
-                .clone()
-                .unwrap()
-                .write()
-                .unwrap()
-                .next = new_node.clone();
-            new_node.clone().unwrap().write().unwrap().prev =
-                self.ll.inner.read().unwrap().tail.clone();
-            self.ll.inner.write().unwrap().tail = new_node.clone();
-        }
-
-        self.m.insert(k, new_node.clone());
-        // todo abhi: check the ordering
-        self.len.fetch_add(1, Ordering::Release);
+    fn get(&self, k: &K) -> Option<V> {
+        self.lookups.count(self.m.get(k))
     }
 
-    pub fn remove(&self, k: &K) -> Option<V>
-    where
-        K: Eq + Hash,
-    {
-        if let Some(n) = self.remove_internal(&k) {
-            match Arc::try_unwrap(n) {
-                Ok(n) => {
-                    let n = n.into_inner();
-                    Some(n.unwrap().v)
+    fn peek(&self, k: &K) -> Option<V> {
+        self.m.get(k)
+    }
+
+    fn remove(&self, k: &K) -> Option<V> {
+        let v = self.m.remove(k)?;
+        self.len.fetch_sub(1, Ordering::Release);
+        Some(v)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.m.keys()
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        // an insert landing while the buckets are cleared is counted & kept
+        let cleared = self.m.clear();
+        self.len.fetch_sub(cleared.len(), Ordering::Release);
+        cleared
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    fn hits(&self) -> u64 {
+        self.lookups.hits()
+    }
+
+    fn misses(&self) -> u64 {
+        self.lookups.misses()
+    }
+
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        let mut bucket = self.m.lock_bucket(&k);
+        match bucket.entry(k) {
+            Entry::Occupied(mut entry) => match f(Some(entry.get_mut())) {
+                Action::Keep => {}
+                Action::Insert(v) => {
+                    entry.insert(v);
                 }
-                Err(_) => {
-                    panic!("this shouldn't happen");
+                Action::Remove => {
+                    entry.remove();
+                    self.len.fetch_sub(1, Ordering::Release);
+                }
+            },
+            Entry::Vacant(entry) => {
+                if let Action::Insert(v) = f(None) {
+                    entry.insert(v);
+                    self.len.fetch_add(1, Ordering::Release);
                 }
             }
-        } else {
-            None
         }
+        vec![]
     }
+}
 
-    /// removes an entry from the `LruCache`
-    fn remove_internal(&self, k: &K) -> Option<Arc<RwLock<Node<K, V>>>>
-    where
-        K: Eq + Hash,
-    {
-        if self.m.contains_key(&k) {
-            let node = self.m.get(&k).unwrap().clone();
-            self.m.remove(&k);
-
-            // todo abhi: check the ordering
-            self.len.fetch_sub(1, Ordering::Release);
-
-            // tail node; del only prev & set tail to prev node
-            if node.clone().unwrap().read().unwrap().next.is_none() {
-                // set tail to prev node
-                let mut inner = self.ll.inner.write().unwrap();
-                if let Some(node_arc) = node.clone() {
-                    let prev = node_arc.read().unwrap().prev.clone();
-                    inner.tail = prev.clone();
-
-                    if let Some(prev_arc) = prev {
-                        prev_arc.write().unwrap().next = None;
-                    }
-                }
-            } else if node.clone().unwrap().read().unwrap().prev.is_none() {
-                // head node; remove next node's prev link, set head to next node
-                self.ll.inner.write().unwrap().head =
-                    node.clone().unwrap().read().unwrap().next.clone();
-                self.ll
-                    .inner
-                    .write()
-                    .unwrap()
-                    .head
-                    .clone()
-                    .unwrap()
-                    .write()
-                    .unwrap()
-                    .prev = None;
-                node.clone().unwrap().write().unwrap().next = None;
-            } else {
-                // intermediate node; adjust prev & next pointers
-                let prev = node.clone().unwrap().read().unwrap().prev.clone();
-                let next = node.clone().unwrap().read().unwrap().next.clone();
-                prev.clone().unwrap().write().unwrap().next = next.clone();
-                next.clone().unwrap().write().unwrap().prev = prev.clone();
-            }
+/// A thread-safe cache that leaves the choice of what to evict to an `EvictionPolicy`.
+pub struct EvictingCache<K, V, P> {
+    m: ConcurrentHashMap<K, V>,
+    /// also serializes inserts so the cache never goes over its limits
+    policy: Mutex<P>,
+    th: AtomicUsize,
+    len: AtomicUsize,
+    /// total size of the values; always 0 unless the cache is bounded by bytes
+    bytes: AtomicUsize,
+    max_bytes: usize,
+    size_of: fn(&V) -> usize,
+    evictions: AtomicU64,
+    lookups: Lookups,
+}
 
-            return node;
+impl<K: Hash + Eq + Clone, V: Clone + ByteSized, P: EvictionPolicy<K>> EvictingCache<K, V, P> {
+    /// creates a new `EvictingCache` that evicts entries as per `policy` once the values take
+    /// up more than `max_bytes`, however many entries there are
+    pub fn with_max_bytes(max_bytes: usize, policy: P) -> Self {
+        Self {
+            max_bytes,
+            size_of: V::byte_size,
+            ..Self::new(usize::MAX, policy)
         }
-        None
     }
+}
 
-    /// prints all entries in the `LruCache`
-    fn print(&self) {
-        let mut p = self.ll.inner.read().unwrap().head.clone();
-        while let Some(n) = p {
-            println!("{:?} ", n.read().unwrap().v);
-            p = n.read().unwrap().next.clone();
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> EvictingCache<K, V, P> {
+    /// creates a new `EvictingCache` with the given threshold `th`, evicting entries as per
+    /// `policy` once it is reached
+    pub fn new(th: usize, policy: P) -> Self {
+        Self {
+            m: ConcurrentHashMap::new(),
+            policy: Mutex::new(policy),
+            th: AtomicUsize::new(th),
+            len: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_bytes: usize::MAX,
+            size_of: |_| 0,
+            evictions: AtomicU64::new(0),
+            lookups: Lookups::default(),
         }
     }
 
-    /// gets the value for the key `k` if present
-    pub fn get(&self, k: &K) -> Option<V> {
-        if self.m.contains_key(&k) {
-            let node = self.remove_internal(&k)?;
-            let mut inner = self.ll.inner.write().unwrap();
-            if let Some(tail) = inner.tail.clone() {
-                tail.write().unwrap().next = Some(node.clone());
+    /// evicts the entry picked by `policy`, passing over keys it picks that are no longer stored
+    fn evict(&self, policy: &mut P) -> Option<(K, V)> {
+        loop {
+            let k = policy.victim()?;
+            if let Some(v) = self.m.remove(&k) {
+                self.len.fetch_sub(1, Ordering::Release);
+                self.bytes.fetch_sub((self.size_of)(&v), Ordering::Release);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                return Some((k, v));
             }
+        }
+    }
 
-            inner.tail = Some(node.clone());
+    /// returns the total size of the values if the `EvictingCache` is bounded by bytes, 0
+    /// otherwise
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+}
 
-            Some(node.read().unwrap().v.clone())
-        } else {
-            None
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> EvictingCache<K, V, P> {
+    /// inserts `v` against `k` with the policy lock held
+    fn insert_locked(&self, policy: &mut P, k: K, v: V) -> Vec<(K, V)> {
+        let mut evicted = vec![];
+
+        let mut replaced = self.m.get(&k).map(|prev| (self.size_of)(&prev));
+        if replaced.is_none() && self.len() >= self.th.load(Ordering::Acquire) {
+            evicted.extend(self.evict(policy));
+        }
+
+        // make room beforehand so the new entry is never the one evicted; it is kept even if it
+        // alone exceeds the limit
+        let size = (self.size_of)(&v);
+        while self.bytes() - replaced.unwrap_or(0) + size > self.max_bytes && self.len() > 0 {
+            match self.evict(policy) {
+                // the value being replaced; it is going anyway
+                Some((victim, _)) if victim == k => replaced = None,
+                Some(entry) => evicted.push(entry),
+                None => break,
+            }
+        }
+
+        match self.m.insert(k.clone(), v) {
+            Some(prev) => {
+                self.bytes
+                    .fetch_sub((self.size_of)(&prev), Ordering::Release);
+            }
+            None => {
+                self.len.fetch_add(1, Ordering::Release);
+            }
         }
+        self.bytes.fetch_add(size, Ordering::Release);
+        policy.on_insert(&k);
+
+        evicted
     }
 
-    #[inline(always)]
-    fn head(&self) -> V {
-        self.ll
-            .inner
-            .read()
-            .unwrap()
-            .head
-            .clone()
-            .unwrap()
-            .read()
-            .unwrap()
-            .v
-            .clone()
+    /// removes the entry for the key `k` with the policy lock held
+    fn remove_locked(&self, policy: &mut P, k: &K) -> Option<V> {
+        let v = self.m.remove(k)?;
+        policy.on_remove(k);
+        self.len.fetch_sub(1, Ordering::Release);
+        self.bytes.fetch_sub((self.size_of)(&v), Ordering::Release);
+        Some(v)
     }
+}
 
-    #[inline(always)]
-    fn tail(&self) -> V {
-        self.ll
-            .inner
-            .read()
-            .unwrap()
-            .tail
-            .clone()
-            .unwrap()
-            .read()
-            .unwrap()
-            .v
-            .clone()
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> Store<K, V> for EvictingCache<K, V, P> {
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        let mut policy = self.policy.lock().unwrap();
+        self.insert_locked(&mut policy, k, v)
     }
 
-    #[inline(always)]
-    fn len(&self) -> usize {
-        self.len.load(Ordering::Acquire)
+    fn get(&self, k: &K) -> Option<V> {
+        // a remove landing between the lookup & the access would leave the policy tracking a key
+        // that's gone
+        let mut policy = self.policy.lock().unwrap();
+        let v = self.lookups.count(self.m.get(k))?;
+        policy.on_access(k);
+        Some(v)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::cache::{ConcurrentHashMap, LruCache};
-    use bytes::Bytes;
-    use std::sync::Arc;
+    fn peek(&self, k: &K) -> Option<V> {
+        self.m.get(k)
+    }
 
-    #[test]
-    fn test() {
-        let cache = LruCache::new(5);
-        cache.insert(1, 1);
-        cache.insert(2, 2);
-        cache.insert(3, 3);
-        assert_eq!(cache.head(), 1);
-        assert_eq!(cache.tail(), 3);
-        assert_eq!(cache.len(), 3);
-        cache.print();
-        cache.remove(&3);
-        cache.print();
-        assert_eq!(cache.tail(), 2);
-        cache.insert(3, 3);
-        cache.print();
-        cache.remove(&2);
-        cache.print();
-        assert_eq!(cache.len(), 2);
-        cache.remove(&1);
-        assert_eq!(cache.len(), 1);
-        cache.print();
+    fn remove(&self, k: &K) -> Option<V> {
+        let mut policy = self.policy.lock().unwrap();
+        self.remove_locked(&mut policy, k)
     }
 
-    #[test]
-    fn test_threshold() {
-        let cache = LruCache::new(5);
-        cache.insert(1, 1);
-        cache.insert(2, 2);
-        cache.insert(3, 3);
-        cache.insert(4, 4);
-        cache.insert(5, 5);
-        assert_eq!(cache.len(), 5);
-        cache.insert(6, 6);
-        assert_eq!(cache.len(), 5);
-        assert_eq!(cache.head(), 2);
-        assert_eq!(cache.tail(), 6);
+    fn keys(&self) -> Vec<K> {
+        self.m.keys()
     }
 
-    #[test]
-    fn test_accesses() {
-        let cache = LruCache::new(5);
-        cache.insert(1, 1);
-        cache.insert(2, 2);
-        cache.insert(3, 3);
-        cache.insert(4, 4);
-        cache.insert(5, 5);
-        assert_eq!(cache.get(&3), Some(3));
-        assert_eq!(cache.tail(), 3);
-        assert_eq!(cache.get(&1), Some(1));
-        assert_eq!(cache.tail(), 1);
-        assert_eq!(cache.head(), 2);
+    fn clear(&self) -> Vec<(K, V)> {
+        let mut policy = self.policy.lock().unwrap();
+        let cleared = self.m.clear();
+        policy.clear();
+        self.len.store(0, Ordering::Release);
+        self.bytes.store(0, Ordering::Release);
+        cleared
     }
 
-    #[test]
-    fn test_generic() {
-        let cache = LruCache::new(5);
-        cache.insert(1, Bytes::from("abhi"));
-        cache.insert(2, Bytes::from("ash"));
-        cache.insert(3, Bytes::from("lilb"));
-        cache.insert(4, Bytes::from("pads"));
-        cache.insert(5, Bytes::from("zigg"));
-        assert_eq!(cache.get(&3), Some(Bytes::from("lilb")));
-        assert_eq!(cache.tail(), Bytes::from("lilb"));
-        assert_eq!(cache.get(&1), Some(Bytes::from("abhi")));
-        assert_eq!(cache.tail(), Bytes::from("abhi"));
-        assert_eq!(cache.head(), Bytes::from("ash"));
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
     }
 
-    #[test]
-    fn test_hm() {
-        let map = ConcurrentHashMap::new();
-        map.insert(1, 1);
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    fn hits(&self) -> u64 {
+        self.lookups.hits()
+    }
+
+    fn misses(&self) -> u64 {
+        self.lookups.misses()
+    }
+
+    fn set_capacity(&self, capacity: usize) -> Option<Vec<(K, V)>> {
+        // inserts hold the policy lock too, so none of them sees the old threshold after this
+        let mut policy = self.policy.lock().unwrap();
+        self.th.store(capacity, Ordering::Release);
+        let mut evicted = vec![];
+        while self.len() > capacity {
+            match self.evict(&mut policy) {
+                Some(entry) => evicted.push(entry),
+                None => break,
+            }
+        }
+        Some(evicted)
+    }
+
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        // inserts & removes hold the policy lock too, so none lands while `f` runs; readers see
+        // the value from before until the changed copy is put back
+        let mut policy = self.policy.lock().unwrap();
+        let mut v = self.m.get(&k);
+        match (f(v.as_mut()), v) {
+            (Action::Keep, Some(v)) | (Action::Insert(v), _) => {
+                self.insert_locked(&mut policy, k, v)
+            }
+            (Action::Keep, None) => vec![],
+            (Action::Remove, _) => {
+                self.remove_locked(&mut policy, &k);
+                vec![]
+            }
+        }
+    }
+}
+
+/// What `LruCache::with_entry` does with an entry once its value has been looked at.
+#[derive(Debug, PartialEq)]
+pub enum Action<V> {
+    /// keeps the entry with any change made to its value; nothing is stored if it was absent
+    Keep,
+    /// stores the value in place of the one there if any, keeping its ttl; a new entry has none
+    Insert(V),
+    /// removes the entry
+    Remove,
+}
+
+pub struct LruCache<K, V> {
+    m: ConcurrentHashMap<K, Link<K, V>>,
+    ll: ConcurrentLL<K, V>,
+    th: AtomicUsize,
+    len: AtomicUsize,
+    /// total size of the values; always 0 unless the cache is bounded by bytes
+    bytes: AtomicUsize,
+    max_bytes: usize,
+    size_of: fn(&V) -> usize,
+    evictions: AtomicU64,
+    lookups: Lookups,
+    /// values being computed by `get_or_insert_with`, shared with the callers waiting on them
+    loading: Mutex<HashMap<K, Arc<OnceLock<V>>>>,
+    on_evict: Option<OnEvict<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone + ByteSized> LruCache<K, V> {
+    /// creates a new `LruCache` that evicts lru entries once the values take up more than
+    /// `max_bytes`, however many entries there are
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            size_of: V::byte_size,
+            ..Self::new(usize::MAX)
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone> LruCache<K, V> {
+    /// creates a new `LruCache` with the given threshold `th`
+    pub fn new(th: usize) -> Self {
+        Self {
+            m: ConcurrentHashMap::new(),
+            ll: ConcurrentLL::new(),
+            th: AtomicUsize::new(th),
+            len: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_bytes: usize::MAX,
+            size_of: |_| 0,
+            evictions: AtomicU64::new(0),
+            lookups: Lookups::default(),
+            loading: Mutex::new(HashMap::new()),
+            on_evict: None,
+        }
+    }
+
+    /// calls `f` with each entry evicted to make room, once the cache is unlocked so `f` may use
+    /// it; entries removed, cleared or found expired aren't evicted
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let evicted = Arc::new(Mutex::new(vec![]));
+    /// let recorded = evicted.clone();
+    /// let cache = LruCache::new(1).on_evict(move |k, _| recorded.lock().unwrap().push(*k));
+    /// cache.insert(1, "abhi");
+    /// cache.insert(2, "ash");
+    /// assert_eq!(*evicted.lock().unwrap(), vec![1]);
+    /// ```
+    pub fn on_evict(mut self, f: impl FnMut(&K, &V) + Send + Sync + 'static) -> Self {
+        self.on_evict = Some(Arc::new(Mutex::new(Box::new(f))));
+        self
+    }
+
+    /// passes the `evicted` entries to the eviction callback, if any
+    fn notify(&self, evicted: &[(K, V)]) {
+        if let Some(on_evict) = self.on_evict.as_ref().filter(|_| !evicted.is_empty()) {
+            let mut on_evict = on_evict.lock().unwrap();
+            for (k, v) in evicted {
+                on_evict(k, v);
+            }
+        }
+    }
+
+    /// inserts value in `LruCache`, evicting lru entries if necessary
+    ///
+    /// returns the evicted entries, least recently used first
+    pub fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        self.insert_until(k, v, None)
+    }
+
+    /// inserts value in `LruCache` like `insert`, treating it as absent once `ttl` has passed
+    ///
+    /// expired entries are removed the next time they are read & may be evicted before then
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    /// use std::time::Duration;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert_with_ttl(1, "abhi", Duration::ZERO);
+    /// assert_eq!(cache.get(&1), None);
+    /// assert!(cache.is_empty());
+    /// ```
+    pub fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) -> Vec<(K, V)> {
+        self.insert_until(k, v, Some(Instant::now() + ttl))
+    }
+
+    /// inserts value in `LruCache` expiring at `deadline`, if any
+    fn insert_until(&self, k: K, v: V, deadline: Option<Instant>) -> Vec<(K, V)> {
+        let mut evicted = vec![];
+        let mut inner = self.ll.inner.write().unwrap();
+
+        if let Some(node) = self.m.get(&k).flatten() {
+            // existing key; update the value in place & mark it as recently used
+            {
+                let mut node = node.write().unwrap();
+                self.bytes.fetch_add((self.size_of)(&v), Ordering::Release);
+                self.bytes
+                    .fetch_sub((self.size_of)(&node.v), Ordering::Release);
+                node.v = v;
+                node.deadline = deadline;
+            }
+            Self::move_back(&mut inner, &node);
+        } else {
+            // check if threshold reached; evict head (lru)
+            if self.len() >= self.capacity() {
+                evicted.extend(self.evict_lru(&mut inner));
+            }
+            let node = Arc::new(RwLock::new(Node::new(k.clone(), v, deadline)));
+            self.link_back(&mut inner, k, node);
+        }
+
+        // the most recently used entry is kept even if it alone exceeds the limit
+        while self.bytes() > self.max_bytes && self.len() > 1 {
+            evicted.extend(self.evict_lru(&mut inner));
+        }
+
+        drop(inner);
+        self.notify(&evicted);
+        evicted
+    }
+
+    /// removes the least recently used entry
+    fn evict_lru(&self, inner: &mut ConcurrentLLInner<K, V>) -> Option<(K, V)> {
+        let k = inner.head.as_ref()?.read().unwrap().k.clone();
+        let node = self.unlink(inner, &k)?;
+        let v = node.read().unwrap().v.clone();
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Some((k, v))
+    }
+
+    /// removes the entry for the key `k`, returning its value
+    pub fn remove(&self, k: &K) -> Option<V> {
+        let node = self.unlink(&mut self.ll.inner.write().unwrap(), k)?;
+        // a concurrent `peek` may still hold the node so the value is cloned out of it
+        let v = node.read().unwrap().v.clone();
+        Some(v)
+    }
+
+    /// detaches the entry for the key `k` from the map & from the list behind `inner`
+    ///
+    /// every change to the list & the map is made under the list's write guard, taken once per
+    /// operation & passed down as `inner`, so no one sees the list half updated
+    fn unlink(
+        &self,
+        inner: &mut ConcurrentLLInner<K, V>,
+        k: &K,
+    ) -> Option<Arc<RwLock<Node<K, V>>>> {
+        let node = self.m.remove(k)??;
+        self.len.fetch_sub(1, Ordering::Release);
+        self.bytes
+            .fetch_sub((self.size_of)(&node.read().unwrap().v), Ordering::Release);
+        Self::detach(inner, &node);
+        Some(node)
+    }
+
+    /// takes `node` out of the list behind `inner`, joining its neighbours
+    fn detach(inner: &mut ConcurrentLLInner<K, V>, node: &Arc<RwLock<Node<K, V>>>) {
+        let (prev, next) = {
+            let mut n = node.write().unwrap();
+            (n.prev.take(), n.next.take())
+        };
+
+        match &prev {
+            Some(prev) => prev.write().unwrap().next = next.clone(),
+            // head node
+            None => inner.head = next.clone(),
+        }
+        match &next {
+            Some(next) => next.write().unwrap().prev = prev,
+            // tail node
+            None => inner.tail = prev,
+        }
+    }
+
+    /// prints all entries in the `LruCache`
+    #[cfg(test)]
+    fn print(&self) {
+        let mut p = self.ll.inner.read().unwrap().head.clone();
+        while let Some(n) = p {
+            println!("{:?} ", n.read().unwrap().v);
+            p = n.read().unwrap().next.clone();
+        }
+    }
+
+    /// gets the value for the key `k` if present, removing it instead if it has expired
+    ///
+    /// counts a hit or a miss, an expired entry being a miss
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.lookups.count(self.lookup(k))
+    }
+
+    /// gets the value for the key `k` like `get` without counting a hit or a miss
+    fn lookup(&self, k: &K) -> Option<V> {
+        if !self.m.contains_key(k) {
+            return None;
+        }
+        let mut inner = self.ll.inner.write().unwrap();
+        let node = self.m.get(k)??;
+        if node.read().unwrap().is_expired() {
+            self.unlink(&mut inner, k);
+            return None;
+        }
+        Self::move_back(&mut inner, &node);
+        let v = node.read().unwrap().v.clone();
+        Some(v)
+    }
+
+    /// gets the value for the key `k`, inserting the one computed by `f` if absent
+    ///
+    /// only one caller computes the value of a missing key; the others block until it is inserted
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// assert_eq!(cache.get_or_insert_with(1, || "abhi"), "abhi");
+    /// assert_eq!(cache.get_or_insert_with(1, || unreachable!()), "abhi");
+    /// ```
+    pub fn get_or_insert_with(&self, k: K, f: impl FnOnce() -> V) -> V {
+        if let Some(v) = self.get(&k) {
+            return v;
+        }
+
+        let slot = self
+            .loading
+            .lock()
+            .unwrap()
+            .entry(k.clone())
+            .or_default()
+            .clone();
+        let mut loaded = false;
+        let v = slot
+            .get_or_init(|| {
+                loaded = true;
+                // another caller may have inserted it since the miss
+                self.lookup(&k).unwrap_or_else(|| {
+                    let v = f();
+                    self.insert(k.clone(), v.clone());
+                    v
+                })
+            })
+            .clone();
+        if loaded {
+            self.loading.lock().unwrap().remove(&k);
+        }
+        v
+    }
+
+    /// adds a new or `unlink` detached `node` as the most recently used entry to the list behind
+    /// `inner`
+    fn link_back(&self, inner: &mut ConcurrentLLInner<K, V>, k: K, node: Arc<RwLock<Node<K, V>>>) {
+        Self::append(inner, &node);
+        self.len.fetch_add(1, Ordering::Release);
+        self.bytes
+            .fetch_add((self.size_of)(&node.read().unwrap().v), Ordering::Release);
+        self.m.insert(k, Some(node));
+    }
+
+    /// puts a detached `node` after the current tail of the list behind `inner`
+    fn append(inner: &mut ConcurrentLLInner<K, V>, node: &Arc<RwLock<Node<K, V>>>) {
+        let old_tail = inner.tail.clone();
+        {
+            let mut n = node.write().unwrap();
+            n.prev = old_tail.clone();
+            n.next = None;
+        }
+        match old_tail {
+            Some(tail) => tail.write().unwrap().next = Some(node.clone()),
+            // it was the only node
+            None => inner.head = Some(node.clone()),
+        }
+        inner.tail = Some(node.clone());
+    }
+
+    /// makes `node`, already in the list behind `inner`, the most recently used entry
+    ///
+    /// the entry stays in the map throughout, so a concurrent `peek` or `get` never misses it
+    fn move_back(inner: &mut ConcurrentLLInner<K, V>, node: &Arc<RwLock<Node<K, V>>>) {
+        Self::detach(inner, node);
+        Self::append(inner, node);
+    }
+
+    /// runs `f` on the value for the key `k`, `None` if it's absent or expired, & then does what
+    /// `f` returns with the entry, all at once as far as any other operation on the cache can tell
+    ///
+    /// `f` may change the value in place; a concurrent `get` or `peek` gets the value from before
+    /// or after `f` ran, never one it is halfway through changing. an entry kept or stored becomes
+    /// the most recently used one. returns the entries evicted to make room, least recently used
+    /// first
+    ///
+    /// `f` runs with the list locked for writing, so it must not use the cache or it deadlocks
+    ///
+    /// ```
+    /// use core::cache::{Action, LruCache};
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert("abhi", vec![1]);
+    /// cache.with_entry("abhi", |v| match v {
+    ///     Some(v) => {
+    ///         v.push(2);
+    ///         Action::Keep
+    ///     }
+    ///     None => Action::Insert(vec![2]),
+    /// });
+    /// assert_eq!(cache.get(&"abhi"), Some(vec![1, 2]));
+    /// ```
+    pub fn with_entry(&self, k: K, f: impl FnOnce(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        let mut evicted = vec![];
+        let mut inner = self.ll.inner.write().unwrap();
+        let node = match self.m.get(&k).flatten() {
+            Some(node) if node.read().unwrap().is_expired() => {
+                self.unlink(&mut inner, &k);
+                None
+            }
+            node => node,
+        };
+
+        match node {
+            Some(node) => {
+                let remove = {
+                    // readers of the node wait for `f` to be done with it
+                    let mut n = node.write().unwrap();
+                    let before = (self.size_of)(&n.v);
+                    let action = f(Some(&mut n.v));
+                    let remove = matches!(action, Action::Remove);
+                    if let Action::Insert(v) = action {
+                        n.v = v;
+                    }
+                    self.bytes
+                        .fetch_add((self.size_of)(&n.v), Ordering::Release);
+                    self.bytes.fetch_sub(before, Ordering::Release);
+                    remove
+                };
+                if remove {
+                    self.unlink(&mut inner, &k);
+                } else {
+                    Self::move_back(&mut inner, &node);
+                }
+            }
+            None => {
+                if let Action::Insert(v) = f(None) {
+                    if self.len() >= self.capacity() {
+                        evicted.extend(self.evict_lru(&mut inner));
+                    }
+                    let node = Arc::new(RwLock::new(Node::new(k.clone(), v, None)));
+                    self.link_back(&mut inner, k, node);
+                }
+            }
+        }
+
+        while self.bytes() > self.max_bytes && self.len() > 1 {
+            evicted.extend(self.evict_lru(&mut inner));
+        }
+        drop(inner);
+        self.notify(&evicted);
+        evicted
+    }
+
+    /// gets the value for the key `k` if present & unexpired without marking it as recently used
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(2);
+    /// cache.insert(1, "abhi");
+    /// cache.insert(2, "ash");
+    /// assert_eq!(cache.peek(&1), Some("abhi"));
+    /// // 1 is still the lru entry
+    /// cache.insert(3, "lilb");
+    /// assert_eq!(cache.peek(&1), None);
+    /// ```
+    pub fn peek(&self, k: &K) -> Option<V> {
+        let node = self.m.get(k)??;
+        let node = node.read().unwrap();
+        (!node.is_expired()).then(|| node.v.clone())
+    }
+
+    /// returns all the keys from the least to the most recently used
+    pub fn keys(&self) -> Vec<K> {
+        let mut keys = vec![];
+        let mut p = self.ll.inner.read().unwrap().head.clone();
+        while let Some(n) = p {
+            keys.push(n.read().unwrap().k.clone());
+            p = n.read().unwrap().next.clone();
+        }
+        keys
+    }
+
+    /// removes all the entries, returning them from the least to the most recently used
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert(1, "abhi");
+    /// assert_eq!(cache.clear(), vec![(1, "abhi")]);
+    /// assert!(cache.is_empty());
+    /// ```
+    pub fn clear(&self) -> Vec<(K, V)> {
+        let mut inner = self.ll.inner.write().unwrap();
+
+        // unlink the nodes so the ones pointing at each other get dropped
+        let mut cleared = vec![];
+        let mut p = inner.head.take();
+        while let Some(n) = p {
+            let mut n = n.write().unwrap();
+            cleared.push((n.k.clone(), n.v.clone()));
+            n.prev = None;
+            p = n.next.take();
+        }
+        inner.tail = None;
+
+        self.m.clear();
+        self.len.store(0, Ordering::Release);
+        self.bytes.store(0, Ordering::Release);
+        cleared
+    }
+
+    /// returns all the entries from the least to the most recently used
+    ///
+    /// every entry is cloned so this is O(n) & meant for admin use like diagnostics or dumps
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert(1, "abhi");
+    /// cache.insert(2, "ash");
+    /// assert_eq!(cache.snapshot(), vec![(1, "abhi"), (2, "ash")]);
+    /// ```
+    pub fn snapshot(&self) -> Vec<(K, V)> {
+        // the list can't change while it is walked
+        let inner = self.ll.inner.read().unwrap();
+        let mut entries = vec![];
+        let mut p = inner.head.clone();
+        while let Some(n) = p {
+            let n = n.read().unwrap();
+            entries.push((n.k.clone(), n.v.clone()));
+            p = n.next.clone();
+        }
+        entries
+    }
+
+    #[cfg(test)]
+    #[inline(always)]
+    fn head(&self) -> V {
+        self.ll
+            .inner
+            .read()
+            .unwrap()
+            .head
+            .clone()
+            .unwrap()
+            .read()
+            .unwrap()
+            .v
+            .clone()
+    }
+
+    #[cfg(test)]
+    #[inline(always)]
+    fn tail(&self) -> V {
+        self.ll
+            .inner
+            .read()
+            .unwrap()
+            .tail
+            .clone()
+            .unwrap()
+            .read()
+            .unwrap()
+            .v
+            .clone()
+    }
+
+    /// returns the number of entries in the `LruCache`
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert(1, "abhi");
+    /// cache.insert(1, "abhi");
+    /// assert_eq!(cache.len(), 1);
+    /// ```
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// returns the total size of the values if the `LruCache` is bounded by bytes, 0 otherwise
+    #[inline(always)]
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    /// returns `true` if the `LruCache` has no entries
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// assert!(cache.is_empty());
+    /// cache.insert(1, "abhi");
+    /// assert!(!cache.is_empty());
+    /// ```
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// returns the number of entries the `LruCache` holds before it starts evicting
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::<u32, u32>::new(5);
+    /// assert_eq!(cache.capacity(), 5);
+    /// ```
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.th.load(Ordering::Acquire)
+    }
+
+    /// changes the threshold to `th`, evicting lru entries right away until the `LruCache` fits
+    ///
+    /// returns the evicted entries, least recently used first
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert(1, "abhi");
+    /// cache.insert(2, "ash");
+    /// assert_eq!(cache.set_capacity(1), vec![(1, "abhi")]);
+    /// assert_eq!(cache.capacity(), 1);
+    /// ```
+    pub fn set_capacity(&self, th: usize) -> Vec<(K, V)> {
+        // inserts hold the list lock too, so none of them sees the old threshold after this
+        let mut inner = self.ll.inner.write().unwrap();
+        self.th.store(th, Ordering::Release);
+        let mut evicted = vec![];
+        while self.len() > th {
+            match self.evict_lru(&mut inner) {
+                Some(entry) => evicted.push(entry),
+                None => break,
+            }
+        }
+        drop(inner);
+        self.notify(&evicted);
+        evicted
+    }
+
+    /// returns the number of entries evicted because the `LruCache` was full
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(1);
+    /// cache.insert(1, "abhi");
+    /// cache.insert(2, "ash");
+    /// assert_eq!(cache.evictions(), 1);
+    /// ```
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// returns the number of `get`s that found a live entry
+    ///
+    /// ```
+    /// use core::cache::LruCache;
+    ///
+    /// let cache = LruCache::new(5);
+    /// cache.insert(1, "abhi");
+    /// cache.get(&1);
+    /// cache.get(&2);
+    /// assert_eq!(cache.hits(), 1);
+    /// assert_eq!(cache.misses(), 1);
+    /// ```
+    pub fn hits(&self) -> u64 {
+        self.lookups.hits()
+    }
+
+    /// returns the number of `get`s that found no entry or an expired one
+    pub fn misses(&self) -> u64 {
+        self.lookups.misses()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone> Store<K, V> for LruCache<K, V> {
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        LruCache::insert(self, k, v)
+    }
+
+    fn get(&self, k: &K) -> Option<V> {
+        LruCache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<V> {
+        LruCache::peek(self, k)
+    }
+
+    fn remove(&self, k: &K) -> Option<V> {
+        LruCache::remove(self, k)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        LruCache::keys(self)
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        LruCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        LruCache::len(self)
+    }
+
+    fn evictions(&self) -> u64 {
+        LruCache::evictions(self)
+    }
+
+    fn hits(&self) -> u64 {
+        LruCache::hits(self)
+    }
+
+    fn misses(&self) -> u64 {
+        LruCache::misses(self)
+    }
+
+    fn set_capacity(&self, capacity: usize) -> Option<Vec<(K, V)>> {
+        Some(LruCache::set_capacity(self, capacity))
+    }
+
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        LruCache::with_entry(self, k, f)
+    }
+}
+
+/// An LRU cache split into segments, each with its own list & lock.
+///
+/// A key always lands in the same segment, picked by its hash, & the threshold is divided between
+/// the segments so they evict independently. Operations on keys in different segments never
+/// contend, at the cost of evicting the least recently used entry of a segment rather than of
+/// the whole cache.
+pub struct ShardedLruCache<K, V> {
+    shards: Vec<LruCache<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone + ByteSized> ShardedLruCache<K, V> {
+    /// creates a new `ShardedLruCache` of `shards` segments that evict lru entries once the
+    /// values take up more than their share of `max_bytes`
+    pub fn with_max_bytes(max_bytes: usize, shards: usize) -> Self {
+        Self {
+            shards: split(max_bytes, shards)
+                .map(LruCache::with_max_bytes)
+                .collect(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone> ShardedLruCache<K, V> {
+    /// creates a new `ShardedLruCache` of `shards` segments sharing the threshold `th`
+    ///
+    /// ```
+    /// use core::cache::ShardedLruCache;
+    ///
+    /// let cache = ShardedLruCache::new(10, 4);
+    /// cache.insert(1, "abhi");
+    /// assert_eq!(cache.get(&1), Some("abhi"));
+    /// assert_eq!(cache.capacity(), 10);
+    /// ```
+    pub fn new(th: usize, shards: usize) -> Self {
+        Self {
+            shards: split(th, shards).map(LruCache::new).collect(),
+        }
+    }
+
+    /// calls `f` with each entry any segment evicts to make room, like `LruCache::on_evict`
+    pub fn on_evict(mut self, f: impl FnMut(&K, &V) + Send + Sync + 'static) -> Self {
+        let on_evict: OnEvict<K, V> = Arc::new(Mutex::new(Box::new(f)));
+        for shard in &mut self.shards {
+            shard.on_evict = Some(on_evict.clone());
+        }
+        self
+    }
+
+    /// gets the segment holding key `k`
+    fn shard(&self, k: &K) -> &LruCache<K, V> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        k.hash(&mut hasher);
+        // the segments' maps pick buckets by the low bits of the same hash, so picking the
+        // segment by them too would leave all but one bucket of each segment empty
+        &self.shards[(hasher.finish() >> 32) as usize % self.shards.len()]
+    }
+
+    /// inserts value in its segment, evicting lru entries of that segment if necessary
+    ///
+    /// returns the evicted entries, least recently used first
+    pub fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        self.shard(&k).insert(k, v)
+    }
+
+    /// inserts value like `insert`, treating it as absent once `ttl` has passed
+    pub fn insert_with_ttl(&self, k: K, v: V, ttl: Duration) -> Vec<(K, V)> {
+        self.shard(&k).insert_with_ttl(k, v, ttl)
+    }
+
+    /// gets the value for key `k`, marking it the most recently used in its segment
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.shard(k).get(k)
+    }
+
+    /// gets the value for key `k` or inserts the one computed by `f` if it is missing
+    ///
+    /// only one caller computes the value of a missing key; the others block until it is inserted
+    pub fn get_or_insert_with(&self, k: K, f: impl FnOnce() -> V) -> V {
+        self.shard(&k).get_or_insert_with(k, f)
+    }
+
+    /// runs `f` on the value for key `k` & does what it returns with the entry, atomically like
+    /// `LruCache::with_entry`, within the key's segment
+    pub fn with_entry(&self, k: K, f: impl FnOnce(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        self.shard(&k).with_entry(k, f)
+    }
+
+    /// gets the value for key `k` without marking it used
+    pub fn peek(&self, k: &K) -> Option<V> {
+        self.shard(k).peek(k)
+    }
+
+    /// removes key `k`, returning its value
+    pub fn remove(&self, k: &K) -> Option<V> {
+        self.shard(k).remove(k)
+    }
+
+    /// returns all the keys, segment by segment from the least to the most recently used
+    pub fn keys(&self) -> Vec<K> {
+        self.shards.iter().flat_map(LruCache::keys).collect()
+    }
+
+    /// removes all the entries, returning them segment by segment
+    pub fn clear(&self) -> Vec<(K, V)> {
+        self.shards.iter().flat_map(LruCache::clear).collect()
+    }
+
+    /// returns all the entries, segment by segment from the least to the most recently used
+    pub fn snapshot(&self) -> Vec<(K, V)> {
+        self.shards.iter().flat_map(LruCache::snapshot).collect()
+    }
+
+    /// returns the number of entries in all the segments
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(LruCache::len).sum()
+    }
+
+    /// returns the total size of the values if the cache is bounded by bytes, 0 otherwise
+    pub fn bytes(&self) -> usize {
+        self.shards.iter().map(LruCache::bytes).sum()
+    }
+
+    /// returns `true` if no segment has entries
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(LruCache::is_empty)
+    }
+
+    /// returns the number of entries the segments hold between them before they start evicting
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(LruCache::capacity)
+            .fold(0, usize::saturating_add)
+    }
+
+    /// changes the threshold to `th`, split between the segments like when created, evicting lru
+    /// entries of each segment right away until it fits its share
+    ///
+    /// returns the evicted entries, segment by segment
+    pub fn set_capacity(&self, th: usize) -> Vec<(K, V)> {
+        self.shards
+            .iter()
+            .zip(split(th, self.shards.len()))
+            .flat_map(|(shard, th)| shard.set_capacity(th))
+            .collect()
+    }
+
+    /// returns the number of segments
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// returns the number of entries evicted because their segment was full
+    pub fn evictions(&self) -> u64 {
+        self.shards.iter().map(LruCache::evictions).sum()
+    }
+
+    /// returns the number of `get`s that found a live entry in any segment
+    pub fn hits(&self) -> u64 {
+        self.shards.iter().map(LruCache::hits).sum()
+    }
+
+    /// returns the number of `get`s that found no entry or an expired one in any segment
+    pub fn misses(&self) -> u64 {
+        self.shards.iter().map(LruCache::misses).sum()
+    }
+}
+
+/// splits `total` into `n` parts as even as possible, the first ones taking the remainder
+fn split(total: usize, n: usize) -> impl Iterator<Item = usize> {
+    assert!(n > 0, "a ShardedLruCache needs at least one shard");
+    (0..n).map(move |i| total / n + usize::from(i < total % n))
+}
+
+impl<K: Eq + Hash + Clone, V: Debug + Clone> Store<K, V> for ShardedLruCache<K, V> {
+    fn insert(&self, k: K, v: V) -> Vec<(K, V)> {
+        ShardedLruCache::insert(self, k, v)
+    }
+
+    fn get(&self, k: &K) -> Option<V> {
+        ShardedLruCache::get(self, k)
+    }
+
+    fn peek(&self, k: &K) -> Option<V> {
+        ShardedLruCache::peek(self, k)
+    }
+
+    fn remove(&self, k: &K) -> Option<V> {
+        ShardedLruCache::remove(self, k)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        ShardedLruCache::keys(self)
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        ShardedLruCache::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        ShardedLruCache::len(self)
+    }
+
+    fn evictions(&self) -> u64 {
+        ShardedLruCache::evictions(self)
+    }
+
+    fn hits(&self) -> u64 {
+        ShardedLruCache::hits(self)
+    }
+
+    fn misses(&self) -> u64 {
+        ShardedLruCache::misses(self)
+    }
+
+    fn set_capacity(&self, capacity: usize) -> Option<Vec<(K, V)>> {
+        Some(ShardedLruCache::set_capacity(self, capacity))
+    }
+
+    fn with_entry(&self, k: K, f: &mut dyn FnMut(Option<&mut V>) -> Action<V>) -> Vec<(K, V)> {
+        ShardedLruCache::with_entry(self, k, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{
+        Action, ConcurrentHashMap, EvictingCache, Link, LruCache, ShardedLruCache, Store,
+        UnboundedCache,
+    };
+    use crate::eviction::{Lfu, Lru, Random};
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier, Mutex, OnceLock, Weak};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(cache.head(), 1);
+        assert_eq!(cache.tail(), 3);
+        assert_eq!(cache.len(), 3);
+        cache.print();
+        cache.remove(&3);
+        cache.print();
+        assert_eq!(cache.tail(), 2);
+        cache.insert(3, 3);
+        cache.print();
+        cache.remove(&2);
+        cache.print();
+        assert_eq!(cache.len(), 2);
+        cache.remove(&1);
+        assert_eq!(cache.len(), 1);
+        cache.print();
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let cache = LruCache::new(5);
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 5);
+        cache.insert(1, 1);
+        assert!(!cache.is_empty());
+        cache.remove(&1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_threshold() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.insert(4, 4);
+        cache.insert(5, 5);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.insert(6, 6), vec![(1, 1)]);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.head(), 2);
+        assert_eq!(cache.tail(), 6);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let cache = LruCache::with_max_bytes(10);
+        assert!(cache.insert(1, "aaaa".to_string()).is_empty());
+        assert!(cache.insert(2, "bbbb".to_string()).is_empty());
+        assert!(cache.insert(3, "cc".to_string()).is_empty());
+        assert_eq!(cache.bytes(), 10);
+
+        // one entry more than fits evicts just the lru
+        assert_eq!(
+            cache.insert(4, "ddd".to_string()),
+            vec![(1, "aaaa".to_string())]
+        );
+        assert_eq!(cache.bytes(), 9);
+        assert_eq!(cache.keys(), vec![2, 3, 4]);
+
+        // a large entry evicts several
+        assert_eq!(cache.insert(5, "eeeeeee".to_string()).len(), 2);
+        assert_eq!(cache.keys(), vec![4, 5]);
+        assert_eq!(cache.bytes(), 10);
+
+        // updating an entry accounts for the size change
+        assert!(cache.insert(4, "d".to_string()).is_empty());
+        assert_eq!(cache.bytes(), 8);
+        cache.remove(&5);
+        assert_eq!(cache.bytes(), 1);
+    }
+
+    #[test]
+    fn test_max_bytes_many_small() {
+        // the entry count isn't what bounds the cache
+        let cache = LruCache::with_max_bytes(100);
+        for i in 0..100 {
+            assert!(cache.insert(i, "a".to_string()).is_empty());
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(
+            cache.insert(100, "a".to_string()),
+            vec![(0, "a".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_accesses() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.insert(4, 4);
+        cache.insert(5, 5);
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.tail(), 3);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.tail(), 1);
+        assert_eq!(cache.head(), 2);
+    }
+
+    #[test]
+    fn test_get_relinks() {
+        let cache = LruCache::new(5);
+        for i in 1..=5 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.keys(), vec![1, 2, 4, 5, 3]);
+
+        // the prev links must mirror the next links
+        let mut keys = vec![];
+        let mut p = cache.ll.inner.read().unwrap().tail.clone();
+        while let Some(n) = p {
+            keys.push(n.read().unwrap().k);
+            p = n.read().unwrap().prev.clone();
+        }
+        assert_eq!(keys, vec![3, 5, 4, 2, 1]);
+    }
+
+    #[test]
+    fn test_insert_existing() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        assert!(cache.insert(1, 10).is_empty());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.keys(), vec![1]);
+        assert_eq!(cache.get(&1), Some(10));
+
+        // the updated key becomes the most recently used
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.insert(2, 20);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.keys(), vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_repeated_get() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.keys(), vec![1]);
+    }
+
+    #[test]
+    fn test_remove_only_entry() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.len(), 0);
+        assert!(cache.keys().is_empty());
+        cache.insert(2, 2);
+        assert_eq!(cache.head(), 2);
+    }
+
+    #[test]
+    fn test_peek_and_keys() {
+        let cache = LruCache::new(5);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(cache.peek(&1), Some(1));
+        assert_eq!(cache.peek(&4), None);
+        // peeking doesn't change the order
+        assert_eq!(cache.keys(), vec![1, 2, 3]);
+        assert_eq!(cache.head(), 1);
+        // while getting moves the entry to the tail
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.keys(), vec![2, 3, 1]);
+        assert_eq!(cache.head(), 2);
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = LruCache::with_max_bytes(100);
+        for (k, v) in [(1, "abhi"), (2, "ash"), (3, "lilb")] {
+            cache.insert(k, v.to_string());
+        }
+        cache.get(&1);
+        assert_eq!(
+            cache.clear(),
+            vec![
+                (2, "ash".to_string()),
+                (3, "lilb".to_string()),
+                (1, "abhi".to_string())
+            ]
+        );
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.bytes(), 0);
+        for k in [1, 2, 3] {
+            assert_eq!(cache.get(&k), None);
+        }
+        assert!(cache.snapshot().is_empty());
+
+        // still usable afterwards
+        cache.insert(4, "pads".to_string());
+        assert_eq!(cache.snapshot(), vec![(4, "pads".to_string())]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let cache = LruCache::new(3);
+        assert!(cache.snapshot().is_empty());
+        cache.insert(1, "abhi");
+        cache.insert(2, "ash");
+        cache.insert(3, "lilb");
+        cache.get(&1);
+        assert_eq!(cache.snapshot(), vec![(2, "ash"), (3, "lilb"), (1, "abhi")]);
+
+        // evicts 2
+        cache.insert(4, "pads");
+        assert_eq!(
+            cache.snapshot(),
+            vec![(3, "lilb"), (1, "abhi"), (4, "pads")]
+        );
+    }
+
+    #[test]
+    fn test_generic() {
+        let cache = LruCache::new(5);
+        cache.insert(1, Bytes::from("abhi"));
+        cache.insert(2, Bytes::from("ash"));
+        cache.insert(3, Bytes::from("lilb"));
+        cache.insert(4, Bytes::from("pads"));
+        cache.insert(5, Bytes::from("zigg"));
+        assert_eq!(cache.get(&3), Some(Bytes::from("lilb")));
+        assert_eq!(cache.tail(), Bytes::from("lilb"));
+        assert_eq!(cache.get(&1), Some(Bytes::from("abhi")));
+        assert_eq!(cache.tail(), Bytes::from("abhi"));
+        assert_eq!(cache.head(), Bytes::from("ash"));
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let cache = UnboundedCache::new();
+        for k in 0..100 {
+            assert!(Store::insert(&cache, k, k).is_empty());
+        }
+        assert_eq!(Store::len(&cache), 100);
+
+        // replacing a value doesn't add an entry
+        Store::insert(&cache, 1, 10);
+        assert_eq!(Store::len(&cache), 100);
+        assert_eq!(Store::get(&cache, &1), Some(10));
+
+        assert_eq!(Store::remove(&cache, &1), Some(10));
+        assert_eq!(Store::remove(&cache, &1), None);
+        let mut keys = Store::keys(&cache);
+        keys.sort();
+        assert_eq!(keys, (0..100).filter(|&k| k != 1).collect::<Vec<_>>());
+
+        Store::clear(&cache);
+        assert!(Store::is_empty(&cache));
+        assert_eq!(Store::get(&cache, &2), None);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let cache = LruCache::new(5);
+        cache.insert_with_ttl(1, "abhi", Duration::from_millis(50));
+        cache.insert_with_ttl(2, "ash", Duration::from_millis(50));
+        cache.insert_with_ttl(3, "lilb", Duration::from_secs(60));
+        cache.insert(4, "nish");
+        assert_eq!(cache.get(&1), Some("abhi"));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.peek(&1), None);
+        // only read entries are removed
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&3), Some("lilb"));
+        assert_eq!(cache.get(&4), Some("nish"));
+        assert_eq!(cache.get_or_insert_with(2, || "go"), "go");
+        assert_eq!(cache.keys(), vec![3, 4, 2]);
+
+        // a plain insert drops the ttl
+        cache.insert_with_ttl(5, "zig", Duration::ZERO);
+        cache.insert(5, "zig");
+        assert_eq!(cache.get(&5), Some("zig"));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let cache = LruCache::new(2);
+        cache.insert(1, "abhi");
+        assert_eq!(
+            cache.get_or_insert_with(1, || panic!("loaded a hit")),
+            "abhi"
+        );
+        assert_eq!(cache.get_or_insert_with(2, || "ash"), "ash");
+        assert_eq!(cache.get(&2), Some("ash"));
+        // the hit marked 1 as recently used
+        assert_eq!(cache.get_or_insert_with(3, || "lilb"), "lilb");
+        assert_eq!(cache.keys(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_get_or_insert_with_concurrent() {
+        let cache = Arc::new(LruCache::new(5));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, loads, barrier) = (cache.clone(), loads.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with("abhi", || {
+                        loads.fetch_add(1, Ordering::Relaxed);
+                        // keep loading while the others miss
+                        thread::sleep(Duration::from_millis(50));
+                        "rust".to_string()
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), "rust");
+        }
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_with_entry() {
+        let cache = LruCache::new(3);
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+
+        // changed in place & made the most recently used
+        let evicted = cache.with_entry(1, |v| {
+            *v.unwrap() += 1;
+            Action::Keep
+        });
+        assert!(evicted.is_empty());
+        assert_eq!(Some(11), cache.peek(&1));
+        assert_eq!(vec![2, 1], cache.keys());
+
+        cache.with_entry(3, |v| {
+            assert_eq!(None, v);
+            Action::Insert(30)
+        });
+        // nothing to keep
+        cache.with_entry(4, |_| Action::Keep);
+        assert_eq!(vec![2, 1, 3], cache.keys());
+        assert_eq!(vec![(2, 20)], cache.with_entry(4, |_| Action::Insert(40)));
+
+        cache.with_entry(1, |_| Action::Remove);
+        assert_eq!(None, cache.peek(&1));
+        cache.with_entry(3, |_| Action::Insert(33));
+        assert_eq!(vec![(4, 40), (3, 33)], cache.snapshot());
+        assert_consistent(&cache);
+
+        // an expired entry is absent
+        cache.insert_with_ttl(5, 50, Duration::ZERO);
+        cache.with_entry(5, |v| {
+            assert_eq!(None, v);
+            Action::Keep
+        });
+        assert_eq!(2, cache.len());
+        assert_consistent(&cache);
+
+        // growing a value counts towards the bytes, evicting others if need be
+        let cache = LruCache::with_max_bytes(10);
+        cache.insert(1, "abhi".to_string());
+        cache.insert(2, "rust".to_string());
+        let evicted = cache.with_entry(1, |v| {
+            v.unwrap().push_str("bhagat");
+            Action::Keep
+        });
+        assert_eq!(vec![(2, "rust".to_string())], evicted);
+        assert_eq!(10, cache.bytes());
+
+        let cache = ShardedLruCache::new(4, 2);
+        cache.with_entry("abhi", |_| Action::Insert(1));
+        cache.with_entry("abhi", |v| Action::Insert(v.map_or(0, |v| *v + 1)));
+        assert_eq!(Some(2), cache.get(&"abhi"));
+
+        // replacing a value keeps its ttl
+        let cache = LruCache::new(3);
+        cache.insert_with_ttl(1, 10, Duration::from_millis(50));
+        cache.with_entry(1, |v| Action::Insert(*v.unwrap() + 1));
+        assert_eq!(Some(11), cache.peek(&1));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(None, cache.peek(&1));
+    }
+
+    #[test]
+    fn test_store_with_entry() {
+        let stores: Vec<Box<dyn Store<u32, u32>>> = vec![
+            Box::new(LruCache::new(2)),
+            Box::new(ShardedLruCache::new(2, 1)),
+            Box::new(EvictingCache::new(2, Lru::new())),
+            Box::new(UnboundedCache::new()),
+        ];
+        for store in stores {
+            assert!(store.with_entry(1, &mut |_| Action::Insert(10)).is_empty());
+            store.with_entry(1, &mut |v| {
+                *v.unwrap() += 1;
+                Action::Keep
+            });
+            assert_eq!(Some(11), store.peek(&1));
+            store.with_entry(2, &mut |v| Action::Insert(v.map_or(20, |v| *v + 1)));
+            store.with_entry(3, &mut |_| Action::Keep);
+            assert_eq!(2, store.len());
+
+            let evicted = store.with_entry(3, &mut |_| Action::Insert(30));
+            assert_eq!(store.len() + evicted.len(), 3);
+            store.with_entry(3, &mut |_| Action::Remove);
+            assert_eq!(None, store.peek(&3));
+            assert_eq!(2 - evicted.len(), store.len());
+        }
+    }
+
+    #[test]
+    fn test_store_with_entry_concurrent() {
+        let stores: Vec<Box<dyn Store<u32, u64> + Sync>> = vec![
+            Box::new(LruCache::new(5)),
+            Box::new(EvictingCache::new(5, Lfu::new())),
+            Box::new(UnboundedCache::new()),
+        ];
+        for store in stores {
+            store.insert(1, 0);
+            thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        for _ in 0..500 {
+                            store.with_entry(1, &mut |v| Action::Insert(v.map_or(1, |v| *v + 1)));
+                        }
+                    });
+                }
+            });
+            assert_eq!(Some(2000), store.peek(&1));
+        }
+    }
+
+    #[test]
+    fn test_with_entry_concurrent() {
+        let cache = LruCache::new(5);
+        cache.insert("counter", (0u64, 0u64));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let writers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..500 {
+                            cache.with_entry("counter", |v| {
+                                let v = v.unwrap();
+                                v.0 += 1;
+                                // let the readers in between the two halves if they can
+                                thread::yield_now();
+                                v.1 += 1;
+                                Action::Keep
+                            });
+                        }
+                    })
+                })
+                .collect();
+            for _ in 0..2 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        // never missing or half updated
+                        let (a, b) = cache.peek(&"counter").unwrap();
+                        assert_eq!(a, b);
+                        let (a, b) = cache.get(&"counter").unwrap();
+                        assert_eq!(a, b);
+                    }
+                });
+            }
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(Some((2000, 2000)), cache.peek(&"counter"));
+    }
+
+    #[test]
+    fn test_evictions() {
+        let cache = LruCache::new(3);
+        for k in 0..10 {
+            cache.insert(k, k);
+        }
+        // replacing & removing entries isn't evicting them
+        cache.insert(9, 9);
+        cache.remove(&9);
+        assert_eq!(cache.evictions(), 7);
+        cache.clear();
+        assert_eq!(cache.evictions(), 7);
+
+        let cache = LruCache::with_max_bytes(10);
+        for v in ["abhi", "ash", "lilb", "typescript"] {
+            cache.insert(v, v.to_string());
+        }
+        assert_eq!(cache.evictions(), 3);
+
+        let cache: Box<dyn Store<u32, u32>> = Box::new(EvictingCache::new(3, Lfu::new()));
+        for k in 0..10 {
+            cache.insert(k, k);
+        }
+        assert_eq!(cache.evictions(), 7);
+        assert_eq!(UnboundedCache::<u32, u32>::new().evictions(), 0);
+    }
+
+    #[test]
+    fn test_hits_misses() {
+        let stores: Vec<Box<dyn Store<u32, u32>>> = vec![
+            Box::new(LruCache::new(5)),
+            Box::new(ShardedLruCache::new(5, 2)),
+            Box::new(EvictingCache::new(5, Lru::new())),
+            Box::new(UnboundedCache::new()),
+        ];
+        for cache in stores {
+            cache.insert(1, 1);
+            cache.get(&1);
+            cache.get(&1);
+            cache.get(&2);
+            // peeking isn't a read a client made
+            cache.peek(&1);
+            cache.peek(&2);
+            assert_eq!(cache.hits(), 2);
+            assert_eq!(cache.misses(), 1);
+        }
+
+        // a lazily expired entry is a miss
+        let cache = LruCache::new(5);
+        cache.insert_with_ttl(1, 1, Duration::from_millis(10));
+        assert_eq!(cache.get(&1), Some(1));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        // a caller loading the missing value counts one miss
+        assert_eq!(cache.get_or_insert_with(2, || 2), 2);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn test_on_evict() {
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let recorded = evicted.clone();
+        let cache = LruCache::new(3).on_evict(move |&k, &v| recorded.lock().unwrap().push((k, v)));
+        let mut returned = vec![];
+        for k in 0..10 {
+            returned.extend(cache.insert(k, k * 10));
+        }
+        assert_eq!((0..7).map(|k| (k, k * 10)).collect::<Vec<_>>(), returned);
+        assert_eq!(returned, *evicted.lock().unwrap());
+
+        // removing, replacing, expiring & clearing entries isn't evicting them
+        cache.remove(&9);
+        cache.insert(8, 8);
+        cache.insert_with_ttl(9, 9, Duration::ZERO);
+        assert_eq!(None, cache.get(&9));
+        cache.with_entry(7, |_| Action::Remove);
+        cache.clear();
+        assert_eq!(7, evicted.lock().unwrap().len());
+
+        for k in 0..3 {
+            cache.insert(k, k);
+        }
+        assert_eq!(vec![(0, 0)], cache.with_entry(3, |_| Action::Insert(3)));
+        assert_eq!(vec![(1, 1), (2, 2)], cache.set_capacity(1));
+        assert_eq!(
+            vec![(0, 0), (1, 1), (2, 2)],
+            evicted.lock().unwrap().split_off(7)
+        );
+
+        // the callback may use the cache, which is unlocked by then
+        let slot: Arc<OnceLock<Weak<LruCache<u32, u32>>>> = Arc::new(OnceLock::new());
+        let weak = slot.clone();
+        let cache = Arc::new(LruCache::new(1).on_evict(move |k, _| {
+            let cache = weak.get().unwrap().upgrade().unwrap();
+            assert_eq!(None, cache.peek(k));
+            assert_eq!(1, cache.len());
+        }));
+        slot.set(Arc::downgrade(&cache)).unwrap();
+        cache.insert(1, 1);
+        assert_eq!(vec![(1, 1)], cache.insert(2, 2));
+
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let recorded = evicted.clone();
+        let cache = ShardedLruCache::new(4, 2)
+            .on_evict(move |&k, _: &u32| recorded.lock().unwrap().push(k));
+        let mut returned = vec![];
+        for k in 0..20 {
+            returned.extend(cache.insert(k, k).into_iter().map(|(k, _)| k));
+        }
+        assert_eq!(16, returned.len());
+        assert_eq!(returned, *evicted.lock().unwrap());
+    }
+
+    #[test]
+    fn test_evicting_lru() {
+        let cache = EvictingCache::new(3, Lru::new());
+        for k in [1, 2, 3] {
+            Store::insert(&cache, k, k);
+        }
+        Store::get(&cache, &1);
+        assert_eq!(Store::insert(&cache, 4, 4), vec![(2, 2)]);
+        // peeking isn't a use
+        Store::peek(&cache, &3);
+        assert_eq!(Store::insert(&cache, 5, 5), vec![(3, 3)]);
+        assert_eq!(Store::len(&cache), 3);
+    }
+
+    #[test]
+    fn test_evicting_lfu() {
+        let cache = EvictingCache::new(3, Lfu::new());
+        for k in [1, 2, 3] {
+            Store::insert(&cache, k, k);
+        }
+        for k in [1, 1, 2, 3, 3] {
+            Store::get(&cache, &k);
+        }
+        assert_eq!(Store::insert(&cache, 4, 4), vec![(2, 2)]);
+        // the newest entry hasn't been used yet
+        assert_eq!(Store::insert(&cache, 5, 5), vec![(4, 4)]);
+        // replacing a value keeps its count & evicts nothing
+        assert!(Store::insert(&cache, 1, 10).is_empty());
+        Store::get(&cache, &5);
+        Store::get(&cache, &5);
+        assert_eq!(Store::insert(&cache, 6, 6), vec![(3, 3)]);
+    }
+
+    #[test]
+    fn test_evicting_random() {
+        let cache = EvictingCache::new(3, Random::new());
+        for k in [1, 2, 3] {
+            Store::insert(&cache, k, k);
+        }
+        let evicted = Store::insert(&cache, 4, 4);
+        assert_eq!(evicted.len(), 1);
+        let (victim, _) = evicted[0];
+        assert!([1, 2, 3].contains(&victim));
+        assert_eq!(Store::get(&cache, &victim), None);
+        assert_eq!(Store::get(&cache, &4), Some(4));
+        assert_eq!(Store::len(&cache), 3);
+    }
+
+    #[test]
+    fn test_evicting_concurrent() {
+        const TH: u32 = 8;
+        let stores: Vec<Box<dyn Store<u32, u32> + Send + Sync>> = vec![
+            Box::new(EvictingCache::new(TH as usize, Lru::new())),
+            Box::new(EvictingCache::new(TH as usize, Lfu::new())),
+        ];
+        for cache in stores {
+            let cache = Arc::new(cache);
+            for round in 0..200 {
+                // a key read while being removed in the last round mustn't be picked over these
+                let keys = round * 100..round * 100 + 2 * TH;
+                for k in keys.clone() {
+                    cache.insert(k, k);
+                    assert!(cache.len() <= TH as usize, "{} entries", cache.len());
+                }
+
+                let start = Arc::new(Barrier::new(4));
+                let readers: Vec<_> = (0..3)
+                    .map(|_| {
+                        let cache = cache.clone();
+                        let keys = keys.clone();
+                        let start = start.clone();
+                        thread::spawn(move || {
+                            start.wait();
+                            for _ in 0..20 {
+                                for k in keys.clone() {
+                                    cache.get(&k);
+                                }
+                            }
+                        })
+                    })
+                    .collect();
+                start.wait();
+                for k in keys {
+                    cache.remove(&k);
+                }
+                for reader in readers {
+                    reader.join().unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_evicting_max_bytes() {
+        let cache = EvictingCache::with_max_bytes(12, Lfu::new());
+        for (k, v) in [(1, "rust"), (2, "go"), (3, "java")] {
+            Store::insert(&cache, k, v.to_string());
+        }
+        Store::get(&cache, &1);
+        Store::get(&cache, &3);
+
+        // 10 bytes are stored; 3 more evict the least used entry only
+        assert_eq!(
+            Store::insert(&cache, 4, "zig".to_string()),
+            vec![(2, "go".to_string())]
+        );
+        assert_eq!(cache.bytes(), 11);
+
+        // the new entry is kept even if it alone is over the limit
+        let evicted = Store::insert(&cache, 5, "typescript!".to_string());
+        assert_eq!(evicted.len(), 3);
+        assert_eq!(Store::len(&cache), 1);
+        assert_eq!(cache.bytes(), 11);
+
+        // replacing the only entry doesn't evict it
+        assert!(Store::insert(&cache, 5, "c".to_string()).is_empty());
+        assert_eq!(cache.bytes(), 1);
+    }
+
+    #[test]
+    fn test_boxed_store() {
+        let stores: Vec<Box<dyn Store<u32, u32>>> =
+            vec![Box::new(LruCache::new(2)), Box::new(UnboundedCache::new())];
+        for store in stores {
+            store.insert(1, 1);
+            store.insert(2, 2);
+            store.insert(3, 3);
+            assert_eq!(store.peek(&3), Some(3));
+            assert_eq!(store.remove(&3), Some(3));
+        }
+    }
+
+    #[test]
+    fn test_hm() {
+        let map = ConcurrentHashMap::new();
+        map.insert(1, 1);
         map.insert(2, 2);
         map.insert(3, 3);
         map.insert(4, 4);
@@ -399,6 +2249,52 @@ mod tests {
         assert_eq!(map.get(&7), Some(7));
     }
 
+    #[test]
+    fn test_hm_buckets() {
+        use crossbeam_utils::thread;
+        let map = Arc::new(ConcurrentHashMap::with_buckets(64));
+        assert_eq!(map.buckets.len(), 64);
+        thread::scope(|s| {
+            for t in 0..8 {
+                let map = map.clone();
+                s.spawn(move |_| {
+                    for i in 0..1000 {
+                        map.insert(t * 1000 + i, i);
+                    }
+                });
+            }
+        })
+        .unwrap();
+
+        for k in 0..8000 {
+            assert_eq!(map.get(&k), Some(k % 1000));
+        }
+        let total: usize = map.buckets.iter().map(|b| b.read().unwrap().len()).sum();
+        assert_eq!(total, 8000);
+    }
+
+    #[test]
+    fn test_hm_independent_buckets() {
+        let map = ConcurrentHashMap::<u32, u32>::new();
+        for (i, a) in map.buckets.iter().enumerate() {
+            for b in &map.buckets[i + 1..] {
+                assert!(!Arc::ptr_eq(a, b));
+            }
+        }
+
+        // keys in different buckets can be written while another bucket is locked
+        let (k1, k2) = (0..)
+            .map(|k| (0, k))
+            .find(|&(k1, k2)| map.get_bucket(&k1) != map.get_bucket(&k2))
+            .unwrap();
+        let _guard = map.buckets[map.get_bucket(&k1) as usize].write().unwrap();
+        assert!(
+            map.buckets[map.get_bucket(&k2) as usize]
+                .try_write()
+                .is_ok()
+        );
+    }
+
     #[test]
     fn test_concurrent_ops() {
         use crossbeam_utils::thread;
@@ -415,4 +2311,292 @@ mod tests {
 
         assert_eq!(cache.len(), 5);
     }
+
+    /// checks `a` & `b` link to the same node, or both to none
+    fn same_node<K, V>(a: &Link<K, V>, b: &Link<K, V>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// checks the list of `cache` links up both ways & holds exactly the entries in its map
+    fn assert_consistent(cache: &LruCache<u32, u32>) {
+        let inner = cache.ll.inner.read().unwrap();
+        assert!(
+            inner
+                .head
+                .as_ref()
+                .is_none_or(|head| head.read().unwrap().prev.is_none())
+        );
+        assert!(
+            inner
+                .tail
+                .as_ref()
+                .is_none_or(|tail| tail.read().unwrap().next.is_none())
+        );
+
+        let mut forward = vec![];
+        let mut prev = None;
+        let mut p = inner.head.clone();
+        while let Some(n) = p {
+            let node = n.read().unwrap();
+            assert!(same_node(&node.prev, &prev), "bad prev link at {}", node.k);
+            forward.push(node.k);
+            p = node.next.clone();
+            prev = Some(n.clone());
+        }
+        assert!(same_node(&inner.tail, &prev));
+
+        let mut backward = vec![];
+        let mut p = inner.tail.clone();
+        while let Some(n) = p {
+            backward.push(n.read().unwrap().k);
+            p = n.read().unwrap().prev.clone();
+        }
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        let mut keys = cache.m.keys();
+        keys.sort();
+        forward.sort();
+        assert_eq!(forward, keys);
+        assert_eq!(forward.len(), cache.len());
+    }
+
+    /// a xorshift sequence, enough to pick operations without a rand dependency
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    /// runs `ops` random operations over `keys` keys on each of `threads` threads at once
+    fn stress(cache: &Arc<LruCache<u32, u32>>, threads: u32, ops: usize, keys: u32, seed: u32) {
+        let barrier = Arc::new(Barrier::new(threads as usize));
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let (cache, barrier) = (cache.clone(), barrier.clone());
+                thread::spawn(move || {
+                    // xorshift gets stuck at 0
+                    let mut rng = XorShift((seed ^ t.wrapping_mul(0x9e3779b9)) | 1);
+                    barrier.wait();
+                    for _ in 0..ops {
+                        let x = rng.next();
+                        let k = x % keys;
+                        match (x >> 8) % 100 {
+                            0..30 => {
+                                cache.insert(k, x);
+                            }
+                            30..35 => {
+                                cache.insert_with_ttl(k, x, Duration::from_millis(1));
+                            }
+                            35..60 => {
+                                cache.get(&k);
+                            }
+                            60..65 => {
+                                cache.peek(&k);
+                            }
+                            65..70 => {
+                                cache.get_or_insert_with(k, || x);
+                            }
+                            70..99 => {
+                                cache.remove(&k);
+                            }
+                            _ => {
+                                cache.clear();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_invariants() {
+        for (capacity, keys) in [(1, 4), (16, 32), (64, 48)] {
+            let cache = Arc::new(LruCache::new(capacity));
+            for seed in 1..=10 {
+                stress(&cache, 8, 2000, keys, seed);
+                // every round ends with all the threads joined, so the cache is quiescent
+                assert_consistent(&cache);
+                assert!(
+                    cache.len() <= capacity,
+                    "capacity {} seed {}",
+                    capacity,
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sharded() {
+        let cache = ShardedLruCache::new(10, 4);
+        assert_eq!(4, cache.shards());
+        // the threshold is split as evenly as it goes
+        let capacities: Vec<_> = cache.shards.iter().map(LruCache::capacity).collect();
+        assert_eq!(vec![3, 3, 2, 2], capacities);
+        assert_eq!(10, cache.capacity());
+
+        assert!(cache.is_empty());
+        cache.insert(1, "abhi");
+        cache.insert(2, "ash");
+        assert_eq!(Some("abhi"), cache.get(&1));
+        assert_eq!(Some("ash"), cache.peek(&2));
+        assert_eq!(2, cache.len());
+        // every key is in exactly one segment
+        let mut keys = cache.keys();
+        keys.sort();
+        assert_eq!(vec![1, 2], keys);
+        assert_eq!(
+            1,
+            cache
+                .shards
+                .iter()
+                .filter(|shard| shard.peek(&1).is_some())
+                .count()
+        );
+
+        assert_eq!(Some("abhi"), cache.remove(&1));
+        assert_eq!(None, cache.get(&1));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!("lilb", cache.get_or_insert_with(3, || "lilb"));
+    }
+
+    #[test]
+    fn test_sharded_budget() {
+        let cache = ShardedLruCache::new(1000, 8);
+        for i in 0..10000 {
+            cache.insert(i, i);
+            assert!(cache.len() <= 1000);
+        }
+        // every segment filled up & evicted its own entries
+        assert_eq!(1000, cache.len());
+        assert_eq!(9000, cache.evictions());
+        for shard in &cache.shards {
+            assert_eq!(shard.capacity(), shard.len());
+        }
+        // the most recent keys are spread across segments, so only roughly the last 1000 stay
+        let recent = (9000..10000).filter(|i| cache.peek(i).is_some()).count();
+        assert!(recent > 800, "{} of the last 1000 keys kept", recent);
+
+        let cache = ShardedLruCache::with_max_bytes(1000, 4);
+        for i in 0..1000 {
+            cache.insert(i, Bytes::from(vec![0; 10]));
+            assert!(cache.bytes() <= 1000);
+        }
+        assert!(cache.bytes() >= 900, "{} bytes kept", cache.bytes());
+    }
+
+    #[test]
+    fn test_sharded_concurrent() {
+        let cache = Arc::new(ShardedLruCache::new(64, 4));
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        let k = (t * 31 + i * 7) % 256;
+                        if i % 3 == 0 {
+                            cache.insert(k, i);
+                        } else {
+                            cache.get(&k);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for shard in &cache.shards {
+            assert_consistent(shard);
+            assert!(shard.len() <= shard.capacity());
+        }
+        assert!(cache.len() <= 64);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let cache = LruCache::new(10);
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.get(&0);
+
+        // the 6 least recently used entries go right away, oldest first
+        assert_eq!(
+            vec![(1, 1), (2, 2), (3, 3), (4, 4), (5, 5), (6, 6)],
+            cache.set_capacity(4)
+        );
+        assert_eq!(vec![7, 8, 9, 0], cache.keys());
+        assert_eq!(4, cache.capacity());
+        assert_eq!(6, cache.evictions());
+        assert_consistent(&cache);
+        assert_eq!(vec![(7, 7)], cache.insert(10, 10));
+
+        // growing evicts nothing & makes room for more
+        assert!(cache.set_capacity(6).is_empty());
+        assert!(cache.insert(11, 11).is_empty());
+        assert!(cache.insert(12, 12).is_empty());
+        assert_eq!(6, cache.len());
+        assert_consistent(&cache);
+    }
+
+    #[test]
+    fn test_set_capacity_stores() {
+        let evicting = EvictingCache::new(10, Lru::new());
+        let sharded = ShardedLruCache::new(10, 3);
+        for store in [
+            Box::new(evicting) as Box<dyn Store<u32, u32>>,
+            Box::new(sharded),
+        ] {
+            for i in 0..10 {
+                store.insert(i, i);
+            }
+            assert_eq!(6, store.set_capacity(4).unwrap().len());
+            assert_eq!(4, store.len());
+            for i in 10..20 {
+                store.insert(i, i);
+            }
+            assert_eq!(4, store.len());
+        }
+        assert_eq!(None, UnboundedCache::<u32, u32>::new().set_capacity(4));
+    }
+
+    #[test]
+    fn test_set_capacity_concurrent() {
+        let cache = Arc::new(LruCache::new(64));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        cache.insert((t * 2000 + i) % 512, i);
+                    }
+                })
+            })
+            .collect();
+        for capacity in [48, 32, 16, 8, 24] {
+            cache.set_capacity(capacity);
+            thread::yield_now();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_consistent(&cache);
+        assert!(cache.len() <= 24);
+    }
 }
@@ -1,39 +1,546 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "compression")]
+use crate::compression::{self, Compression};
+use bytes::{Bytes, BytesMut};
+use log::info;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub struct Client {
-    stream: TcpStream,
+/// result of a storage command
+#[derive(Debug, PartialEq)]
+pub enum StoreResult {
+    Stored,
+    NotStored,
+}
+
+/// result of a delete command
+#[derive(Debug, PartialEq)]
+pub enum DeleteResult {
+    Deleted,
+    NotFound,
+}
+
+/// returns the length of the reply at the start of `buf` once all of it has been read
+///
+/// `VALUE` replies are walked using their byte counts so values containing `END\r\n` or spanning
+/// several reads are framed correctly, a meta command's `VA` reply ends after the data block its
+/// byte count gives, `STAT`, `KEY` & `ITEM` replies end with their `END` line & any other reply
+/// ends with its first line
+fn reply_len(buf: &[u8]) -> Option<usize> {
+    if buf.starts_with(b"STAT ") || buf.starts_with(b"KEY ") || buf.starts_with(b"ITEM ") {
+        return buf
+            .windows(7)
+            .position(|w| w == b"\r\nEND\r\n")
+            .map(|end| end + 7);
+    }
+    if buf.starts_with(b"VA ") {
+        let header_end = buf.windows(2).position(|w| w == b"\r\n")?;
+        let len: usize = match str::from_utf8(&buf[..header_end])
+            .ok()
+            .and_then(|header| header.split(' ').nth(1))
+            .and_then(|len| len.parse().ok())
+        {
+            Some(len) => len,
+            // not something we can frame; hand over the line as is
+            None => return Some(header_end + 2),
+        };
+        let end = header_end + 2 + len + 2;
+        return (end <= buf.len()).then_some(end);
+    }
+    if !buf.starts_with(b"VALUE") {
+        return buf.windows(2).position(|w| w == b"\r\n").map(|end| end + 2);
+    }
+
+    let mut pos = 0;
+    loop {
+        let rest = &buf[pos..];
+        if rest.starts_with(b"END\r\n") {
+            return Some(pos + 5);
+        }
+        let header_end = rest.windows(2).position(|w| w == b"\r\n")?;
+        if !rest.starts_with(b"VALUE") {
+            // not something we can frame; hand it over as is
+            return Some(buf.len());
+        }
+        let len: usize = match str::from_utf8(&rest[..header_end])
+            .ok()
+            .and_then(|header| header.split(' ').nth(3))
+            .and_then(|len| len.parse().ok())
+        {
+            Some(len) => len,
+            None => return Some(buf.len()),
+        };
+        pos += header_end + 2 + len + 2;
+        if pos > buf.len() {
+            return None;
+        }
+    }
+}
+
+/// reads a whole reply off `stream`
+async fn read_reply_bytes<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Bytes> {
+    Ok(read_replies(stream, 1).await?.remove(0))
+}
+
+/// reads `n` whole replies off `stream`, those of as many pipelined requests
+pub(crate) async fn read_replies<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    n: usize,
+) -> std::io::Result<Vec<Bytes>> {
+    let mut replies = Vec::with_capacity(n);
+    let mut pending = BytesMut::new();
+    let mut buf = vec![0; 1024];
+    while replies.len() < n {
+        if let Some(len) = reply_len(&pending) {
+            replies.push(pending.split_to(len).freeze());
+            continue;
+        }
+        let size = stream.read(&mut buf).await?;
+        if size == 0 {
+            // the server closed the connection
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        pending.extend_from_slice(&buf[..size]);
+    }
+    Ok(replies)
+}
+
+/// The first value of a `get` or `gets` reply.
+#[derive(Debug, PartialEq)]
+pub enum GetResponse {
+    /// the key is stored
+    Hit {
+        /// the data block
+        value: Bytes,
+        /// opaque flags stored along with the value
+        flags: u32,
+        /// the value's cas token; only `gets` replies carry one
+        cas: Option<u64>,
+    },
+    /// the key isn't stored
+    Miss,
+}
+
+impl GetResponse {
+    /// parses the first `VALUE <key> <flags> <bytes> [<cas>]` block of `reply`, or its `END`
+    pub fn parse(reply: &[u8]) -> anyhow::Result<Self> {
+        Ok(match parse_block(reply)? {
+            Some((_, response, _)) => response,
+            None => GetResponse::Miss,
+        })
+    }
+
+    /// returns the value, `None` on a miss
+    pub fn into_value(self) -> Option<Bytes> {
+        match self {
+            GetResponse::Hit { value, .. } => Some(value),
+            GetResponse::Miss => None,
+        }
+    }
+}
+
+/// parses the `VALUE` block at the start of `reply` into its key & the value, along with the
+/// block's length; `None` at the `END` closing the reply
+fn parse_block(reply: &[u8]) -> anyhow::Result<Option<(String, GetResponse, usize)>> {
+    if reply.starts_with(b"END\r\n") {
+        return Ok(None);
+    }
+    let header_end = reply
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .filter(|_| reply.starts_with(b"VALUE "))
+        .ok_or_else(|| anyhow::anyhow!("unexpected reply {:?}", String::from_utf8_lossy(reply)))?;
+    let header: Vec<_> = str::from_utf8(&reply[..header_end])?.split(' ').collect();
+    let (key, flags, len, cas) = match header[..] {
+        [_, key, flags, len] => (key, flags, len, None),
+        [_, key, flags, len, cas] => (key, flags, len, Some(cas)),
+        _ => anyhow::bail!("malformed value header {:?}", header.join(" ")),
+    };
+    let len: usize = len.parse()?;
+    let start = header_end + 2;
+    match reply.get(start..start + len + 2) {
+        Some(data) if data.ends_with(b"\r\n") => {
+            let response = GetResponse::Hit {
+                value: Bytes::copy_from_slice(&data[..len]),
+                flags: flags.parse()?,
+                cas: cas.map(str::parse).transpose()?,
+            };
+            Ok(Some((key.to_string(), response, start + len + 2)))
+        }
+        Some(_) => anyhow::bail!("value longer than its byte count"),
+        None => anyhow::bail!("value shorter than its byte count"),
+    }
+}
+
+/// extracts every value of a multi-key `get` reply along with its key
+pub(crate) fn parse_values(reply: &[u8]) -> anyhow::Result<Vec<(String, Bytes)>> {
+    let mut values = vec![];
+    let mut pos = 0;
+    while let Some((key, response, len)) = parse_block(&reply[pos..])? {
+        values.extend(response.into_value().map(|value| (key, value)));
+        pos += len;
+    }
+    Ok(values)
+}
+
+/// extracts the version from a `version` reply
+pub(crate) fn parse_version(reply: &str) -> anyhow::Result<String> {
+    match reply.strip_prefix("VERSION ") {
+        Some(version) => Ok(version.trim_end().to_string()),
+        None => anyhow::bail!("unexpected reply {:?}", reply),
+    }
+}
+
+/// parses the reply to a `delete`, failing on anything but `DELETED` or `NOT_FOUND`
+pub(crate) fn parse_delete(reply: &str) -> anyhow::Result<DeleteResult> {
+    match reply.trim_end() {
+        "DELETED" => Ok(DeleteResult::Deleted),
+        "NOT_FOUND" => Ok(DeleteResult::NotFound),
+        _ => anyhow::bail!("unexpected reply {:?}", reply),
+    }
+}
+
+/// whether `e` means the server closed the connection, say on restarting
+fn is_closed(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+    )
+}
+
+/// a connection being opened
+type Connecting<'a, S> = Pin<Box<dyn Future<Output = std::io::Result<S>> + Send + 'a>>;
+
+/// opens a connection to `server`:`port` with `TCP_NODELAY` set so requests are sent as soon as
+/// they're written
+fn connect(server: &str, port: u16) -> Connecting<'_, TcpStream> {
+    Box::pin(async move {
+        let stream = TcpStream::connect((server, port)).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    })
+}
+
+/// where a `Client` connected to & how, so it can connect again once the connection is closed
+struct Origin<S> {
+    server: String,
+    port: u16,
+    connect: fn(&str, u16) -> Connecting<'_, S>,
+}
+
+/// A client for a single server.
+///
+/// Once the server closes the connection, say on restarting, a client created with `new` connects
+/// to it again & sends the request once more, whatever the method. The request may have been
+/// carried out before the connection closed, so a `delete` sent twice can report `NotFound` &
+/// a request passed to `command` is carried out twice unless it does the same when repeated.
+pub struct Client<S = TcpStream> {
+    stream: S,
+    /// set if the client opened the connection itself
+    origin: Option<Origin<S>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
 }
 
 impl Client {
+    /// connects to the server at `server`:`port` with `TCP_NODELAY` set so requests are sent as
+    /// soon as they're written
+    ///
+    /// should the server close the connection, the client connects to it again
     pub async fn new(server: &str, port: u16) -> anyhow::Result<Self> {
-        Ok(Self {
-            stream: TcpStream::connect(format!("{}:{}", server, port)).await?,
-        })
+        let mut client = Self::from_stream(connect(server, port).await?);
+        client.origin = Some(Origin {
+            server: server.to_string(),
+            port,
+            connect,
+        });
+        Ok(client)
+    }
+
+    /// sets or, with `false`, clears `TCP_NODELAY` on the connection
+    pub fn set_nodelay(&self, nodelay: bool) -> anyhow::Result<()> {
+        Ok(self.stream.set_nodelay(nodelay)?)
     }
+}
 
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// creates a `Client` talking to a server over an already connected `stream`
+    ///
+    /// the client can't connect again once the stream is closed
+    pub fn from_stream(stream: S) -> Self {
+        Self {
+            stream,
+            origin: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+        }
+    }
+
+    /// compresses large values as per `compression` when setting them & decompresses them when
+    /// getting them back
+    ///
+    /// the codec is recorded in the high bits of the flags, which are then no longer free to set
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// sets the `value` for the given `key` with `flags` and expiry time `exp_time`
     pub async fn set(
         &mut self,
         key: &str,
         flags: u32,
         exp_time: u32,
-        value: &str,
-    ) -> anyhow::Result<String> {
-        let _size = self
-            .stream
-            .write(format!("set {} {} {} {}", key, flags, exp_time, value).as_bytes())
+        value: &[u8],
+    ) -> anyhow::Result<StoreResult> {
+        #[cfg(feature = "compression")]
+        let (flags, value) = match &self.compression {
+            Some(compression) => compression.compress(flags, value)?,
+            None => (flags, value.into()),
+        };
+        #[cfg(feature = "compression")]
+        let value: &[u8] = &value;
+        let mut request =
+            format!("set {} {} {} {}\r\n", key, flags, exp_time, value.len()).into_bytes();
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n");
+
+        let reply = self.round_trip(&request).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        match reply.trim_end() {
+            "STORED" => Ok(StoreResult::Stored),
+            "NOT_STORED" => Ok(StoreResult::NotStored),
+            _ => anyhow::bail!("unexpected reply {:?}", reply),
+        }
+    }
+
+    /// gets the value for the given `key`, `None` if it isn't stored
+    pub async fn get(&mut self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        let reply = self
+            .round_trip(format!("get {}\r\n", key).as_bytes())
             .await?;
-        self.stream.flush().await?;
-        let mut buf = vec![0; 1024];
-        let size = self.stream.read(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf[..size]).into())
+        Ok(self.decompress(GetResponse::parse(&reply)?)?.into_value())
     }
 
-    pub async fn get(&mut self, key: &str) -> anyhow::Result<String> {
-        let _size = self.stream.write(format!("get {}", key).as_bytes()).await?;
+    /// gets the value for the given `key` along with its flags & cas token
+    pub async fn gets(&mut self, key: &str) -> anyhow::Result<GetResponse> {
+        let reply = self
+            .round_trip(format!("gets {}\r\n", key).as_bytes())
+            .await?;
+        self.decompress(GetResponse::parse(&reply)?)
+    }
+
+    /// writes `request` & reads its reply, connecting again & sending `request` once more if the
+    /// server closed the connection & the client knows where it connected to
+    async fn round_trip(&mut self, request: &[u8]) -> anyhow::Result<Bytes> {
+        match self.send(request).await {
+            Err(e) if is_closed(&e) && self.origin.is_some() => {
+                let origin = self.origin.as_ref().unwrap();
+                info!(
+                    "connecting to {}:{} again: {}",
+                    origin.server, origin.port, e
+                );
+                self.stream = (origin.connect)(&origin.server, origin.port).await?;
+                Ok(self.send(request).await?)
+            }
+            reply => Ok(reply?),
+        }
+    }
+
+    /// writes `request` & reads its reply
+    async fn send(&mut self, request: &[u8]) -> std::io::Result<Bytes> {
+        self.stream.write_all(request).await?;
         self.stream.flush().await?;
-        let mut buf = vec![0; 1024];
-        let size = self.stream.read(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf[..size]).into())
+        read_reply_bytes(&mut self.stream).await
+    }
+
+    /// decompresses the value in `response` if this client compresses values & it's compressed
+    fn decompress(&self, response: GetResponse) -> anyhow::Result<GetResponse> {
+        #[cfg(feature = "compression")]
+        if let (Some(_), GetResponse::Hit { value, flags, cas }) = (&self.compression, &response) {
+            let (flags, value) = compression::decompress(*flags, value.clone())?;
+            return Ok(GetResponse::Hit {
+                value,
+                flags,
+                cas: *cas,
+            });
+        }
+        Ok(response)
+    }
+
+    /// deletes the given `key`, failing on any reply but `DELETED` or `NOT_FOUND`
+    pub async fn delete(&mut self, key: &str) -> anyhow::Result<DeleteResult> {
+        let reply = self
+            .round_trip(format!("delete {}\r\n", key).as_bytes())
+            .await?;
+        parse_delete(&String::from_utf8_lossy(&reply))
+    }
+
+    /// gets the server's version
+    pub async fn version(&mut self) -> anyhow::Result<String> {
+        let reply = self.round_trip(b"version\r\n").await?;
+        parse_version(&String::from_utf8_lossy(&reply))
+    }
+
+    /// checks the server is alive, failing unless it answers `PONG`
+    pub async fn ping(&mut self) -> anyhow::Result<()> {
+        match &self.round_trip(b"ping\r\n").await?[..] {
+            b"PONG\r\n" => Ok(()),
+            reply => Err(anyhow::anyhow!(
+                "unexpected reply {:?}",
+                String::from_utf8_lossy(reply)
+            )),
+        }
+    }
+
+    /// writes `raw` to the server as is & returns the reply, for commands there's no method for
+    ///
+    /// framing the request, `\r\n`s & any data block included, is up to the caller. the reply is
+    /// read as the other methods read theirs: `VALUE` & `STAT` replies up to their `END` line, a
+    /// meta command's `VA` reply up to the end of its data block & any other up to the end of its
+    /// first line, so a reply spanning more lines than that leaves the rest to be read with the
+    /// next command's reply
+    pub async fn command(&mut self, raw: &[u8]) -> anyhow::Result<Bytes> {
+        self.round_trip(raw).await
+    }
+
+    /// sets `value`, serialized as json, for the given `key` with expiry time `exp_time`
+    #[cfg(feature = "serde")]
+    pub async fn set_json<T: serde::Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &T,
+        exp_time: u32,
+    ) -> anyhow::Result<StoreResult> {
+        self.set(key, 0, exp_time, &serde_json::to_vec(value)?)
+            .await
+    }
+
+    /// gets the value for the given `key` deserialized from json, `None` if it isn't stored
+    #[cfg(feature = "serde")]
+    pub async fn get_json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        key: &str,
+    ) -> anyhow::Result<Option<T>> {
+        match self.get(key).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{Client, DeleteResult, GetResponse, parse_delete, reply_len};
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_reply_len() {
+        assert_eq!(None, reply_len(b"STORED"));
+        assert_eq!(Some(8), reply_len(b"STORED\r\nEND\r\n"));
+        assert_eq!(Some(5), reply_len(b"END\r\n"));
+        assert_eq!(None, reply_len(b"VALUE abhi 0 4\r\nru"));
+        assert_eq!(None, reply_len(b"VALUE abhi 0 4\r\nrust\r\n"));
+
+        // the data contains what looks like the end of the reply
+        let reply = b"VALUE abhi 0 5\r\nEND\r\n\r\nEND\r\n";
+        assert_eq!(None, reply_len(&reply[..21]));
+        assert_eq!(Some(reply.len()), reply_len(reply));
+
+        let reply = b"VALUE abhi 0 4\r\nrust\r\nVALUE ash 0 2\r\ngo\r\nEND\r\n";
+        assert_eq!(Some(reply.len()), reply_len(reply));
+
+        let reply = b"STAT cmd_set 2\r\nSTAT curr_items 1\r\nEND\r\n";
+        assert_eq!(None, reply_len(&reply[..16]));
+        assert_eq!(Some(reply.len()), reply_len(reply));
+
+        let reply = b"KEY abhi\r\nKEY ash\r\nEND\r\n";
+        assert_eq!(None, reply_len(&reply[..19]));
+        assert_eq!(Some(reply.len()), reply_len(reply));
+
+        let reply = b"ITEM abhi [4 b; 0 s]\r\nEND\r\n";
+        assert_eq!(None, reply_len(&reply[..22]));
+        assert_eq!(Some(reply.len()), reply_len(reply));
+
+        // the data block holds what looks like the end of a line
+        let reply = b"VA 6 f0 c3\r\nru\r\nst\r\nHD\r\n";
+        assert_eq!(None, reply_len(&reply[..15]));
+        assert_eq!(Some(20), reply_len(reply));
+        assert_eq!(Some(4), reply_len(b"HD\r\nEN\r\n"));
+    }
+
+    #[test]
+    fn test_get_response() {
+        assert_eq!(GetResponse::Miss, GetResponse::parse(b"END\r\n").unwrap());
+        assert_eq!(
+            GetResponse::Hit {
+                value: Bytes::from("NOT FOUND"),
+                flags: 0,
+                cas: None
+            },
+            GetResponse::parse(b"VALUE abhi 0 9\r\nNOT FOUND\r\nEND\r\n").unwrap()
+        );
+        assert_eq!(
+            GetResponse::Hit {
+                value: Bytes::from("ru\r\nst"),
+                flags: 42,
+                cas: Some(7)
+            },
+            GetResponse::parse(b"VALUE abhi 42 6 7\r\nru\r\nst\r\nEND\r\n").unwrap()
+        );
+        // only the first value is parsed
+        assert_eq!(
+            Some(Bytes::from("rust")),
+            GetResponse::parse(b"VALUE abhi 1 4\r\nrust\r\nVALUE ash 2 2\r\ngo\r\nEND\r\n")
+                .unwrap()
+                .into_value()
+        );
+        assert_eq!(None, GetResponse::Miss.into_value());
+
+        for reply in [
+            &b"SERVER_ERROR out of memory\r\n"[..],
+            b"VALUE abhi 0\r\nrust\r\nEND\r\n",
+            b"VALUE abhi x 4\r\nrust\r\nEND\r\n",
+            b"VALUE abhi 0 4 x\r\nrust\r\nEND\r\n",
+            b"VALUE abhi 0 4 1 2\r\nrust\r\nEND\r\n",
+            b"VALUE abhi 0 8\r\nrust\r\n",
+            b"VALUE abhi 0 2\r\nrust\r\nEND\r\n",
+        ] {
+            assert!(GetResponse::parse(reply).is_err(), "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn test_parse_delete() {
+        assert_eq!(DeleteResult::Deleted, parse_delete("DELETED\r\n").unwrap());
+        assert_eq!(
+            DeleteResult::NotFound,
+            parse_delete("NOT_FOUND\r\n").unwrap()
+        );
+        for reply in [
+            "NOT_STORED\r\n",
+            "SERVER_ERROR out of memory\r\n",
+            "DELETED NOW\r\n",
+        ] {
+            assert!(parse_delete(reply).is_err(), "{:?}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nodelay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = Client::new("127.0.0.1", port).await.unwrap();
+        assert!(client.stream.nodelay().unwrap());
+        client.set_nodelay(false).unwrap();
+        assert!(!client.stream.nodelay().unwrap());
     }
 }
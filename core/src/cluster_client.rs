@@ -1,69 +1,1433 @@
-use log::info;
-use murmur3::murmur3_32;
-use std::io::Cursor;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::client::{DeleteResult, parse_delete, parse_values, parse_version, read_replies};
+use crate::distributor::{Distribution, KeyDistributor};
+use crate::hash_ring::DEFAULT_VIRTUAL_NODES;
+use bytes::Bytes;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
-pub struct ClusterClient {
-    streams: Vec<Option<TcpStream>>,
+/// number of times a request is retried on a fresh connection after the old one broke, unless set
+/// with `ClusterClientBuilder::retries`
+pub const DEFAULT_RETRIES: usize = 1;
+
+/// number of connections kept to each server unless set with `ClusterClientBuilder::pool_size`
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// number of requests to a server that fail in a row before it's marked down, unless set with
+/// `ClusterClientBuilder::failure_threshold`
+pub const DEFAULT_FAILURE_THRESHOLD: usize = 3;
+
+/// how long a server marked down is passed over before it's tried again, unless set with
+/// `ClusterClientBuilder::probe_interval`
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// errors returned by `ClusterClient`
+#[derive(Debug)]
+pub enum ClusterError {
+    /// a connection to the server couldn't be established
+    Unreachable {
+        server: String,
+        port: u16,
+        source: std::io::Error,
+    },
+    /// a `set` or `delete` was applied on some of the key's replicas but failed on the `failed`
+    /// servers
+    PartialWrite {
+        written: usize,
+        failed: Vec<(String, u16)>,
+    },
+    /// the server didn't accept a connection or answer a request within the client's `timeout`
+    Timeout {
+        server: String,
+        port: u16,
+        timeout: Duration,
+    },
+}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterError::Unreachable {
+                server,
+                port,
+                source,
+            } => write!(f, "couldn't connect to {}:{}: {}", server, port, source),
+            ClusterError::PartialWrite { written, failed } => write!(
+                f,
+                "written to {} replicas but failed on {:?}",
+                written, failed
+            ),
+            ClusterError::Timeout {
+                server,
+                port,
+                timeout,
+            } => write!(f, "{}:{} timed out after {:?}", server, port, timeout),
+        }
+    }
+}
+
+impl std::error::Error for ClusterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClusterError::Unreachable { source, .. } => Some(source),
+            ClusterError::PartialWrite { .. } | ClusterError::Timeout { .. } => None,
+        }
+    }
+}
+
+/// the stats of every server in a cluster, as returned by `ClusterClient::stats`
+#[derive(Debug, Default)]
+pub struct ClusterStats {
+    /// each server's stats, keyed by its address
+    pub nodes: HashMap<(String, u16), HashMap<String, u64>>,
+    /// every stat summed over all the servers
+    pub totals: HashMap<String, u64>,
+}
+
+/// the connections to one server, opened as needed up to the pool's size, & how it's been doing
+struct Pool {
+    idle: Mutex<Vec<TcpStream>>,
+    permits: Semaphore,
+    /// requests that failed in a row
+    failures: AtomicUsize,
+    /// when the server was last marked down
+    down_since: Mutex<Option<Instant>>,
+}
+
+impl Pool {
+    fn new(size: usize) -> Self {
+        Self {
+            idle: Mutex::new(vec![]),
+            permits: Semaphore::new(size.max(1)),
+            failures: AtomicUsize::new(0),
+            down_since: Mutex::new(None),
+        }
+    }
+}
+
+/// a connection checked out of a `Pool`
+///
+/// it is only put back by `checkin`, so one dropped midway through a request, with its reply
+/// still unread, is closed instead of being handed to the next request
+struct PooledStream<'a> {
+    stream: TcpStream,
+    pool: &'a Pool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledStream<'_> {
+    /// returns the connection to the pool for the next request
+    fn checkin(self) {
+        self.pool.idle.lock().unwrap().push(self.stream);
+    }
+}
+
+impl Deref for PooledStream<'_> {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl DerefMut for PooledStream<'_> {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+/// configures a `ClusterClient`, started with `ClusterClient::builder`
+pub struct ClusterClientBuilder {
     cluster: Vec<(String, u16)>,
+    replicas: usize,
+    distribution: Distribution,
+    virtual_nodes: usize,
+    seed: u32,
+    pool_size: usize,
+    timeout: Option<Duration>,
+    retries: usize,
+    nodelay: bool,
+    failure_threshold: usize,
+    probe_interval: Duration,
+}
+
+impl ClusterClientBuilder {
+    /// stores every key on `replicas` servers
+    pub fn replicas(mut self, replicas: usize) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// spreads keys over the servers as per `distribution`
+    pub fn distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// places each server at `virtual_nodes` points on the hash ring
+    pub fn virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.virtual_nodes = virtual_nodes;
+        self
+    }
+
+    /// hashes keys with `seed`, to place them as other clients using that seed do
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// keeps up to `size` connections to each server so that many requests to one server can be in
+    /// flight at once
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// gives up on connecting to a server, or on a request to it, after `timeout` so a node that
+    /// stopped answering doesn't hang the caller
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// retries a request up to `retries` times on a fresh connection after the old one broke
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// sets or, with `false`, leaves off `TCP_NODELAY` on the connections; on by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// marks a server down once `failures` requests to it have failed in a row, sending its keys
+    /// to the servers after it until it's back; 0 never marks a server down
+    pub fn failure_threshold(mut self, failures: usize) -> Self {
+        self.failure_threshold = failures;
+        self
+    }
+
+    /// tries a server marked down again once `interval` has passed, bringing it back if the
+    /// request succeeds & passing it over for another `interval` if not
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// creates the configured `ClusterClient`
+    pub fn build(self) -> ClusterClient {
+        ClusterClient {
+            pools: self
+                .cluster
+                .iter()
+                .map(|_| Pool::new(self.pool_size))
+                .collect(),
+            distributor: self.distribution.distributor(
+                &self.cluster,
+                self.virtual_nodes,
+                self.seed,
+            ),
+            replicas: self.replicas.max(1),
+            timeout: self.timeout,
+            retries: self.retries,
+            nodelay: self.nodelay,
+            failure_threshold: self.failure_threshold,
+            probe_interval: self.probe_interval,
+        }
+    }
+}
+
+pub struct ClusterClient {
+    pools: Vec<Pool>,
+    distributor: Box<dyn KeyDistributor>,
+    replicas: usize,
+    timeout: Option<Duration>,
+    retries: usize,
+    nodelay: bool,
+    failure_threshold: usize,
+    probe_interval: Duration,
 }
 
 impl ClusterClient {
-    pub fn new(cluster: &[(&str, u16)]) -> Self {
-        Self {
-            streams: vec![None, None, None],
+    /// creates a `ClusterClient` storing every key on `replicas` servers
+    pub fn new(cluster: &[(&str, u16)], replicas: usize) -> Self {
+        Self::builder(cluster).replicas(replicas).build()
+    }
+
+    /// creates a `ClusterClient` placing each server at `virtual_nodes` points on the hash ring
+    pub fn with_virtual_nodes(
+        cluster: &[(&str, u16)],
+        replicas: usize,
+        virtual_nodes: usize,
+    ) -> Self {
+        Self::builder(cluster)
+            .replicas(replicas)
+            .virtual_nodes(virtual_nodes)
+            .build()
+    }
+
+    /// starts configuring a `ClusterClient` for the servers in `cluster`
+    pub fn builder(cluster: &[(&str, u16)]) -> ClusterClientBuilder {
+        ClusterClientBuilder {
             cluster: cluster.iter().map(|&(k, v)| (k.to_string(), v)).collect(),
+            replicas: 1,
+            distribution: Distribution::default(),
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            seed: 0,
+            pool_size: DEFAULT_POOL_SIZE,
+            timeout: None,
+            retries: DEFAULT_RETRIES,
+            nodelay: true,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            probe_interval: DEFAULT_PROBE_INTERVAL,
         }
     }
 
-    /// sets the `value` for the given `key` with `flags` and expiry time `exp_time`
+    /// sets the `value` for the given `key` with `flags` and expiry time `exp_time` on each of the
+    /// key's replicas
+    ///
+    /// returns the primary's reply if every replica stored the value and
+    /// `ClusterError::PartialWrite` if only some of them did
     pub async fn set(
-        &mut self,
+        &self,
         key: &str,
         flags: u32,
         exp_time: u32,
         value: &str,
     ) -> anyhow::Result<String> {
-        let stream = self.get_stream(key).await?;
-        info!("storing key in {:?}", stream.peer_addr());
+        self.write(key, &set_request(key, flags, exp_time, value))
+            .await
+    }
 
-        let _ = stream
-            .write(format!("set {} {} {} {}", key, flags, exp_time, value).as_bytes())
-            .await?;
-        stream.flush().await?;
+    /// sets every `(key, flags, exp_time, value)` of `items` on each of the key's replicas,
+    /// returning the primary's reply for each in the order given
+    ///
+    /// the sets are grouped by server & pipelined, so a batch costs a round trip per server
+    /// rather than one per key. if any server fails its error is returned, the sets on the other
+    /// servers having been made all the same
+    pub async fn set_many(&self, items: &[(&str, u32, u32, &str)]) -> anyhow::Result<Vec<String>> {
+        // the indices of the items each server stores, & whether it is their primary
+        let mut batches: BTreeMap<usize, Vec<(usize, bool)>> = BTreeMap::new();
+        for (i, (key, ..)) in items.iter().enumerate() {
+            for (replica, server_index) in self.server_indices(key)?.into_iter().enumerate() {
+                batches
+                    .entry(server_index)
+                    .or_default()
+                    .push((i, replica == 0));
+            }
+        }
+
+        let mut replies = vec![String::new(); items.len()];
+        let mut errors = vec![];
+        for (server_index, batch) in batches {
+            let request: String = batch
+                .iter()
+                .map(|&(i, _)| {
+                    let (key, flags, exp_time, value) = items[i];
+                    set_request(key, flags, exp_time, value)
+                })
+                .collect();
+            match self
+                .pipeline(server_index, request.as_bytes(), batch.len())
+                .await
+            {
+                Ok(server_replies) => {
+                    for ((i, primary), reply) in batch.into_iter().zip(server_replies) {
+                        if primary {
+                            replies[i] = String::from_utf8_lossy(&reply).into();
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "couldn't write keys to {:?}: {}",
+                        self.distributor.nodes()[server_index],
+                        e
+                    );
+                    errors.push(e);
+                }
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(replies),
+        }
+    }
+
+    /// deletes the given `key` from each of its replicas
+    ///
+    /// returns the primary's result if every replica was reached and
+    /// `ClusterError::PartialWrite` if only some of them were
+    pub async fn delete(&self, key: &str) -> anyhow::Result<DeleteResult> {
+        parse_delete(&self.write(key, &format!("delete {}\r\n", key)).await?)
+    }
 
-        let mut buf = vec![0; 1024];
-        let size = stream.read(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf[..size]).into())
+    /// sends the write `request` for `key` to each of the key's replicas
+    async fn write(&self, key: &str, request: &str) -> anyhow::Result<String> {
+        let mut reply = None;
+        let mut errors = vec![];
+        // fewer than `replicas` once some are down
+        let server_indices = self.server_indices(key)?;
+        let targeted = server_indices.len();
+        for server_index in server_indices {
+            match self.request(server_index, request.as_bytes()).await {
+                Ok(r) => {
+                    reply.get_or_insert(r);
+                }
+                Err(e) => {
+                    warn!(
+                        "couldn't write key to {:?}: {}",
+                        self.distributor.nodes()[server_index],
+                        e
+                    );
+                    errors.push((server_index, e));
+                }
+            }
+        }
+
+        match reply {
+            Some(reply) if errors.is_empty() => Ok(reply),
+            Some(_) => Err(ClusterError::PartialWrite {
+                written: targeted - errors.len(),
+                failed: errors
+                    .into_iter()
+                    .map(|(server_index, _)| self.distributor.nodes()[server_index].clone())
+                    .collect(),
+            }
+            .into()),
+            None => Err(errors.remove(0).1),
+        }
     }
 
-    /// gets the value for the given `key`
-    pub async fn get(&mut self, key: &str) -> anyhow::Result<String> {
-        let stream = self.get_stream(key).await?;
+    /// gets the value for the given `key`, falling back to the next replica if a server is down
+    pub async fn get(&self, key: &str) -> anyhow::Result<String> {
+        let request = format!("get {}\r\n", key);
+        let mut last_error = None;
+        for server_index in self.server_indices(key)? {
+            match self.request(server_index, request.as_bytes()).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => {
+                    warn!(
+                        "couldn't get key from {:?}: {}",
+                        self.distributor.nodes()[server_index],
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// gets the values for `keys` in the order given, `None` for those that aren't stored
+    ///
+    /// the keys are grouped by server & each server is sent a single multi-key `get`, so a batch
+    /// costs a round trip per server rather than one per key. the keys of a server that fails are
+    /// asked of their next replica, as with `get`
+    pub async fn get_many(&self, keys: &[&str]) -> anyhow::Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        // the indices of the keys yet to be got, along with the servers holding them
+        let mut pending = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| Ok((i, self.server_indices(key)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut last_error = None;
+        for replica in 0..self.replicas {
+            let mut batches: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+            for (i, server_indices) in &pending {
+                if let Some(&server_index) = server_indices.get(replica) {
+                    batches.entry(server_index).or_default().push(*i);
+                }
+            }
+
+            let mut failed = vec![];
+            for (server_index, batch) in batches {
+                let batch_keys: Vec<_> = batch.iter().map(|&i| keys[i]).collect();
+                let request = format!("get {}\r\n", batch_keys.join(" "));
+                let reply = self.pipeline(server_index, request.as_bytes(), 1).await;
+                match reply.and_then(|reply| parse_values(&reply[0])) {
+                    Ok(found) => {
+                        let found: HashMap<_, _> = found.into_iter().collect();
+                        for i in batch {
+                            values[i] = found
+                                .get(keys[i])
+                                .map(|value| String::from_utf8_lossy(value).into());
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            "couldn't get keys from {:?}: {}",
+                            self.distributor.nodes()[server_index],
+                            e
+                        );
+                        failed.extend(batch);
+                        last_error = Some(e);
+                    }
+                }
+            }
+            pending.retain(|(i, _)| failed.contains(i));
+            if pending.is_empty() {
+                break;
+            }
+        }
+        match last_error {
+            Some(e) if !pending.is_empty() => Err(e),
+            _ => Ok(values),
+        }
+    }
+
+    /// gets the version of the first server in the cluster that answers
+    pub async fn version(&self) -> anyhow::Result<String> {
+        let mut last_error = None;
+        for server_index in 0..self.distributor.nodes().len() {
+            match self.request(server_index, b"version\r\n").await {
+                Ok(reply) => return parse_version(&reply),
+                Err(e) => {
+                    warn!(
+                        "couldn't get the version of {:?}: {}",
+                        self.distributor.nodes()[server_index],
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no servers in the cluster")))
+    }
+
+    /// gets the version of every server in the cluster, keyed by its address
+    pub async fn versions(&self) -> anyhow::Result<HashMap<(String, u16), String>> {
+        let mut versions = HashMap::new();
+        for (server_index, node) in self.distributor.nodes().iter().enumerate() {
+            let reply = self.request(server_index, b"version\r\n").await?;
+            versions.insert(node.clone(), parse_version(&reply)?);
+        }
+        Ok(versions)
+    }
+
+    /// pings every server in the cluster, returning whether each answered, keyed by its address
+    ///
+    /// the idle connections to a server that didn't answer are closed as they're likely broken
+    ///
+    /// a server that accepts connections but hangs holds this up until the client's `timeout`
+    pub async fn ping_all(&self) -> HashMap<(String, u16), bool> {
+        let mut health = HashMap::new();
+        for (server_index, node) in self.distributor.nodes().iter().enumerate() {
+            let alive = match self.request(server_index, b"ping\r\n").await {
+                Ok(reply) if reply == "PONG\r\n" => true,
+                Ok(reply) => {
+                    warn!("unexpected reply {:?} to a ping of {:?}", reply, node);
+                    false
+                }
+                Err(e) => {
+                    warn!("couldn't ping {:?}: {}", node, e);
+                    false
+                }
+            };
+            if !alive {
+                self.pools[server_index].idle.lock().unwrap().clear();
+            }
+            health.insert(node.clone(), alive);
+        }
+        health
+    }
+
+    /// returns the servers marked down after failing `failure_threshold` requests in a row, whose
+    /// keys are sent to the servers after them for now
+    pub fn down_nodes(&self) -> Vec<(String, u16)> {
+        (0..self.pools.len())
+            .filter(|&server_index| self.is_down(server_index))
+            .map(|server_index| self.distributor.nodes()[server_index].clone())
+            .collect()
+    }
+
+    /// gets the stats of every server in the cluster along with their totals
+    ///
+    /// stats whose value isn't a number, like a version, are left out
+    pub async fn stats(&self) -> anyhow::Result<ClusterStats> {
+        let mut stats = ClusterStats::default();
+        for server_index in 0..self.distributor.nodes().len() {
+            let reply = self.request(server_index, b"stats\r\n").await?;
+            let mut node = HashMap::new();
+            for line in reply.lines().take_while(|&line| line != "END") {
+                let (name, value) = line
+                    .strip_prefix("STAT ")
+                    .and_then(|stat| stat.split_once(' '))
+                    .ok_or_else(|| anyhow::anyhow!("unexpected reply {:?}", reply))?;
+                if let Ok(value) = value.parse::<u64>() {
+                    *stats.totals.entry(name.to_string()).or_default() += value;
+                    node.insert(name.to_string(), value);
+                }
+            }
+            stats
+                .nodes
+                .insert(self.distributor.nodes()[server_index].clone(), node);
+        }
+        Ok(stats)
+    }
+
+    /// sends `request` to the server at `server_index` & returns its reply
+    async fn request(&self, server_index: usize, request: &[u8]) -> anyhow::Result<String> {
+        let reply = self.pipeline(server_index, request, 1).await?.remove(0);
+        Ok(String::from_utf8_lossy(&reply).into())
+    }
+
+    /// sends `requests`, `n` pipelined requests, to the server at `server_index` in one go &
+    /// returns their replies
+    ///
+    /// a broken connection is dropped, along with the idle ones to the same server as they're
+    /// likely broken too, & the requests are retried on a fresh one
+    ///
+    /// requests that time out aren't retried & their connection, with the replies still to come,
+    /// is closed
+    async fn pipeline(
+        &self,
+        server_index: usize,
+        requests: &[u8],
+        n: usize,
+    ) -> anyhow::Result<Vec<Bytes>> {
+        let replies: anyhow::Result<_> = async {
+            let mut attempt = 0;
+            loop {
+                let mut stream = self.checkout_stream(server_index).await?;
+                let round_trip = Self::round_trip(&mut stream, requests, n);
+                match self.with_timeout(server_index, round_trip).await? {
+                    Ok(replies) => {
+                        stream.checkin();
+                        return Ok(replies);
+                    }
+                    Err(e) if attempt < self.retries => {
+                        warn!(
+                            "connection to {:?} broke ({}), reconnecting",
+                            self.distributor.nodes()[server_index],
+                            e
+                        );
+                        self.pools[server_index].idle.lock().unwrap().clear();
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        .await;
+        self.record(server_index, replies.is_ok());
+        replies
+    }
+
+    /// counts a request to the server at `server_index` towards marking it down or, if it
+    /// `succeeded`, brings the server back
+    fn record(&self, server_index: usize, succeeded: bool) {
+        let pool = &self.pools[server_index];
+        let node = &self.distributor.nodes()[server_index];
+        let mut down_since = pool.down_since.lock().unwrap();
+        if succeeded {
+            pool.failures.store(0, Ordering::Relaxed);
+            if down_since.take().is_some() {
+                info!("{:?} is back up", node);
+            }
+            return;
+        }
+
+        let failures = pool.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.failure_threshold > 0 && failures >= self.failure_threshold {
+            if down_since.is_none() {
+                warn!("marking {:?} down after {} failures", node, failures);
+            }
+            // a failed probe passes the server over for another interval
+            *down_since = Some(Instant::now());
+        }
+    }
 
-        let _ = stream.write(format!("get {}", key).as_bytes()).await?;
+    /// checks whether the server at `server_index` is marked down & not due to be tried again
+    fn is_down(&self, server_index: usize) -> bool {
+        self.pools[server_index]
+            .down_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| since.elapsed() < self.probe_interval)
+    }
+
+    /// awaits `future`, failing with `ClusterError::Timeout` if it outlasts the client's timeout
+    async fn with_timeout<T>(
+        &self,
+        server_index: usize,
+        future: impl Future<Output = T>,
+    ) -> Result<T, ClusterError> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, future).await.map_err(|_| {
+                let (server, port) = self.distributor.nodes()[server_index].clone();
+                ClusterError::Timeout {
+                    server,
+                    port,
+                    timeout,
+                }
+            }),
+            None => Ok(future.await),
+        }
+    }
+
+    /// writes `requests` to `stream` & reads the replies to the `n` of them
+    async fn round_trip(
+        stream: &mut TcpStream,
+        requests: &[u8],
+        n: usize,
+    ) -> std::io::Result<Vec<Bytes>> {
+        stream.write_all(requests).await?;
         stream.flush().await?;
+        read_replies(stream, n).await
+    }
+
+    /// gets the indices of the servers holding `key`, primary first, as per the distribution
+    ///
+    /// servers marked down are passed over for the ones after them, unless every server is down
+    fn server_indices(&self, key: &str) -> anyhow::Result<Vec<usize>> {
+        let all = self.distributor.node_indices(key, self.pools.len());
+        if all.is_empty() {
+            anyhow::bail!("no servers in the cluster");
+        }
+        let up: Vec<_> = all
+            .iter()
+            .copied()
+            .filter(|&server_index| !self.is_down(server_index))
+            .take(self.replicas)
+            .collect();
+        if up.is_empty() {
+            return Ok(all.into_iter().take(self.replicas).collect());
+        }
+        Ok(up)
+    }
+
+    /// checks out a connection to the server at `server_index`, connecting if none is idle
+    ///
+    /// waits for one to be checked in once the pool's size are all in use
+    async fn checkout_stream(&self, server_index: usize) -> anyhow::Result<PooledStream<'_>> {
+        let pool = &self.pools[server_index];
+        let permit = pool.permits.acquire().await?;
+        let idle = pool.idle.lock().unwrap().pop();
+        let stream = match idle {
+            Some(stream) => stream,
+            None => {
+                let (server, port) = &self.distributor.nodes()[server_index];
+                let connect = TcpStream::connect((server.as_str(), *port));
+                let stream = self
+                    .with_timeout(server_index, connect)
+                    .await?
+                    .map_err(|source| ClusterError::Unreachable {
+                        server: server.clone(),
+                        port: *port,
+                        source,
+                    })?;
+                stream.set_nodelay(self.nodelay)?;
+                info!("connected to {:?}", stream.peer_addr());
+                stream
+            }
+        };
+        Ok(PooledStream {
+            stream,
+            pool,
+            _permit: permit,
+        })
+    }
+}
+
+/// formats a `set` request
+fn set_request(key: &str, flags: u32, exp_time: u32, value: &str) -> String {
+    format!(
+        "set {} {} {} {}\r\n{}\r\n",
+        key,
+        flags,
+        exp_time,
+        value.len(),
+        value
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::DeleteResult;
+    use crate::cluster_client::{ClusterClient, ClusterError};
+    use crate::distributor::Distribution;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::{JoinHandle, JoinSet};
+
+    /// starts a server on `port` that understands just enough of `set`, `get`, `delete` & `stats`
+    /// to store keys in memory
+    ///
+    /// pipelined requests are answered together & `stats` counts the reads that brought requests
+    /// as `reads`, the round trips made to the server. aborting the server also closes the
+    /// connections it accepted
+    async fn mock_server(port: u16) -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let store = Arc::new(Mutex::new(HashMap::new()));
+            let sets = Arc::new(AtomicUsize::new(0));
+            let reads = Arc::new(AtomicUsize::new(0));
+            // dropped, aborting every connection, when the server is
+            let mut connections = JoinSet::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (store, sets, reads) = (store.clone(), sets.clone(), reads.clone());
+                connections.spawn(async move {
+                    let mut pending = String::new();
+                    let mut buf = vec![0; 1024];
+                    while let Ok(size) = stream.read(&mut buf).await {
+                        if size == 0 {
+                            break;
+                        }
+                        reads.fetch_add(1, Ordering::SeqCst);
+                        pending.push_str(&String::from_utf8_lossy(&buf[..size]));
+                        let mut reply = String::new();
+                        while let Some(end) = pending.find("\r\n") {
+                            let args: Vec<_> = pending[..end].split(' ').collect();
+                            let mut len = end + 2;
+                            if args[0] == "set" {
+                                // wait for the whole data block
+                                len += args[4].parse::<usize>().unwrap() + 2;
+                                if pending.len() < len {
+                                    break;
+                                }
+                            }
+                            let mut store = store.lock().unwrap();
+                            match args[0] {
+                                "set" => {
+                                    let value = pending[end + 2..len - 2].to_string();
+                                    store.insert(args[1].to_string(), value);
+                                    sets.fetch_add(1, Ordering::SeqCst);
+                                    reply.push_str("STORED\r\n");
+                                }
+                                "delete" => match store.remove(args[1]) {
+                                    Some(_) => reply.push_str("DELETED\r\n"),
+                                    None => reply.push_str("NOT_FOUND\r\n"),
+                                },
+                                "get" => {
+                                    for key in &args[1..] {
+                                        if let Some(value) = store.get(*key) {
+                                            reply.push_str(&format!(
+                                                "VALUE {} 0 {}\r\n{}\r\n",
+                                                key,
+                                                value.len(),
+                                                value
+                                            ));
+                                        }
+                                    }
+                                    reply.push_str("END\r\n");
+                                }
+                                "ping" => reply.push_str("PONG\r\n"),
+                                "stats" => reply.push_str(&format!(
+                                    "STAT version mock\r\nSTAT cmd_set {}\r\n\
+                                     STAT curr_items {}\r\nSTAT reads {}\r\nEND\r\n",
+                                    sets.load(Ordering::SeqCst),
+                                    store.len(),
+                                    reads.load(Ordering::SeqCst)
+                                )),
+                                _ => reply.push_str("ERROR\r\n"),
+                            }
+                            pending.drain(..len);
+                        }
+                        stream.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_cluster_sizes() {
+        let cluster = [
+            ("127.0.0.1", 11211),
+            ("127.0.0.2", 11211),
+            ("127.0.0.3", 11211),
+            ("127.0.0.4", 11211),
+            ("127.0.0.5", 11211),
+        ];
+        for n in [2, 5] {
+            let client = ClusterClient::new(&cluster[..n], 1);
+            assert_eq!(n, client.pools.len());
+            for i in 0..100 {
+                let indices = client.server_indices(&format!("key{}", i)).unwrap();
+                assert!(indices[0] < n);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (port, server) = mock_server(0).await;
+        let client = ClusterClient::new(&[("127.0.0.1", port)], 1);
+        assert_eq!("END\r\n", client.get("abhi").await.unwrap());
+
+        // restart the server; the cached connection is now dead
+        server.abort();
+        let _ = server.await;
+        let (_, server) = mock_server(port).await;
+        assert_eq!("END\r\n", client.get("abhi").await.unwrap());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let (port, server) = mock_server(0).await;
+        server.abort();
+        let _ = server.await;
+
+        let client = ClusterClient::new(&[("127.0.0.1", port)], 1);
+        let e = client.get("abhi").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ClusterError>(),
+            Some(ClusterError::Unreachable { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replica_fallback() {
+        let (port1, server1) = mock_server(0).await;
+        let (port2, server2) = mock_server(0).await;
+        let client = ClusterClient::new(&[("127.0.0.1", port1), ("127.0.0.1", port2)], 2);
+        assert_eq!(
+            "STORED\r\n",
+            client.set("abhi", 0, 0, "rust").await.unwrap()
+        );
+
+        // stop the primary
+        let (primary, replica) = if client.server_indices("abhi").unwrap()[0] == 0 {
+            (server1, server2)
+        } else {
+            (server2, server1)
+        };
+        primary.abort();
+        let _ = primary.await;
+        assert_eq!(
+            "VALUE abhi 0 4\r\nrust\r\nEND\r\n",
+            client.get("abhi").await.unwrap()
+        );
+
+        // only the replica is left to store on
+        let e = client.set("abhi", 0, 0, "zig").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ClusterError>(),
+            Some(ClusterError::PartialWrite { written: 1, failed }) if failed.len() == 1
+        ));
+        assert_eq!(
+            "VALUE abhi 0 3\r\nzig\r\nEND\r\n",
+            client.get("abhi").await.unwrap()
+        );
+        replica.abort();
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let (port1, server1) = mock_server(0).await;
+        let (port2, server2) = mock_server(0).await;
+        let client = ClusterClient::new(&[("127.0.0.1", port1), ("127.0.0.1", port2)], 2);
+        client.set("abhi", 0, 0, "rust").await.unwrap();
+        assert_eq!(DeleteResult::Deleted, client.delete("abhi").await.unwrap());
+        assert_eq!("END\r\n", client.get("abhi").await.unwrap());
+
+        // the replica doesn't have it either
+        server1.abort();
+        let _ = server1.await;
+        assert_eq!("END\r\n", client.get("abhi").await.unwrap());
+        server2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_five_nodes() {
+        let mut servers = vec![];
+        for _ in 0..5 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let client = ClusterClient::new(&cluster, 1);
+
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                "STORED\r\n",
+                client.set(&key, 0, 0, &i.to_string()).await.unwrap()
+            );
+        }
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            let value = i.to_string();
+            assert_eq!(
+                format!("VALUE {} 0 {}\r\n{}\r\nEND\r\n", key, value.len(), value),
+                client.get(&key).await.unwrap()
+            );
+        }
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let client = ClusterClient::new(&cluster, 1);
+        for i in 0..10 {
+            client
+                .set(&format!("key{}", i), 0, 0, "rust")
+                .await
+                .unwrap();
+        }
+        client.set("key0", 0, 0, "zig").await.unwrap();
+
+        let stats = client.stats().await.unwrap();
+        assert_eq!(3, stats.nodes.len());
+        for (i, (port, _)) in servers.iter().enumerate() {
+            let keys = (0..10)
+                .filter(|k| client.server_indices(&format!("key{}", k)).unwrap()[0] == i)
+                .count() as u64;
+            let node = &stats.nodes[&("127.0.0.1".to_string(), *port)];
+            assert_eq!(keys, node["curr_items"]);
+            // the version isn't a counter
+            assert!(!node.contains_key("version"));
+        }
+        assert_eq!(10, stats.totals["curr_items"]);
+        assert_eq!(11, stats.totals["cmd_set"]);
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        // a server answering every connection at once, but slowly, counting the connections
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 1024];
+                    while let Ok(size) = stream.read(&mut buf).await {
+                        if size == 0 {
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        stream.write_all(b"END\r\n").await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = ClusterClient::builder(&[("127.0.0.1", port)])
+            .pool_size(3)
+            .build();
+        let get = || client.request(0, b"get abhi\r\n");
+        let replies = tokio::join!(get(), get(), get(), get(), get(), get());
+        for reply in <[_; 6]>::from(replies) {
+            assert_eq!("END\r\n", reply.unwrap());
+        }
+        // the gets were spread over as many connections as the pool allows
+        assert_eq!(3, connections.load(Ordering::SeqCst));
+        assert_eq!(3, client.pools[0].idle.lock().unwrap().len());
+
+        // idle connections are reused
+        client.request(0, b"get abhi\r\n").await.unwrap();
+        assert_eq!(3, connections.load(Ordering::SeqCst));
+        server.abort();
+    }
 
-        let mut buf = vec![0; 1024];
-        let size = stream.read(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf[..size]).into())
-    }
-
-    /// gets the correct `server` based on the hash of the `key`
-    async fn get_stream(&mut self, key: &str) -> anyhow::Result<&mut TcpStream> {
-        let hash = murmur3_32(&mut Cursor::new(key), 0)? as usize;
-        let server_index = hash % self.cluster.len();
-        if self.streams[server_index].is_none() {
-            self.streams[server_index] = Some(
-                TcpStream::connect(format!(
-                    "{}:{}",
-                    self.cluster[server_index].0, self.cluster[server_index].1
-                ))
-                .await?,
+    #[tokio::test]
+    async fn test_shared() {
+        let (port1, server1) = mock_server(0).await;
+        let (port2, server2) = mock_server(0).await;
+        let client = Arc::new(ClusterClient::new(
+            &[("127.0.0.1", port1), ("127.0.0.1", port2)],
+            1,
+        ));
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            client.set(&key, 0, 0, &i.to_string()).await.unwrap();
+        }
+
+        let mut tasks = JoinSet::new();
+        for t in 0..8 {
+            let client = client.clone();
+            tasks.spawn(async move {
+                for i in (t..20).step_by(4) {
+                    let key = format!("key{}", i);
+                    let value = i.to_string();
+                    assert_eq!(
+                        format!("VALUE {} 0 {}\r\n{}\r\nEND\r\n", key, value.len(), value),
+                        client.get(&key).await.unwrap()
+                    );
+                }
+            });
+        }
+        while let Some(task) = tasks.join_next().await {
+            task.unwrap();
+        }
+        server1.abort();
+        server2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // a node that accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let timeout = Duration::from_millis(100);
+        let client = ClusterClient::builder(&[("127.0.0.1", port)])
+            .timeout(timeout)
+            .build();
+        let start = tokio::time::Instant::now();
+        let e = client.get("abhi").await.unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < timeout * 10);
+        assert!(matches!(
+            e.downcast_ref::<ClusterError>(),
+            Some(ClusterError::Timeout { port: p, .. }) if *p == port
+        ));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let (port1, server1) = mock_server(0).await;
+        let (port2, server2) = mock_server(0).await;
+        let client = ClusterClient::builder(&[("127.0.0.1", port1), ("127.0.0.1", port2)])
+            .replicas(2)
+            .virtual_nodes(10)
+            .pool_size(2)
+            .timeout(Duration::from_secs(1))
+            .retries(0)
+            .build();
+        assert_eq!(2, client.replicas);
+        assert_eq!(2, client.pools[0].permits.available_permits());
+        assert_eq!(Some(Duration::from_secs(1)), client.timeout);
+        assert_eq!(2, client.server_indices("abhi").unwrap().len());
+
+        // both servers get the key
+        client.set("abhi", 0, 0, "rust").await.unwrap();
+        assert_eq!(
+            "VALUE abhi 0 4\r\nrust\r\nEND\r\n",
+            client.get("abhi").await.unwrap()
+        );
+        let stats = client.stats().await.unwrap();
+        assert_eq!(2, stats.totals["curr_items"]);
+
+        // without retries a broken connection fails the request
+        server1.abort();
+        let _ = server1.await;
+        let (_, server1) = mock_server(port1).await;
+        assert!(client.request(0, b"get abhi\r\n").await.is_err());
+        assert_eq!("END\r\n", client.request(0, b"get abhi\r\n").await.unwrap());
+        server1.abort();
+        server2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_distribution() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        for distribution in [
+            Distribution::Modulo,
+            Distribution::Rendezvous,
+            Distribution::JumpHash,
+        ] {
+            let client = ClusterClient::builder(&cluster)
+                .distribution(distribution)
+                .build();
+            for i in 0..10 {
+                let key = format!("{:?}{}", distribution, i);
+                client.set(&key, 0, 0, "rust").await.unwrap();
+                assert_eq!(
+                    format!("VALUE {} 0 4\r\nrust\r\nEND\r\n", key),
+                    client.get(&key).await.unwrap()
+                );
+            }
+        }
+        assert_eq!(
+            30,
+            ClusterClient::new(&cluster, 1)
+                .stats()
+                .await
+                .unwrap()
+                .totals["cmd_set"]
+        );
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[test]
+    fn test_seed() {
+        let cluster: Vec<_> = (1..=5).map(|i| (format!("127.0.0.{}", i), 11211)).collect();
+        let cluster: Vec<_> = cluster.iter().map(|(s, p)| (s.as_str(), *p)).collect();
+        for distribution in [
+            Distribution::Modulo,
+            Distribution::ConsistentRing,
+            Distribution::Rendezvous,
+            Distribution::JumpHash,
+        ] {
+            let client = |seed| {
+                ClusterClient::builder(&cluster)
+                    .distribution(distribution)
+                    .seed(seed)
+                    .build()
+            };
+            let (client, same, other) = (client(7), client(7), client(8));
+            let mut moved = 0;
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                let indices = client.server_indices(&key).unwrap();
+                assert_eq!(indices, same.server_indices(&key).unwrap());
+                if indices != other.server_indices(&key).unwrap() {
+                    moved += 1;
+                }
+            }
+            assert!(moved > 0, "{:?} ignores the seed", distribution);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nodelay() {
+        let (port, server) = mock_server(0).await;
+        for nodelay in [true, false] {
+            let client = ClusterClient::builder(&[("127.0.0.1", port)])
+                .nodelay(nodelay)
+                .build();
+            let stream = client.checkout_stream(0).await.unwrap();
+            assert_eq!(nodelay, stream.nodelay().unwrap());
+        }
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_many() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let client = ClusterClient::new(&cluster, 1);
+
+        let keys: Vec<_> = (0..30).map(|i| format!("key{}", i)).collect();
+        let values: Vec<_> = (0..30).map(|i| i.to_string()).collect();
+        let items: Vec<_> = keys
+            .iter()
+            .zip(&values)
+            .map(|(key, value)| (key.as_str(), 0, 0, value.as_str()))
+            .collect();
+        assert_eq!(
+            vec!["STORED\r\n"; 30],
+            client.set_many(&items).await.unwrap()
+        );
+
+        // a key that was never set among the rest, which are spread over every server
+        let mut asked: Vec<_> = keys.iter().rev().map(String::as_str).collect();
+        asked.insert(10, "missing");
+        let reads = client.stats().await.unwrap().totals["reads"];
+        let got = client.get_many(&asked).await.unwrap();
+        // less the reads of the `stats` requests themselves
+        let round_trips = client.stats().await.unwrap().totals["reads"] - reads - 3;
+
+        let mut expected: Vec<_> = values.iter().rev().cloned().map(Some).collect();
+        expected.insert(10, None);
+        assert_eq!(expected, got);
+        assert_eq!(3, round_trips);
+        assert_eq!(
+            Vec::<Option<String>>::new(),
+            client.get_many(&[]).await.unwrap()
+        );
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_many_replica_fallback() {
+        let (port1, server1) = mock_server(0).await;
+        let (port2, server2) = mock_server(0).await;
+        let client = ClusterClient::new(&[("127.0.0.1", port1), ("127.0.0.1", port2)], 2);
+        let items = [("abhi", 0, 0, "rust"), ("bhagat", 0, 0, "zig")];
+        client.set_many(&items).await.unwrap();
+
+        server1.abort();
+        let _ = server1.await;
+        assert_eq!(
+            vec![Some("zig".to_string()), Some("rust".to_string())],
+            client.get_many(&["bhagat", "abhi"]).await.unwrap()
+        );
+
+        // only one server is left to store on
+        assert!(client.set_many(&items).await.is_err());
+        server2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_ping_all() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let client = ClusterClient::new(&cluster, 1);
+        assert!(client.ping_all().await.values().all(|&alive| alive));
+
+        let (down, server) = servers.remove(1);
+        server.abort();
+        let _ = server.await;
+        let health = client.ping_all().await;
+        assert_eq!(3, health.len());
+        for (node, alive) in health {
+            assert_eq!(node.1 != down, alive, "{:?}", node);
+        }
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let interval = Duration::from_millis(200);
+        let client = ClusterClient::builder(&cluster)
+            .failure_threshold(2)
+            .probe_interval(interval)
+            .build();
+
+        // stop the key's server
+        let primary = client.server_indices("abhi").unwrap()[0];
+        let (port, server) = servers.remove(primary);
+        server.abort();
+        let _ = server.await;
+        for _ in 0..2 {
+            assert!(client.set("abhi", 0, 0, "rust").await.is_err());
+        }
+        assert_eq!(vec![("127.0.0.1".to_string(), port)], client.down_nodes());
+
+        // the next server takes over its keys
+        assert_ne!(primary, client.server_indices("abhi").unwrap()[0]);
+        assert_eq!(
+            "STORED\r\n",
+            client.set("abhi", 0, 0, "rust").await.unwrap()
+        );
+        assert_eq!(
+            "VALUE abhi 0 4\r\nrust\r\nEND\r\n",
+            client.get("abhi").await.unwrap()
+        );
+
+        // still down when probed
+        tokio::time::sleep(interval).await;
+        assert!(client.get("abhi").await.is_err());
+        assert_eq!(1, client.down_nodes().len());
+
+        // back once a probe succeeds, fresh & empty
+        let (_, server) = mock_server(port).await;
+        tokio::time::sleep(interval).await;
+        assert_eq!("END\r\n", client.get("abhi").await.unwrap());
+        assert!(client.down_nodes().is_empty());
+        assert_eq!(primary, client.server_indices("abhi").unwrap()[0]);
+
+        server.abort();
+        for (_, server) in servers {
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_write_down_node() {
+        let mut servers = vec![];
+        for _ in 0..3 {
+            servers.push(mock_server(0).await);
+        }
+        let cluster: Vec<_> = servers
+            .iter()
+            .map(|&(port, _)| ("127.0.0.1", port))
+            .collect();
+        let client = ClusterClient::builder(&cluster)
+            .replicas(3)
+            .failure_threshold(1)
+            .build();
+
+        for written in [2, 1] {
+            let (_, server) = servers.remove(0);
+            server.abort();
+            let _ = server.await;
+            // the server down since the last write isn't written to, nor counted as written
+            let e = client.set("abhi", 0, 0, "rust").await.unwrap_err();
+            assert!(
+                matches!(
+                    e.downcast_ref::<ClusterError>(),
+                    Some(ClusterError::PartialWrite { written: w, failed })
+                        if *w == written && failed.len() == 1
+                ),
+                "{:?}",
+                e
             );
         }
+        assert_eq!(2, client.down_nodes().len());
+        servers.remove(0).1.abort();
+    }
 
-        Ok(self.streams[server_index].as_mut().unwrap())
+    #[tokio::test]
+    async fn test_failover_disabled() {
+        let (port, server) = mock_server(0).await;
+        server.abort();
+        let _ = server.await;
+        let client = ClusterClient::builder(&[("127.0.0.1", port)])
+            .failure_threshold(0)
+            .build();
+        for _ in 0..5 {
+            assert!(client.get("abhi").await.is_err());
+        }
+        assert!(client.down_nodes().is_empty());
     }
 }
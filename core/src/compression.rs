@@ -0,0 +1,153 @@
+use bytes::Bytes;
+use std::borrow::Cow;
+
+/// flag bit recording that a value was compressed with lz4
+pub const LZ4_FLAG: u32 = 1 << 30;
+
+/// flag bit recording that a value was compressed with zstd
+pub const ZSTD_FLAG: u32 = 1 << 31;
+
+/// the flag bits taken by the codecs, which a compressing client won't set for callers
+pub const CODEC_FLAGS: u32 = LZ4_FLAG | ZSTD_FLAG;
+
+/// smallest value compressed unless set with `Compression::threshold`
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// the algorithm values are compressed with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    /// fast, for values read often
+    Lz4,
+    /// slower but smaller, for values large enough that the bandwidth & memory matter more
+    Zstd,
+}
+
+impl Codec {
+    /// returns the flag bit recording that a value was compressed with the codec
+    pub fn flag(self) -> u32 {
+        match self {
+            Codec::Lz4 => LZ4_FLAG,
+            Codec::Zstd => ZSTD_FLAG,
+        }
+    }
+}
+
+/// How a `Client` compresses values.
+///
+/// Values of at least `threshold` bytes are compressed before being set, with the codec recorded
+/// in a high bit of the flags word, & decompressed when got back. The server only ever sees
+/// opaque bytes. A value that doesn't get any smaller is stored as is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    codec: Codec,
+    threshold: usize,
+}
+
+impl Compression {
+    /// compresses values of `DEFAULT_THRESHOLD` bytes & up with `codec`
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// compresses values of `threshold` bytes & up, leaving smaller ones as is
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// compresses `value` if it's large enough & gets smaller, returning it along with `flags`
+    /// marked with the codec used
+    pub(crate) fn compress<'a>(
+        &self,
+        flags: u32,
+        value: &'a [u8],
+    ) -> anyhow::Result<(u32, Cow<'a, [u8]>)> {
+        if flags & CODEC_FLAGS != 0 {
+            anyhow::bail!("flags {:#x} use the bits reserved for compression", flags);
+        }
+        if value.len() < self.threshold {
+            return Ok((flags, Cow::Borrowed(value)));
+        }
+        let compressed = match self.codec {
+            Codec::Lz4 => lz4_flex::compress_prepend_size(value),
+            // 0 picks zstd's default level
+            Codec::Zstd => zstd::bulk::compress(value, 0)?,
+        };
+        if compressed.len() >= value.len() {
+            return Ok((flags, Cow::Borrowed(value)));
+        }
+        Ok((flags | self.codec.flag(), Cow::Owned(compressed)))
+    }
+}
+
+/// decompresses `value` if `flags` mark it compressed, returning it along with `flags` less the
+/// codec's bit
+pub(crate) fn decompress(flags: u32, value: Bytes) -> anyhow::Result<(u32, Bytes)> {
+    let value = match flags & CODEC_FLAGS {
+        0 => return Ok((flags, value)),
+        LZ4_FLAG => lz4_flex::decompress_size_prepended(&value)?,
+        ZSTD_FLAG => zstd::decode_all(&value[..])?,
+        _ => anyhow::bail!("flags {:#x} mark more than one codec", flags),
+    };
+    Ok((flags & !CODEC_FLAGS, Bytes::from(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{Codec, Compression, LZ4_FLAG, ZSTD_FLAG, decompress};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_round_trip() {
+        let value = "rust ".repeat(1000).into_bytes();
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let (flags, compressed) = Compression::new(codec).compress(7, &value).unwrap();
+            assert_eq!(7 | codec.flag(), flags);
+            assert!(compressed.len() < value.len() / 10, "{:?}", codec);
+
+            let (flags, decompressed) =
+                decompress(flags, Bytes::from(compressed.to_vec())).unwrap();
+            assert_eq!(7, flags);
+            assert_eq!(value, decompressed);
+        }
+    }
+
+    #[test]
+    fn test_left_as_is() {
+        let compression = Compression::new(Codec::Lz4).threshold(100);
+        // too small
+        let (flags, value) = compression.compress(0, b"rust").unwrap();
+        assert_eq!((0, &b"rust"[..]), (flags, &value[..]));
+
+        // doesn't get any smaller
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                // xorshift
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let (flags, value) = compression.compress(0, &noise).unwrap();
+        assert_eq!((0, &noise[..]), (flags, &value[..]));
+
+        // values that weren't compressed come back as they are
+        assert_eq!(
+            (3, Bytes::from("rust")),
+            decompress(3, Bytes::from("rust")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reserved_flags() {
+        let compression = Compression::new(Codec::Zstd);
+        assert!(compression.compress(LZ4_FLAG, b"rust").is_err());
+        assert!(decompress(LZ4_FLAG | ZSTD_FLAG, Bytes::from("rust")).is_err());
+        assert!(decompress(LZ4_FLAG, Bytes::from("rust")).is_err());
+    }
+}
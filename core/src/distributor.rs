@@ -0,0 +1,310 @@
+use crate::hash_ring::{HashRing, hash};
+use murmur3::murmur3_x64_128;
+use std::io::Cursor;
+
+/// Decides which servers of a cluster hold a key.
+pub trait KeyDistributor: Send + Sync {
+    /// gets the indices of up to `n` distinct nodes for `key`, the owner first
+    fn node_indices(&self, key: &str, n: usize) -> Vec<usize>;
+
+    /// returns all the nodes, in the order their indices refer to
+    fn nodes(&self) -> &[(String, u16)];
+}
+
+/// how a `ClusterClient` spreads keys over its servers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Distribution {
+    /// the key's hash modulo the number of servers; adding or removing one remaps most keys
+    Modulo,
+    /// a consistent hashing ring with virtual nodes
+    #[default]
+    ConsistentRing,
+    /// the server scoring the highest hash with the key
+    Rendezvous,
+    /// jump consistent hashing, for servers that are only ever added at the end
+    JumpHash,
+}
+
+impl Distribution {
+    /// creates the distributor for `nodes` hashing with `seed`, placing each node at
+    /// `virtual_nodes` points if on a ring
+    pub fn distributor(
+        self,
+        nodes: &[(String, u16)],
+        virtual_nodes: usize,
+        seed: u32,
+    ) -> Box<dyn KeyDistributor> {
+        match self {
+            Distribution::Modulo => Box::new(Modulo::with_seed(nodes, seed)),
+            Distribution::ConsistentRing => {
+                Box::new(HashRing::with_seed(nodes, virtual_nodes, seed))
+            }
+            Distribution::Rendezvous => Box::new(Rendezvous::with_seed(nodes, seed)),
+            Distribution::JumpHash => Box::new(JumpHash::with_seed(nodes, seed)),
+        }
+    }
+}
+
+/// Owns a key by the server at its hash modulo the number of servers, with the replicas on the
+/// servers after it.
+pub struct Modulo {
+    nodes: Vec<(String, u16)>,
+    seed: u32,
+}
+
+impl Modulo {
+    /// creates a new `Modulo` over `nodes`
+    pub fn new(nodes: &[(String, u16)]) -> Self {
+        Self::with_seed(nodes, 0)
+    }
+
+    /// creates a new `Modulo` over `nodes` hashing the keys with `seed`
+    pub fn with_seed(nodes: &[(String, u16)], seed: u32) -> Self {
+        Self {
+            nodes: nodes.to_vec(),
+            seed,
+        }
+    }
+}
+
+impl KeyDistributor for Modulo {
+    fn node_indices(&self, key: &str, n: usize) -> Vec<usize> {
+        let len = self.nodes.len();
+        if len == 0 {
+            return vec![];
+        }
+        let owner = hash(key.as_bytes(), self.seed) as usize % len;
+        (0..n.min(len)).map(|i| (owner + i) % len).collect()
+    }
+
+    fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+}
+
+/// Rendezvous or highest random weight hashing.
+///
+/// Every server is scored by hashing it along with the key & the key is owned by the highest
+/// scoring one, so removing a server only remaps the keys it owned.
+pub struct Rendezvous {
+    nodes: Vec<(String, u16)>,
+    seed: u32,
+}
+
+impl Rendezvous {
+    /// creates a new `Rendezvous` over `nodes`
+    pub fn new(nodes: &[(String, u16)]) -> Self {
+        Self::with_seed(nodes, 0)
+    }
+
+    /// creates a new `Rendezvous` over `nodes` scoring them with `seed`
+    pub fn with_seed(nodes: &[(String, u16)], seed: u32) -> Self {
+        Self {
+            nodes: nodes.to_vec(),
+            seed,
+        }
+    }
+
+    /// scores the node at `index` for `key`
+    fn weight(&self, index: usize, key: &str) -> u32 {
+        let (server, port) = &self.nodes[index];
+        hash(format!("{}:{}/{}", server, port, key).as_bytes(), self.seed)
+    }
+}
+
+impl KeyDistributor for Rendezvous {
+    fn node_indices(&self, key: &str, n: usize) -> Vec<usize> {
+        let mut indices: Vec<_> = (0..self.nodes.len()).collect();
+        indices.sort_by_key(|&index| std::cmp::Reverse((self.weight(index, key), index)));
+        indices.truncate(n);
+        indices
+    }
+
+    fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+}
+
+/// Jump consistent hashing, after Lamping & Veach.
+///
+/// A key's 64 bit hash jumps through the buckets `0..n` & lands in one of them, taking O(ln n)
+/// steps without memory for a ring. Adding a server at the end only moves the keys it takes over
+/// to it, but removing any other server remaps most keys, so it suits clusters that are only
+/// ever grown. The replicas are on the servers after the owner.
+pub struct JumpHash {
+    nodes: Vec<(String, u16)>,
+    seed: u32,
+}
+
+impl JumpHash {
+    /// creates a new `JumpHash` over `nodes`
+    pub fn new(nodes: &[(String, u16)]) -> Self {
+        Self::with_seed(nodes, 0)
+    }
+
+    /// creates a new `JumpHash` over `nodes` hashing the keys with `seed`
+    pub fn with_seed(nodes: &[(String, u16)], seed: u32) -> Self {
+        Self {
+            nodes: nodes.to_vec(),
+            seed,
+        }
+    }
+}
+
+impl KeyDistributor for JumpHash {
+    fn node_indices(&self, key: &str, n: usize) -> Vec<usize> {
+        let len = self.nodes.len();
+        if len == 0 {
+            return vec![];
+        }
+        let hash = murmur3_x64_128(&mut Cursor::new(key.as_bytes()), self.seed)
+            .expect("reading from memory can't fail") as u64;
+        let owner = jump(hash, len);
+        (0..n.min(len)).map(|i| (owner + i) % len).collect()
+    }
+
+    fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+}
+
+/// maps `key` to one of `buckets` buckets by jump consistent hashing
+fn jump(mut key: u64, buckets: usize) -> usize {
+    let (mut bucket, mut next) = (-1i64, 0i64);
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::distributor::{Distribution, JumpHash, KeyDistributor, Modulo, Rendezvous, jump};
+    use crate::hash_ring::DEFAULT_VIRTUAL_NODES;
+    use std::collections::HashMap;
+
+    fn nodes(n: usize) -> Vec<(String, u16)> {
+        (1..=n).map(|i| (format!("127.0.0.{}", i), 11211)).collect()
+    }
+
+    /// gets the node owning each of `keys`
+    fn owners<'a>(distributor: &'a dyn KeyDistributor, keys: &[String]) -> Vec<&'a (String, u16)> {
+        keys.iter()
+            .map(|key| &distributor.nodes()[distributor.node_indices(key, 1)[0]])
+            .collect()
+    }
+
+    #[test]
+    fn test_node_indices() {
+        for distribution in [
+            Distribution::Modulo,
+            Distribution::ConsistentRing,
+            Distribution::Rendezvous,
+            Distribution::JumpHash,
+        ] {
+            let distributor = distribution.distributor(&nodes(3), DEFAULT_VIRTUAL_NODES, 0);
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                let indices = distributor.node_indices(&key, 2);
+                assert_eq!(2, indices.len());
+                assert_ne!(indices[0], indices[1]);
+                // the same key always goes to the same nodes
+                assert_eq!(indices, distributor.node_indices(&key, 2));
+                // can't have more replicas than nodes
+                assert_eq!(3, distributor.node_indices(&key, 5).len());
+            }
+            assert!(
+                distribution
+                    .distributor(&[], DEFAULT_VIRTUAL_NODES, 0)
+                    .node_indices("abhi", 1)
+                    .is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn test_balance() {
+        let keys: Vec<_> = (0..10000).map(|i| format!("key{}", i)).collect();
+        for distributor in [
+            Box::new(Modulo::new(&nodes(4))) as Box<dyn KeyDistributor>,
+            Box::new(Rendezvous::new(&nodes(4))),
+            Box::new(JumpHash::new(&nodes(4))),
+        ] {
+            let mut counts = HashMap::new();
+            for owner in owners(distributor.as_ref(), &keys) {
+                *counts.entry(owner).or_insert(0) += 1;
+            }
+            assert_eq!(4, counts.len());
+            for (node, count) in counts {
+                assert!(
+                    (2000..3000).contains(&count),
+                    "{:?} owns {} keys",
+                    node,
+                    count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rendezvous_remove_node() {
+        let keys: Vec<_> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let all = nodes(4);
+        let before = Rendezvous::new(&all);
+        let before = owners(&before, &keys);
+
+        let removed = &all[1];
+        let rest: Vec<_> = all
+            .iter()
+            .filter(|&node| node != removed)
+            .cloned()
+            .collect();
+        let after = Rendezvous::new(&rest);
+        let after = owners(&after, &keys);
+
+        for (before, after) in before.iter().zip(after) {
+            if *before == removed {
+                assert_ne!(removed, after);
+            } else {
+                // only the removed node's keys move
+                assert_eq!(*before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn test_jump() {
+        for key in 0..1000 {
+            let key = u64::wrapping_mul(key, 0x9e3779b97f4a7c15);
+            assert_eq!(0, jump(key, 1));
+            for buckets in 1..50 {
+                // a new bucket only ever takes keys
+                let bucket = jump(key, buckets + 1);
+                assert!(bucket == jump(key, buckets) || bucket == buckets);
+            }
+        }
+    }
+
+    #[test]
+    fn test_jump_hash_add_node() {
+        let keys: Vec<_> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let before = JumpHash::new(&nodes(10));
+        let before = owners(&before, &keys);
+        let after = JumpHash::new(&nodes(11));
+        let after = owners(&after, &keys);
+
+        let added = &nodes(11)[10];
+        let mut moved = 0;
+        for (before, after) in before.iter().zip(after) {
+            if *before != after {
+                // keys only move to the new node
+                assert_eq!(added, after);
+                moved += 1;
+            }
+        }
+        // about a node's share of the keys, 1/11th
+        assert!((700..1100).contains(&moved), "{} keys moved", moved);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, RandomState};
+
+/// Decides which entry a full cache evicts.
+///
+/// The cache reports every insert, access & removal so the policy can keep track of the keys.
+pub trait EvictionPolicy<K> {
+    /// records that `k` was inserted or had its value replaced
+    fn on_insert(&mut self, k: &K);
+
+    /// records that `k` was read
+    fn on_access(&mut self, k: &K);
+
+    /// forgets `k` once it is removed from the cache
+    fn on_remove(&mut self, k: &K);
+
+    /// picks the entry to evict & forgets it, `None` if there are none
+    fn victim(&mut self) -> Option<K>;
+
+    /// forgets all the keys
+    fn clear(&mut self);
+}
+
+/// evicts the least recently used entry
+pub struct Lru<K> {
+    tick: u64,
+    ticks: HashMap<K, u64>,
+    /// keys by the tick of their last use, oldest first
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+    /// creates a new `Lru` policy tracking no keys
+    pub fn new() -> Self {
+        Self {
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Lru<K> {
+    fn on_insert(&mut self, k: &K) {
+        self.on_access(k);
+    }
+
+    fn on_access(&mut self, k: &K) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(k.clone(), self.tick) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, k.clone());
+    }
+
+    fn on_remove(&mut self, k: &K) {
+        if let Some(tick) = self.ticks.remove(k) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let (_, k) = self.order.pop_first()?;
+        self.ticks.remove(&k);
+        Some(k)
+    }
+
+    fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
+}
+
+/// evicts the least frequently used entry, the least recently used of those on a tie
+pub struct Lfu<K> {
+    tick: u64,
+    /// access count & tick of the last access of every key
+    uses: HashMap<K, (u64, u64)>,
+    /// keys by their access count & tick, least used first
+    order: BTreeMap<(u64, u64), K>,
+}
+
+impl<K: Hash + Eq + Clone> Lfu<K> {
+    /// creates a new `Lfu` policy tracking no keys
+    pub fn new() -> Self {
+        Self {
+            tick: 0,
+            uses: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// bumps the access count of `k` by `by`
+    fn bump(&mut self, k: &K, by: u64) {
+        self.tick += 1;
+        let count = match self.uses.get(k) {
+            Some(&(count, tick)) => {
+                self.order.remove(&(count, tick));
+                count + by
+            }
+            None => by,
+        };
+        self.uses.insert(k.clone(), (count, self.tick));
+        self.order.insert((count, self.tick), k.clone());
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Lfu<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Lfu<K> {
+    fn on_insert(&mut self, k: &K) {
+        // replacing a value keeps its count
+        self.bump(k, 0);
+    }
+
+    fn on_access(&mut self, k: &K) {
+        self.bump(k, 1);
+    }
+
+    fn on_remove(&mut self, k: &K) {
+        if let Some(uses) = self.uses.remove(k) {
+            self.order.remove(&uses);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        let (_, k) = self.order.pop_first()?;
+        self.uses.remove(&k);
+        Some(k)
+    }
+
+    fn clear(&mut self) {
+        self.uses.clear();
+        self.order.clear();
+    }
+}
+
+/// evicts an arbitrary entry
+pub struct Random<K> {
+    keys: Vec<K>,
+    /// position of every key in `keys`
+    index: HashMap<K, usize>,
+    seed: RandomState,
+    draws: u64,
+}
+
+impl<K: Hash + Eq + Clone> Random<K> {
+    /// creates a new `Random` policy tracking no keys
+    pub fn new() -> Self {
+        Self {
+            keys: vec![],
+            index: HashMap::new(),
+            seed: RandomState::new(),
+            draws: 0,
+        }
+    }
+
+    /// removes the key at position `i`, moving the last key into its place
+    fn remove_at(&mut self, i: usize) -> K {
+        let k = self.keys.swap_remove(i);
+        self.index.remove(&k);
+        if let Some(moved) = self.keys.get(i) {
+            self.index.insert(moved.clone(), i);
+        }
+        k
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Random<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Random<K> {
+    fn on_insert(&mut self, k: &K) {
+        if !self.index.contains_key(k) {
+            self.index.insert(k.clone(), self.keys.len());
+            self.keys.push(k.clone());
+        }
+    }
+
+    fn on_access(&mut self, _: &K) {}
+
+    fn on_remove(&mut self, k: &K) {
+        if let Some(i) = self.index.get(k).copied() {
+            self.remove_at(i);
+        }
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        // hashing a counter with a randomly keyed hasher is random enough to pick a victim
+        self.draws += 1;
+        let i = self.seed.hash_one(self.draws) as usize % self.keys.len();
+        Some(self.remove_at(i))
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.index.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eviction::{EvictionPolicy, Lfu, Lru, Random};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_lru() {
+        let mut policy = Lru::new();
+        for k in [1, 2, 3] {
+            policy.on_insert(&k);
+        }
+        policy.on_access(&1);
+        assert_eq!(policy.victim(), Some(2));
+        assert_eq!(policy.victim(), Some(3));
+        assert_eq!(policy.victim(), Some(1));
+        assert_eq!(policy.victim(), None);
+    }
+
+    #[test]
+    fn test_lfu() {
+        let mut policy = Lfu::new();
+        for k in [1, 2, 3] {
+            policy.on_insert(&k);
+        }
+        policy.on_access(&1);
+        policy.on_access(&1);
+        policy.on_access(&2);
+        policy.on_access(&3);
+        // 2 & 3 are tied; 2 was used longer ago
+        assert_eq!(policy.victim(), Some(2));
+
+        // replacing a value doesn't count as a use
+        policy.on_insert(&3);
+        policy.on_insert(&4);
+        assert_eq!(policy.victim(), Some(4));
+        policy.on_remove(&3);
+        assert_eq!(policy.victim(), Some(1));
+        assert_eq!(policy.victim(), None);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut policy = Random::new();
+        for k in 0..10 {
+            policy.on_insert(&k);
+        }
+        policy.on_remove(&0);
+
+        // every key is picked exactly once
+        let victims: HashSet<_> = std::iter::from_fn(|| policy.victim()).collect();
+        assert_eq!(victims, (1..10).collect());
+        assert_eq!(policy.victim(), None);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::distributor::KeyDistributor;
+use murmur3::murmur3_32;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+/// number of points each server gets on the ring by default
+pub const DEFAULT_VIRTUAL_NODES: usize = 100;
+
+/// hashes `bytes` with murmur3 seeded with `seed`
+pub(crate) fn hash(bytes: &[u8], seed: u32) -> u32 {
+    murmur3_32(&mut Cursor::new(bytes), seed).expect("reading from memory can't fail")
+}
+
+/// A consistent hashing ring.
+///
+/// Every server is placed on the ring at `virtual_nodes` points and a key is owned by the first
+/// server found walking clockwise from the key's hash, so adding or removing a server only remaps
+/// the keys adjacent to its points.
+pub struct HashRing {
+    ring: BTreeMap<u32, usize>,
+    nodes: Vec<(String, u16)>,
+    virtual_nodes: usize,
+    seed: u32,
+}
+
+impl HashRing {
+    /// creates a new `HashRing` placing each of the `nodes` at `virtual_nodes` points
+    pub fn new(nodes: &[(String, u16)], virtual_nodes: usize) -> Self {
+        Self::with_seed(nodes, virtual_nodes, 0)
+    }
+
+    /// creates a new `HashRing` hashing the points & the keys with `seed`
+    pub fn with_seed(nodes: &[(String, u16)], virtual_nodes: usize, seed: u32) -> Self {
+        let mut ring = Self {
+            ring: BTreeMap::new(),
+            nodes: vec![],
+            virtual_nodes,
+            seed,
+        };
+        for node in nodes {
+            ring.add(node.clone());
+        }
+        ring
+    }
+
+    /// adds `node` to the ring
+    pub fn add(&mut self, node: (String, u16)) {
+        let index = self.nodes.len();
+        for i in 0..self.virtual_nodes {
+            let point = hash(format!("{}:{}-{}", node.0, node.1, i).as_bytes(), self.seed);
+            self.ring.insert(point, index);
+        }
+        self.nodes.push(node);
+    }
+
+    /// gets the index of the node owning `key`
+    pub fn node_index(&self, key: &str) -> Option<usize> {
+        self.node_indices(key, 1).first().copied()
+    }
+
+    /// gets the indices of up to `n` distinct nodes for `key`, in the order they follow it on the
+    /// ring
+    pub fn node_indices(&self, key: &str, n: usize) -> Vec<usize> {
+        let n = n.min(self.nodes.len());
+        let point = hash(key.as_bytes(), self.seed);
+        let mut indices = Vec::with_capacity(n);
+        for (_, &index) in self.ring.range(point..).chain(self.ring.range(..point)) {
+            if indices.len() == n {
+                break;
+            }
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+        indices
+    }
+
+    /// gets the node owning `key`
+    pub fn get(&self, key: &str) -> Option<&(String, u16)> {
+        self.node_index(key).map(|index| &self.nodes[index])
+    }
+
+    /// returns all the nodes in the order they were added
+    pub fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+}
+
+impl KeyDistributor for HashRing {
+    fn node_indices(&self, key: &str, n: usize) -> Vec<usize> {
+        HashRing::node_indices(self, key, n)
+    }
+
+    fn nodes(&self) -> &[(String, u16)] {
+        HashRing::nodes(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash_ring::{DEFAULT_VIRTUAL_NODES, HashRing};
+
+    fn nodes(n: usize) -> Vec<(String, u16)> {
+        (1..=n).map(|i| (format!("127.0.0.{}", i), 11211)).collect()
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let ring = HashRing::new(&nodes(3), DEFAULT_VIRTUAL_NODES);
+        let other = HashRing::new(&nodes(3), DEFAULT_VIRTUAL_NODES);
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            assert_eq!(ring.get(&key), other.get(&key));
+        }
+        assert!(
+            HashRing::new(&[], DEFAULT_VIRTUAL_NODES)
+                .get("abhi")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_add_node() {
+        let mut ring = HashRing::new(&nodes(3), DEFAULT_VIRTUAL_NODES);
+        let keys: Vec<_> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let before: Vec<_> = keys.iter().map(|k| ring.get(k).cloned()).collect();
+
+        ring.add(("127.0.0.4".to_string(), 11211));
+        let moved = keys
+            .iter()
+            .zip(before)
+            .filter(|(k, owner)| ring.get(k).cloned() != *owner)
+            .count();
+
+        // ideally 1/4 of the keys move to the new node; allow some slack for uneven points
+        assert!(moved > 0);
+        assert!(moved < keys.len() * 3 / 10, "{} keys moved", moved);
+    }
+
+    #[test]
+    fn test_node_indices() {
+        let ring = HashRing::new(&nodes(3), DEFAULT_VIRTUAL_NODES);
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let indices = ring.node_indices(&key, 2);
+            assert_eq!(2, indices.len());
+            assert_ne!(indices[0], indices[1]);
+            assert_eq!(ring.node_index(&key), Some(indices[0]));
+
+            // can't have more replicas than nodes
+            assert_eq!(3, ring.node_indices(&key, 5).len());
+        }
+    }
+}
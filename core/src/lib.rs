@@ -1,3 +1,9 @@
 pub mod cache;
 pub mod client;
 pub mod cluster_client;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod distributor;
+pub mod eviction;
+pub mod hash_ring;
+pub mod slab;
@@ -0,0 +1,223 @@
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// size of the chunks in the smallest class by default
+pub const DEFAULT_MIN_CHUNK: usize = 64;
+
+/// size of the chunks in the largest class by default; larger values aren't pooled
+pub const DEFAULT_MAX_CHUNK: usize = 1024 * 1024;
+
+/// how much bigger each class's chunks are than the previous one's by default
+pub const DEFAULT_GROWTH_FACTOR: f64 = 1.25;
+
+/// chunks of one size, free to be reused
+struct SlabClass {
+    size: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+struct Slabs {
+    classes: Vec<SlabClass>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+/// A chunk holding a value, put back in its class when dropped.
+struct Chunk {
+    buf: Vec<u8>,
+    class: usize,
+    slabs: Arc<Slabs>,
+}
+
+impl AsRef<[u8]> for Chunk {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        self.slabs.classes[self.class]
+            .free
+            .lock()
+            .unwrap()
+            .push(buf);
+    }
+}
+
+/// counts of the chunks handed out by a `SlabAllocator`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SlabStats {
+    /// chunks freshly allocated
+    pub allocated: u64,
+    /// chunks reused after the value they held was dropped
+    pub reused: u64,
+}
+
+/// A slab allocator for values.
+///
+/// Values are copied into chunks grouped in size classes, each `growth_factor` times the size of
+/// the one before. Once every copy of a value is dropped, say when it's evicted, its chunk goes
+/// back to its class for the next value of about that size instead of back to the system
+/// allocator. Freed chunks are kept for reuse & never given back, as with memcached's slabs.
+///
+/// Cloning a `SlabAllocator` shares its chunks.
+#[derive(Clone)]
+pub struct SlabAllocator {
+    slabs: Arc<Slabs>,
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_CHUNK, DEFAULT_MAX_CHUNK, DEFAULT_GROWTH_FACTOR)
+    }
+}
+
+impl SlabAllocator {
+    /// creates a `SlabAllocator` with classes from `min_chunk` up to `max_chunk` bytes, each
+    /// `growth_factor` times the size of the one before
+    pub fn new(min_chunk: usize, max_chunk: usize, growth_factor: f64) -> Self {
+        let mut classes = vec![];
+        let mut size = min_chunk.max(1);
+        while size < max_chunk {
+            classes.push(size);
+            // always grow by at least a byte
+            size = ((size as f64 * growth_factor) as usize).max(size + 1);
+        }
+        classes.push(max_chunk.max(1));
+        Self {
+            slabs: Arc::new(Slabs {
+                classes: classes
+                    .into_iter()
+                    .map(|size| SlabClass {
+                        size,
+                        free: Mutex::new(vec![]),
+                    })
+                    .collect(),
+                allocated: AtomicU64::new(0),
+                reused: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// copies `data` into a chunk of the smallest class that fits it
+    ///
+    /// data larger than the largest class is copied into a buffer of its own
+    pub fn alloc(&self, data: &[u8]) -> Bytes {
+        let classes = &self.slabs.classes;
+        let class = classes.partition_point(|class| class.size < data.len());
+        if class == classes.len() {
+            return Bytes::copy_from_slice(data);
+        }
+
+        let free = classes[class].free.lock().unwrap().pop();
+        let mut buf = match free {
+            Some(buf) => {
+                self.slabs.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.slabs.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(classes[class].size)
+            }
+        };
+        buf.extend_from_slice(data);
+        Bytes::from_owner(Chunk {
+            buf,
+            class,
+            slabs: self.slabs.clone(),
+        })
+    }
+
+    /// returns the chunk size of every class, smallest first
+    pub fn class_sizes(&self) -> Vec<usize> {
+        self.slabs.classes.iter().map(|class| class.size).collect()
+    }
+
+    /// returns how many chunks were allocated & how many reused
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            allocated: self.slabs.allocated.load(Ordering::Relaxed),
+            reused: self.slabs.reused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::LruCache;
+    use crate::slab::{SlabAllocator, SlabStats};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_classes() {
+        let slabs = SlabAllocator::new(64, 256, 1.25);
+        assert_eq!(
+            vec![64, 80, 100, 125, 156, 195, 243, 256],
+            slabs.class_sizes()
+        );
+
+        // every value lands in the smallest class that fits it, so freeing each one before
+        // allocating the next only reuses chunks within a class
+        for len in [0, 64, 65, 80, 200, 243, 256] {
+            assert_eq!(vec![7; len], slabs.alloc(&vec![7; len]));
+        }
+        assert_eq!(
+            SlabStats {
+                allocated: 4,
+                reused: 3
+            },
+            slabs.stats()
+        );
+
+        // too large to pool
+        assert_eq!(vec![7; 300], slabs.alloc(&vec![7; 300]));
+        assert_eq!(4, slabs.stats().allocated);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let slabs = SlabAllocator::new(64, 1024, 2.0);
+        let data = slabs.alloc(b"rust");
+        let copy = data.clone();
+        drop(data);
+        // still held by the copy
+        assert_eq!(0, slabs.stats().reused);
+        drop(copy);
+
+        assert_eq!(Bytes::from("zig"), slabs.alloc(b"zig"));
+        assert_eq!(
+            SlabStats {
+                allocated: 1,
+                reused: 1
+            },
+            slabs.stats()
+        );
+        // a different class needs its own chunk
+        slabs.alloc(&[0; 100]);
+        assert_eq!(2, slabs.stats().allocated);
+    }
+
+    #[test]
+    fn test_reuse_after_eviction() {
+        let slabs = SlabAllocator::default();
+        let cache = LruCache::new(2);
+        for i in 0..2 {
+            cache.insert(i, slabs.alloc(format!("value{}", i).as_bytes()));
+        }
+        assert_eq!(2, slabs.stats().allocated);
+
+        // every insert evicts a value, whose chunk the next insert takes
+        for i in 2..100 {
+            let evicted = cache.insert(i, slabs.alloc(format!("value{}", i).as_bytes()));
+            assert_eq!(1, evicted.len());
+        }
+        assert_eq!(Some(Bytes::from("value99")), cache.get(&99));
+        let stats = slabs.stats();
+        assert_eq!(100, stats.allocated + stats.reused);
+        assert!(stats.allocated <= 4, "{:?}", stats);
+    }
+}
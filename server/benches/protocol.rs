@@ -0,0 +1,58 @@
+//! Command parsing throughput & set/get round trips to a server over loopback.
+//!
+//! Run with `cargo bench -p server --bench protocol`.
+
+use atlas::client::Client;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use server::Server;
+use server::commands::parse_input;
+use std::hint::black_box;
+
+/// value sizes benched: small ones like counters & large ones like rendered pages
+const VALUE_SIZES: [usize; 2] = [16, 4096];
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_input");
+    for size in VALUE_SIZES {
+        let mut set = format!("set abhi 0 0 {}\r\n", size).into_bytes();
+        set.extend(vec![b'x'; size]);
+        set.extend_from_slice(b"\r\n");
+        group.throughput(Throughput::Bytes(set.len() as u64));
+        group.bench_with_input(BenchmarkId::new("set", size), &set, |b, set| {
+            b.iter(|| black_box(parse_input(black_box(set))))
+        });
+    }
+
+    let get = b"get abhi ash lilb rust zig go\r\n";
+    group.throughput(Throughput::Bytes(get.len() as u64));
+    group.bench_function("get", |b| b.iter(|| black_box(parse_input(black_box(get)))));
+    group.finish();
+}
+
+fn round_trip(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut client = runtime.block_on(async {
+        let mut server = Server::new("127.0.0.1", 0, 1000);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+        Client::new("127.0.0.1", addr.port()).await.unwrap()
+    });
+
+    let mut group = c.benchmark_group("round_trip");
+    group.throughput(Throughput::Elements(1));
+    for size in VALUE_SIZES {
+        let value = vec![b'x'; size];
+        group.bench_with_input(BenchmarkId::new("set_get", size), &value, |b, value| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    client.set("abhi", 0, 0, value).await.unwrap();
+                    black_box(client.get("abhi").await.unwrap());
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, round_trip);
+criterion_main!(benches);
@@ -1,185 +1,2886 @@
-use bytes::Bytes;
-use core::cache::LruCache;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-pub fn parse_input(input: &str) -> Command {
-    let input_array: Vec<&str> = input.trim().split(' ').collect();
-    match input_array[0] {
-        "set" => {
-            if input_array.len() == 5 {
-                return Command::Set(
-                    input_array[1].to_string(),
-                    input_array[2].parse().unwrap(),
-                    input_array[3].parse().unwrap(),
-                    Bytes::from(input_array[4].as_bytes().to_owned()),
-                );
+use crate::persist::{self, Log, Record};
+use crate::protocol::{ParsedCommand, read_data_block, tokenize};
+use atlas::cache::{Action, ByteSized, LruCache, Store, UnboundedCache};
+use atlas::slab::SlabAllocator;
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// a value stored in the cache along with its metadata
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// opaque flags set by the client
+    pub flags: u32,
+    /// absolute expiry time in millis; 0 means the item never expires
+    pub exp_time: u128,
+    /// unique token that changes every time the item is stored
+    pub cas: u64,
+    /// time in millis when the item was stored
+    pub stored_at: u128,
+    /// the last fetch by a client, shared by all the copies of the item
+    pub access: Arc<Mutex<Access>>,
+    pub data: Bytes,
+}
+
+/// the last fetch of an item, reported by `mg`
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    /// whether the item was ever fetched
+    pub fetched: bool,
+    /// time in millis when the item was last fetched, or stored if it never was
+    pub at: u128,
+}
+
+impl ByteSized for Item {
+    fn byte_size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// server-wide counters reported by `stats`
+#[derive(Default)]
+pub struct Stats {
+    get_hits: AtomicUsize,
+    get_misses: AtomicUsize,
+    cmd_set: AtomicUsize,
+    curr_items: AtomicUsize,
+    bytes: AtomicUsize,
+    curr_connections: AtomicUsize,
+    total_connections: AtomicUsize,
+    max_connections: AtomicUsize,
+}
+
+impl Stats {
+    /// returns the number of reads that found an item
+    pub fn hits(&self) -> u64 {
+        self.get_hits.load(Ordering::Relaxed) as u64
+    }
+
+    /// returns the number of reads that found nothing, expired & flushed items included
+    pub fn misses(&self) -> u64 {
+        self.get_misses.load(Ordering::Relaxed) as u64
+    }
+
+    /// counts a newly opened connection
+    pub fn connection_opened(&self) {
+        self.curr_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a closed connection
+    pub fn connection_closed(&self) {
+        self.curr_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// records how many connections may be open at once
+    pub fn set_max_connections(&self, max_connections: usize) {
+        self.max_connections
+            .store(max_connections, Ordering::Relaxed);
+    }
+}
+
+/// longest key accepted by default, as in memcached
+pub const DEFAULT_MAX_KEY_SIZE: usize = 250;
+
+/// largest value accepted by default, as in memcached
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// most keys listed by one `scan`
+pub const SCAN_PAGE_SIZE: usize = 100;
+
+/// a store picked at runtime
+pub type DynStore = Box<dyn Store<String, Item> + Send + Sync>;
+
+/// state shared across all connections
+pub struct State<C = DynStore> {
+    pub cache: C,
+    pub stats: Stats,
+    cas: AtomicU64,
+    /// items with a cas token up to this one were flushed
+    flushed_cas: AtomicU64,
+    /// time in millis at which a delayed flush takes effect; 0 if none is pending
+    flush_at: AtomicU64,
+    /// keys deleted with a time, refused stores until the time in millis they're mapped to
+    blocked: Mutex<HashMap<String, u128>>,
+    max_key_size: usize,
+    pub(crate) max_value_size: usize,
+    /// values are copied into chunks from here when set
+    slabs: Option<SlabAllocator>,
+    /// whether admin commands that aren't part of memcached, like `scan`, are answered
+    admin: bool,
+    /// up to how many percent of a stored item's ttl is taken off at random
+    ttl_jitter: u32,
+    /// whether the jitter may also add to the ttl
+    ttl_jitter_extends: bool,
+    /// keys the hasher drawing the jitter
+    jitter_seed: RandomState,
+    /// jitters drawn so far, hashed for the next one
+    jitter_draws: AtomicU64,
+    /// ttl of the items stored with an exptime of 0, which never expire without one
+    default_ttl: Option<Duration>,
+    /// commands taking longer than this are logged at warn level
+    pub(crate) slow_log_threshold: Option<Duration>,
+    /// every change is logged here once persisted
+    log: Option<Log>,
+    /// held for reading while a change is made & logged, & for writing while a flush clears the
+    /// cache & logs it, so the log never has a change made after a flush before the flush
+    log_order: RwLock<()>,
+    /// held while saving a snapshot so two never write the same file at once
+    snapshot_lock: Mutex<()>,
+}
+
+impl State {
+    /// creates a new `State` with a cache of threshold `th`
+    pub fn new(th: usize) -> Self {
+        Self::with_cache(Box::new(LruCache::new(th)))
+    }
+
+    /// creates a new `State` with a cache holding up to `max_bytes` of data
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        Self::with_cache(Box::new(LruCache::with_max_bytes(max_bytes)))
+    }
+
+    /// creates a new `State` with a cache that never evicts
+    pub fn unbounded() -> Self {
+        Self::with_cache(Box::new(UnboundedCache::new()))
+    }
+}
+
+impl<C: Store<String, Item>> State<C> {
+    /// creates a new `State` storing items in `cache`
+    pub fn with_cache(cache: C) -> Self {
+        Self {
+            cache,
+            stats: Stats::default(),
+            cas: AtomicU64::new(0),
+            flushed_cas: AtomicU64::new(0),
+            flush_at: AtomicU64::new(0),
+            blocked: Mutex::new(HashMap::new()),
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            slabs: None,
+            admin: false,
+            ttl_jitter: 0,
+            ttl_jitter_extends: false,
+            jitter_seed: RandomState::new(),
+            jitter_draws: AtomicU64::new(0),
+            default_ttl: None,
+            slow_log_threshold: None,
+            log: None,
+            log_order: RwLock::new(()),
+            snapshot_lock: Mutex::new(()),
+        }
+    }
+
+    /// rejects keys longer than `max_key_size` bytes
+    pub fn max_key_size(mut self, max_key_size: usize) -> Self {
+        self.max_key_size = max_key_size;
+        self
+    }
+
+    /// refuses to store values larger than `max_value_size` bytes
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    /// stores values in chunks from `slabs`, reused once the values are evicted or replaced
+    pub fn slabs(mut self, slabs: SlabAllocator) -> Self {
+        self.slabs = Some(slabs);
+        self
+    }
+
+    /// answers admin commands that aren't part of memcached, like `scan`, if `admin`; they're
+    /// unknown commands otherwise
+    pub fn admin(mut self, admin: bool) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// takes up to `percent` percent, at most 100, off the ttl of each item stored at random so
+    /// that items stored together with the same ttl don't all expire at once
+    ///
+    /// if `extends`, the jitter may add as much to a ttl as it may take off, spreading the
+    /// expiries either side of the one asked for instead of only before it
+    pub fn ttl_jitter(mut self, percent: u32, extends: bool) -> Self {
+        self.ttl_jitter = percent.min(100);
+        self.ttl_jitter_extends = extends;
+        self
+    }
+
+    /// expires the items stored with an exptime of 0 after `default_ttl` instead of never
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = Some(default_ttl);
+        self
+    }
+
+    /// logs the commands taking longer than `threshold` to run at warn level
+    pub fn slow_log_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_log_threshold = Some(threshold);
+        self
+    }
+
+    /// converts the `exp_time` of an item being stored into an absolute time in millis like
+    /// `expiry`, with the default ttl for 0 if there is one, jittered as per `ttl_jitter`
+    fn deadline(&self, exp_time: i64) -> anyhow::Result<u128> {
+        let now = now()?;
+        let deadline = match (exp_time, self.default_ttl) {
+            (0, Some(default_ttl)) => now + default_ttl.as_millis(),
+            _ => expiry(exp_time)?,
+        };
+        if self.ttl_jitter == 0 || deadline <= now {
+            return Ok(deadline);
+        }
+        let ttl = (deadline - now) as f64;
+        let draw = self
+            .jitter_seed
+            .hash_one(self.jitter_draws.fetch_add(1, Ordering::Relaxed));
+        // uniform in [0, 1)
+        let r = (draw >> 11) as f64 / (1u64 << 53) as f64;
+        let band = ttl * self.ttl_jitter as f64 / 100.0;
+        let jitter = if self.ttl_jitter_extends {
+            band * (2.0 * r - 1.0)
+        } else {
+            -band * r
+        };
+        // still in the future, so the item isn't taken for one that never expires or is gone
+        Ok(now + ((ttl + jitter) as u128).max(1))
+    }
+
+    /// replays the log at `path` into the cache & logs every change from then on
+    ///
+    /// items that have expired since are skipped & the log is rewritten to hold just the items
+    /// left so it doesn't grow across restarts
+    pub fn persist(mut self, path: &Path) -> anyhow::Result<Self> {
+        self.replay(persist::read(path)?)?;
+        self.log = Some(Log::create(path, self.records()?)?);
+        Ok(self)
+    }
+
+    /// loads the items in the snapshot at `path` into the cache, skipping those expired since
+    pub fn restore(&self, path: &Path) -> anyhow::Result<()> {
+        self.replay(persist::read(path)?)
+    }
+
+    /// writes every live item to a snapshot at `path`, returning how many there were
+    pub fn snapshot(&self, path: &Path) -> anyhow::Result<usize> {
+        let _guard = self.snapshot_lock.lock().unwrap();
+        let records = self.records()?;
+        let len = records.len();
+        persist::write(path, records)?;
+        Ok(len)
+    }
+
+    /// applies `records` read back from disk, skipping items that have expired since
+    fn replay(&self, records: Vec<Record>) -> anyhow::Result<()> {
+        for record in records {
+            match record {
+                Record::Set {
+                    key,
+                    flags,
+                    exp_time,
+                    data,
+                } if !is_expired(exp_time)? => self.store(key, flags, exp_time, data)?,
+                // an expired value replaces an older one all the same
+                Record::Set { key, .. } | Record::Delete(key) => {
+                    self.remove(&key);
+                }
+                Record::Clear => self.flush(0)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// the items that can still be read, least recently used first, as records to write out
+    fn records(&self) -> anyhow::Result<Vec<Record>> {
+        let mut records = vec![];
+        for key in self.cache.keys() {
+            let Some(item) = self.cache.peek(&key) else {
+                continue;
+            };
+            if is_expired(item.exp_time)? || self.is_flushed(&item)? {
+                continue;
+            }
+            records.push(Record::Set {
+                key,
+                flags: item.flags,
+                exp_time: item.exp_time,
+                data: item.data,
+            });
+        }
+        Ok(records)
+    }
+
+    /// writes out the changes logged since the last call, if persisted
+    pub fn flush_log(&self) -> anyhow::Result<()> {
+        match &self.log {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// logs `record` if persisted
+    fn log(&self, record: impl FnOnce() -> Record) {
+        if let Some(log) = &self.log {
+            log.append(record());
+        }
+    }
+
+    /// checks that `key` fits within `max_key_size` & has no spaces or control characters
+    fn is_valid_key(&self, key: &str) -> bool {
+        !key.is_empty()
+            && key.len() <= self.max_key_size
+            && !key.chars().any(|c| c == ' ' || c.is_control())
+    }
+
+    /// returns the next unique cas token
+    fn next_cas(&self) -> u64 {
+        self.cas.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// makes a new item of `data` with a fresh cas token, copied into a slab chunk if values are
+    /// kept in slabs
+    fn item(&self, flags: u32, exp_time: u128, data: Bytes) -> anyhow::Result<Item> {
+        let stored_at = now()?;
+        Ok(Item {
+            flags,
+            exp_time,
+            cas: self.next_cas(),
+            stored_at,
+            access: Arc::new(Mutex::new(Access {
+                fetched: false,
+                at: stored_at,
+            })),
+            data: match &self.slabs {
+                Some(slabs) => slabs.alloc(&data),
+                None => data,
+            },
+        })
+    }
+
+    /// runs `f` on the item for `key`, `None` if there's none that can still be read, & stores
+    /// the item `f` returns in place of the one there, if any, all in one step so no other change
+    /// to the key lands in between; returns what `f` returns besides
+    ///
+    /// `f` runs with the key locked in the cache, so it must not use the cache
+    fn update<R>(
+        &self,
+        key: String,
+        f: impl FnOnce(Option<&Item>) -> anyhow::Result<(Option<Item>, R)>,
+    ) -> anyhow::Result<R> {
+        let mut f = Some(f);
+        let mut out = None;
+        let _order = self.log.as_ref().map(|_| self.log_order.read().unwrap());
+        let evicted = self.cache.with_entry(key.clone(), &mut |prev| {
+            let f = f.take().expect("with_entry calls f once");
+            let result = match prev.as_deref() {
+                Some(item) => self
+                    .is_live(item)
+                    .and_then(|live| f(Some(item).filter(|_| live))),
+                None => f(None),
+            };
+            // an item made before a flush & landing after it is flushed already; it's dropped
+            // rather than stored & logged after the flush
+            let result = result.and_then(|(item, r)| {
+                let flushed = match &item {
+                    Some(item) => self.is_flushed(item)?,
+                    None => false,
+                };
+                Ok((item.filter(|_| !flushed), r))
+            });
+            match result {
+                Ok((Some(item), r)) => {
+                    // counted under the lock so a clear never takes an item off the counters
+                    // before it's on them; the item replaced is taken off, expired or not
+                    self.stats
+                        .bytes
+                        .fetch_add(item.data.len(), Ordering::Relaxed);
+                    match prev {
+                        Some(prev) => {
+                            self.stats
+                                .bytes
+                                .fetch_sub(prev.data.len(), Ordering::Relaxed);
+                        }
+                        None => {
+                            self.stats.curr_items.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    // logged under the lock so the log has the changes to a key in order
+                    self.log(|| Record::Set {
+                        key: key.clone(),
+                        flags: item.flags,
+                        exp_time: item.exp_time,
+                        data: item.data.clone(),
+                    });
+                    out = Some(Ok(r));
+                    Action::Insert(item)
+                }
+                Ok((None, r)) => {
+                    out = Some(Ok(r));
+                    Action::Keep
+                }
+                Err(e) => {
+                    out = Some(Err(e));
+                    Action::Keep
+                }
+            }
+        });
+
+        self.uncount(&evicted);
+        out.expect("with_entry calls f once")
+    }
+
+    /// stores `data` against `key` with a fresh cas token
+    fn store(&self, key: String, flags: u32, exp_time: u128, data: Bytes) -> anyhow::Result<()> {
+        let item = self.item(flags, exp_time, data)?;
+        self.update(key, |_| Ok((Some(item), ())))?;
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// resets the expiry of the item stored against `key` to `exp_time`, keeping its data & cas
+    ///
+    /// returns the item touched, `None` if there's none that can still be read
+    fn touch(&self, key: String, exp_time: i64) -> anyhow::Result<Option<Item>> {
+        let exp_time = self.deadline(exp_time)?;
+        self.update(key, |item| {
+            let item = item.map(|item| Item {
+                exp_time,
+                ..item.clone()
+            });
+            Ok((item.clone(), item))
+        })
+    }
+
+    /// takes the items evicted to make room or cleared off the counters
+    fn uncount(&self, items: &[(String, Item)]) {
+        for (_, item) in items {
+            self.stats.curr_items.fetch_sub(1, Ordering::Relaxed);
+            self.stats
+                .bytes
+                .fetch_sub(item.data.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// changes the number of items kept to `items`, evicting the items over it right away
+    ///
+    /// returns how many were evicted, `None` if the cache has no item limit to change
+    fn set_capacity(&self, items: usize) -> Option<usize> {
+        let evicted = self.cache.set_capacity(items)?;
+        self.uncount(&evicted);
+        Some(evicted.len())
+    }
+
+    /// removes the item for `key`
+    fn remove(&self, key: &str) -> Option<Item> {
+        self.remove_if(key, |_| true)
+    }
+
+    /// removes the item for `key` if `f` holds for it, in one step so that an item stored in
+    /// between isn't the one removed
+    ///
+    /// `f` runs with the key locked in the cache, so it must not use the cache
+    fn remove_if(&self, key: &str, f: impl FnOnce(&Item) -> bool) -> Option<Item> {
+        let mut f = Some(f);
+        let mut removed = None;
+        let _order = self.log.as_ref().map(|_| self.log_order.read().unwrap());
+        self.cache.with_entry(key.to_string(), &mut |item| {
+            let f = f.take().expect("with_entry calls f once");
+            match item {
+                Some(item) if f(item) => {
+                    // logged under the lock so the log has the changes to a key in order
+                    self.log(|| Record::Delete(key.to_string()));
+                    removed = Some(item.clone());
+                    Action::Remove
+                }
+                _ => Action::Keep,
+            }
+        });
+
+        let item = removed?;
+        self.stats.curr_items.fetch_sub(1, Ordering::Relaxed);
+        self.stats
+            .bytes
+            .fetch_sub(item.data.len(), Ordering::Relaxed);
+        Some(item)
+    }
+
+    /// removes the item for `key` & refuses stores to the key until `until`, an absolute time in
+    /// millis, if there was one
+    fn remove_blocking(&self, key: &str, until: u128) -> anyhow::Result<Option<Item>> {
+        let mut blocked = self.blocked.lock().unwrap();
+        let item = self.remove(key);
+        if item.is_some() && !is_expired(until)? {
+            // forget the blocks that ran out so the map doesn't grow
+            let now = now()?;
+            blocked.retain(|_, until| *until >= now);
+            blocked.insert(key.to_string(), until);
+        }
+        Ok(item)
+    }
+
+    /// checks if stores to `key` are refused for now after it was deleted with a time
+    fn is_blocked(&self, key: &String) -> anyhow::Result<bool> {
+        let mut blocked = self.blocked.lock().unwrap();
+        match blocked.get(key) {
+            Some(&until) if is_expired(until)? => {
+                blocked.remove(key);
+                Ok(false)
             }
+            Some(_) => Ok(true),
+            None => Ok(false),
         }
-        "get" => {
-            if input_array.len() == 2 {
-                return Command::Get(input_array[1].to_string());
+    }
+
+    /// gets the item for `key` like `get`, counting a hit or a miss
+    ///
+    /// the fetch is recorded on the item & the one before it is returned along with the item
+    fn lookup(&self, key: &String) -> anyhow::Result<Option<(Item, Access)>> {
+        self.fetched(self.get(key)?)
+    }
+
+    /// counts a hit for the `item` read by a client or a miss if there's none, recording the
+    /// fetch on it like `lookup`
+    fn fetched(&self, item: Option<Item>) -> anyhow::Result<Option<(Item, Access)>> {
+        match item {
+            Some(item) => {
+                self.stats.get_hits.fetch_add(1, Ordering::Relaxed);
+                let access = Access {
+                    fetched: true,
+                    at: now()?,
+                };
+                let prev = std::mem::replace(&mut *item.access.lock().unwrap(), access);
+                Ok(Some((item, prev)))
+            }
+            None => {
+                self.stats.get_misses.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
             }
         }
-        "delete" => {
-            if input_array.len() == 2 {
-                return Command::Delete(input_array[1].to_string());
+    }
+
+    /// the current value of every counter reported by `stats`, by name
+    pub fn stat_values(&self) -> [(&'static str, u64); 9] {
+        let load = |stat: &AtomicUsize| stat.load(Ordering::Relaxed) as u64;
+        [
+            ("get_hits", self.stats.hits()),
+            ("get_misses", self.stats.misses()),
+            ("cmd_set", load(&self.stats.cmd_set)),
+            ("curr_items", load(&self.stats.curr_items)),
+            ("bytes", load(&self.stats.bytes)),
+            ("curr_connections", load(&self.stats.curr_connections)),
+            ("total_connections", load(&self.stats.total_connections)),
+            ("max_connections", load(&self.stats.max_connections)),
+            ("evictions", self.cache.evictions()),
+        ]
+    }
+
+    /// formats the counters as `STAT <name> <value>` lines
+    fn stats(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        for (name, value) in self.stat_values() {
+            buf.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+        }
+        buf.extend_from_slice(b"END\r\n");
+        buf.freeze()
+    }
+
+    /// gets the item for `key` if present, unexpired & not flushed
+    fn get(&self, key: &String) -> anyhow::Result<Option<Item>> {
+        match self.cache.get(key) {
+            Some(item) if self.is_live(&item)? => Ok(Some(item)),
+            _ => Ok(None),
+        }
+    }
+
+    /// checks that `item` can still be read, neither expired nor flushed
+    fn is_live(&self, item: &Item) -> anyhow::Result<bool> {
+        Ok(!is_expired(item.exp_time)? && !self.is_flushed(item)?)
+    }
+
+    /// returns the chunk sizes of the slab classes items are put in buckets by, smallest first,
+    /// whether or not values are kept in slabs
+    fn class_sizes(&self) -> Vec<usize> {
+        match &self.slabs {
+            Some(slabs) => slabs.class_sizes(),
+            None => SlabAllocator::default().class_sizes(),
+        }
+    }
+
+    /// formats the number of live items & their bytes in every bucket holding any as
+    /// `STAT items:<bucket>:<name> <value>` lines
+    fn stats_items(&self) -> anyhow::Result<Bytes> {
+        let classes = self.class_sizes();
+        let mut buckets = BTreeMap::new();
+        for record in self.records()? {
+            let Record::Set { data, .. } = record else {
+                continue;
+            };
+            let (number, bytes) = buckets
+                .entry(bucket(&classes, data.len()))
+                .or_insert((0, 0));
+            *number += 1;
+            *bytes += data.len();
+        }
+        let mut buf = BytesMut::new();
+        for (bucket, (number, bytes)) in buckets {
+            buf.extend_from_slice(
+                format!("STAT items:{}:number {}\r\n", bucket, number).as_bytes(),
+            );
+            buf.extend_from_slice(format!("STAT items:{}:bytes {}\r\n", bucket, bytes).as_bytes());
+        }
+        buf.extend_from_slice(b"END\r\n");
+        Ok(buf.freeze())
+    }
+
+    /// formats up to `limit`, or with 0 all, of the live items in `bucket_id` as
+    /// `ITEM <key> [<bytes> b; <ttl> s]` lines, least recently used first
+    ///
+    /// the ttl is the seconds left, rounded up, or 0 if the item never expires
+    fn cachedump(&self, bucket_id: usize, limit: usize) -> anyhow::Result<Bytes> {
+        let classes = self.class_sizes();
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let now = now()?;
+        let mut buf = BytesMut::new();
+        let items = self
+            .records()?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Set {
+                    key,
+                    exp_time,
+                    data,
+                    ..
+                } if bucket(&classes, data.len()) == bucket_id => Some((key, exp_time, data.len())),
+                _ => None,
+            });
+        for (key, exp_time, len) in items.take(limit) {
+            let ttl = match exp_time {
+                0 => 0,
+                exp_time => exp_time.saturating_sub(now).div_ceil(1000),
+            };
+            buf.extend_from_slice(format!("ITEM {} [{} b; {} s]\r\n", key, len, ttl).as_bytes());
+        }
+        buf.extend_from_slice(b"END\r\n");
+        Ok(buf.freeze())
+    }
+
+    /// lists up to `SCAN_PAGE_SIZE` live keys starting with `prefix`, in order, from those after
+    /// `after` if given
+    fn scan(&self, prefix: &str, after: Option<&str>) -> anyhow::Result<Vec<String>> {
+        let mut keys = vec![];
+        for key in self.cache.keys() {
+            if !key.starts_with(prefix) || after.is_some_and(|after| key.as_str() <= after) {
+                continue;
+            }
+            // peek so listing keys doesn't disturb the lru order
+            let Some(item) = self.cache.peek(&key) else {
+                continue;
+            };
+            if !is_expired(item.exp_time)? && !self.is_flushed(&item)? {
+                keys.push(key);
             }
         }
-        "version" => {
-            if input_array.len() == 1 {
-                return Command::Version;
+        keys.sort_unstable();
+        keys.truncate(SCAN_PAGE_SIZE);
+        Ok(keys)
+    }
+
+    /// removes all the expired & flushed items, returning how many were removed
+    pub fn reap(&self) -> anyhow::Result<usize> {
+        let mut reaped = 0;
+        for key in self.cache.keys() {
+            // peek so the sweep doesn't disturb the lru order
+            let Some(item) = self.cache.peek(&key) else {
+                continue;
+            };
+            // unless it was stored again since
+            if !self.is_live(&item)?
+                && self
+                    .remove_if(&key, |current| current.cas == item.cas)
+                    .is_some()
+            {
+                reaped += 1;
             }
         }
-        _ => {}
+        Ok(reaped)
+    }
+
+    /// invalidates all items stored so far, after `delay` millis if non-zero
+    fn flush(&self, delay: u128) -> anyhow::Result<()> {
+        if delay == 0 {
+            // no change lands between clearing the cache & logging it
+            let _order = self.log.as_ref().map(|_| self.log_order.write().unwrap());
+            self.flushed_cas
+                .store(self.cas.load(Ordering::Relaxed), Ordering::Relaxed);
+            // nothing stored so far can be read again; free it right away
+            let cleared = self.cache.clear();
+            self.uncount(&cleared);
+            self.log(|| Record::Clear);
+        } else {
+            // not logged; a delayed flush doesn't outlive a restart
+            self.flush_at
+                .store((now()? + delay) as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// checks if `item` was invalidated by a `flush_all`
+    fn is_flushed(&self, item: &Item) -> anyhow::Result<bool> {
+        if item.cas <= self.flushed_cas.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        let flush_at = self.flush_at.load(Ordering::Relaxed) as u128;
+        Ok(flush_at != 0 && flush_at <= now()? && item.stored_at <= flush_at)
+    }
+}
+
+/// parses a command line, followed by a data block for storage commands
+///
+/// the command line must be ascii but the data block may hold arbitrary bytes
+pub fn parse_input(input: impl AsRef<[u8]>) -> Command {
+    let input = input.as_ref();
+    let (line, data) = match input.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => (&input[..end], &input[end + 2..]),
+        None => (input, &b""[..]),
+    };
+    let parsed = match tokenize(line) {
+        Ok(parsed) => parsed,
+        Err(e) => return Command::BadSyntax(e),
+    };
+    match parse_command(&parsed, data) {
+        // errors are still replied to
+        Some(command @ (Command::BadDataChunk | Command::Unknown)) => command,
+        Some(command) if parsed.noreply => Command::NoReply(Box::new(command)),
+        Some(command) => command,
+        None => Command::BadFormat,
+    }
+}
+
+/// builds the command out of its tokens, `None` if a numeric argument doesn't parse
+fn parse_command(parsed: &ParsedCommand, data: &[u8]) -> Option<Command> {
+    let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+    let command = match (parsed.keyword, &parsed.args[..]) {
+        ("set", [key, flags, exp_time, _]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Set(
+                key.to_string(),
+                flags.parse().ok()?,
+                exp_time.parse().ok()?,
+                data.len(),
+                data,
+            ),
+            None => Command::BadDataChunk,
+        },
+        ("add", [key, flags, exp_time, _]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Add(
+                key.to_string(),
+                flags.parse().ok()?,
+                exp_time.parse().ok()?,
+                data,
+            ),
+            None => Command::BadDataChunk,
+        },
+        ("replace", [key, flags, exp_time, _]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Replace(
+                key.to_string(),
+                flags.parse().ok()?,
+                exp_time.parse().ok()?,
+                data,
+            ),
+            None => Command::BadDataChunk,
+        },
+        // append/prepend ignore <flags> & <exptime> and keep those of the stored entry
+        ("append", [key, _, _, _]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Append(key.to_string(), data),
+            None => Command::BadDataChunk,
+        },
+        ("prepend", [key, _, _, _]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Prepend(key.to_string(), data),
+            None => Command::BadDataChunk,
+        },
+        ("get", args @ [_, ..]) => Command::Get(keys(args)),
+        ("gets", args @ [_, ..]) => Command::Gets(keys(args)),
+        ("cas", [key, flags, exp_time, _, cas]) => match read_data_block(parsed.data_len?, data) {
+            Some(data) => Command::Cas(
+                key.to_string(),
+                flags.parse().ok()?,
+                exp_time.parse().ok()?,
+                cas.parse().ok()?,
+                data,
+            ),
+            None => Command::BadDataChunk,
+        },
+        ("delete", [key]) => Command::Delete(key.to_string(), 0),
+        ("delete", [key, time]) => Command::Delete(key.to_string(), time.parse().ok()?),
+        ("incr", [key, delta]) => Command::Incr(key.to_string(), delta.parse().ok()?),
+        ("decr", [key, delta]) => Command::Decr(key.to_string(), delta.parse().ok()?),
+        ("gat", [exp_time, args @ ..]) if !args.is_empty() => {
+            Command::Gat(exp_time.parse().ok()?, keys(args))
+        }
+        ("gats", [exp_time, args @ ..]) if !args.is_empty() => {
+            Command::Gats(exp_time.parse().ok()?, keys(args))
+        }
+        ("touch", [key, exp_time]) => Command::Touch(key.to_string(), exp_time.parse().ok()?),
+        ("mg", [key, flags @ ..]) => Command::Mg(
+            key.to_string(),
+            flags
+                .iter()
+                .map(|flag| match flag.as_bytes() {
+                    [flag @ (b't' | b's' | b'h' | b'l' | b'v' | b'k' | b'f' | b'c')] => {
+                        Some(*flag as char)
+                    }
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+        ),
+        ("version", []) => Command::Version,
+        ("ping", []) => Command::Ping,
+        ("stats", []) => Command::Stats,
+        ("stats", ["items"]) => Command::StatsItems,
+        ("stats", ["cachedump", bucket, limit]) => {
+            Command::Cachedump(bucket.parse().ok()?, limit.parse().ok()?)
+        }
+        ("flush_all", []) => Command::Flushall(0),
+        ("flush_all", [delay]) => Command::Flushall(delay.parse().ok()?),
+        ("cache_limit", [items]) => Command::CacheLimit(items.parse().ok()?),
+        ("scan", [prefix]) => Command::Scan(prefix.to_string(), None),
+        ("scan", [prefix, after]) => Command::Scan(prefix.to_string(), Some(after.to_string())),
+        _ => Command::Unknown,
+    };
+    Some(command)
+}
+
+/// returns the bucket of a value of `len` bytes, numbered from 1 like memcached's slab classes:
+/// that of the smallest of the `classes` fitting it, or the one after the largest if none does
+fn bucket(classes: &[usize], len: usize) -> usize {
+    classes.partition_point(|&size| size < len) + 1
+}
+
+/// returns the current time in millis since the unix epoch
+fn now() -> anyhow::Result<u128> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis())
+}
+
+/// longest `exptime` taken as relative to now; longer ones are unix timestamps
+const MAX_RELATIVE_EXP_TIME: i64 = 60 * 60 * 24 * 30;
+
+/// converts an `exp_time` in seconds into an absolute time in millis; 0 means the entry never
+/// expires
+///
+/// up to 30 days the `exp_time` is relative to now, beyond that it is a unix timestamp & a
+/// negative one expires the entry immediately
+fn expiry(exp_time: i64) -> anyhow::Result<u128> {
+    match exp_time {
+        0 => Ok(0),
+        // any time in the past will do
+        ..0 => Ok(1),
+        1..=MAX_RELATIVE_EXP_TIME => Ok(now()? + exp_time as u128 * 1000),
+        _ => Ok(exp_time as u128 * 1000),
+    }
+}
+
+/// checks if an absolute `exp_time` has already passed
+fn is_expired(exp_time: u128) -> anyhow::Result<bool> {
+    Ok(exp_time != 0 && exp_time < now()?)
+}
+
+/// a command that couldn't be carried out, rendered as the protocol's error reply
+#[derive(Debug)]
+pub enum CommandError {
+    /// the command is unknown or has the wrong number of arguments
+    Unknown,
+    /// the data block doesn't match its declared length
+    BadDataChunk,
+    /// a known command with malformed arguments
+    BadFormat,
+    /// the request is malformed in the way described
+    Client(String),
+    /// the server failed while carrying out a valid command
+    Server(anyhow::Error),
+}
+
+impl CommandError {
+    /// returns the error line sent back to the client
+    pub fn reply(&self) -> Bytes {
+        match self {
+            CommandError::Unknown => Bytes::from("ERROR\r\n"),
+            CommandError::BadDataChunk => Bytes::from("CLIENT_ERROR bad data chunk\r\n"),
+            CommandError::BadFormat => Bytes::from("CLIENT_ERROR bad command line format\r\n"),
+            CommandError::Client(msg) => Bytes::from(format!("CLIENT_ERROR {}\r\n", msg)),
+            CommandError::Server(e) => Bytes::from(format!("SERVER_ERROR {}\r\n", e)),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown => write!(f, "unknown command"),
+            CommandError::BadDataChunk => write!(f, "bad data chunk"),
+            CommandError::BadFormat => write!(f, "bad command line format"),
+            CommandError::Client(msg) => write!(f, "{}", msg),
+            CommandError::Server(e) => write!(f, "{}", e),
+        }
     }
+}
+
+impl std::error::Error for CommandError {}
 
-    Command::Invalid
+impl From<anyhow::Error> for CommandError {
+    fn from(e: anyhow::Error) -> Self {
+        CommandError::Server(e)
+    }
 }
 
+/// reply to a storage command whose value exceeds the maximum value size
+pub(crate) const TOO_LARGE: &str = "SERVER_ERROR object too large for cache\r\n";
+
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    // set <key> <flags> <exptime> <data>
-    Set(String, usize, u128, Bytes),
-    Add(String, Bytes),
-    Replace(String, Bytes),
+    // set <key> <flags> <exptime> <bytes>\r\n<data>\r\n
+    Set(String, u32, i64, usize, Bytes),
+    // add <key> <flags> <exptime> <bytes>\r\n<data>\r\n
+    Add(String, u32, i64, Bytes),
+    // replace <key> <flags> <exptime> <bytes>\r\n<data>\r\n
+    Replace(String, u32, i64, Bytes),
+    // append <key> <flags> <exptime> <bytes>\r\n<data>\r\n
     Append(String, Bytes),
+    // prepend <key> <flags> <exptime> <bytes>\r\n<data>\r\n
     Prepend(String, Bytes),
-    Get(String),
-    Gets(String),
-    Delete(String),
-    Incr(String),
-    Decr(String),
-    Cas(String, Bytes),
+    // get <key>*
+    Get(Vec<String>),
+    // gets <key>*
+    Gets(Vec<String>),
+    // delete <key> [time]
+    Delete(String, i64),
+    // incr <key> <value>
+    Incr(String, u64),
+    // decr <key> <value>
+    Decr(String, u64),
+    // cas <key> <flags> <exptime> <bytes> <cas-id>\r\n<data>\r\n
+    Cas(String, u32, i64, u64, Bytes),
+    // touch <key> <exptime>
+    Touch(String, i64),
+    // mg <key> <flag>*
+    Mg(String, Vec<char>),
+    // gat <exptime> <key>*
+    Gat(i64, Vec<String>),
+    // gats <exptime> <key>*
+    Gats(i64, Vec<String>),
     Stats,
+    // stats items
+    StatsItems,
+    // stats cachedump <bucket> <limit>, an admin command
+    Cachedump(usize, usize),
     Version,
-    Flushall,
-    Invalid,
+    // ping, answered with PONG as a liveness probe
+    Ping,
+    // flush_all [delay]
+    Flushall(u128),
+    // cache_limit <items>
+    CacheLimit(usize),
+    // scan <prefix> [<after>], an admin command
+    Scan(String, Option<String>),
+    // the data block doesn't match its declared length
+    BadDataChunk,
+    // a known command whose arguments don't parse
+    BadFormat,
+    // a request that can't be parsed, with the reason
+    BadSyntax(String),
+    // <command> ... noreply
+    NoReply(Box<Command>),
+    // a command that isn't supported
+    Unknown,
 }
 
 impl Command {
-    pub fn handle(self, cache: Arc<LruCache<String, (u128, Bytes)>>) -> anyhow::Result<Bytes> {
+    /// carries out the command, returning its reply; the reply is empty for `noreply` commands
+    pub fn handle<C: Store<String, Item>>(
+        self,
+        state: Arc<State<C>>,
+    ) -> Result<Bytes, CommandError> {
+        if !self.keys().iter().all(|key| state.is_valid_key(key)) {
+            return Err(CommandError::BadFormat);
+        }
         match self {
-            Command::Set(key, flags, exp_time, data) => {
-                let exp_time = if exp_time != 0 {
-                    SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() + exp_time
-                } else {
-                    0
-                };
-                cache.insert(key, (exp_time, data));
-                Ok(Bytes::from("STORED"))
-            }
-            Command::Add(_, _) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Replace(_, _) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Append(_, _) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Prepend(_, _) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Get(key) => {
-                if let Some(v) = cache.get(&key) {
-                    if v.0 != 0 {
-                        let current_time =
-                            SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-                        if v.0 >= current_time {
-                            Ok(v.1.clone())
-                        } else {
-                            Ok(Bytes::from("NOT FOUND"))
-                        }
-                    } else {
-                        Ok(v.1.clone())
+            Command::Set(_, _, _, _, data)
+            | Command::Add(_, _, _, data)
+            | Command::Replace(_, _, _, data)
+            | Command::Cas(_, _, _, _, data)
+                if data.len() > state.max_value_size =>
+            {
+                Ok(Bytes::from(TOO_LARGE))
+            }
+            // refused for a while after a delete with a time
+            Command::Set(ref key, ..)
+            | Command::Add(ref key, ..)
+            | Command::Replace(ref key, ..)
+            | Command::Append(ref key, _)
+            | Command::Prepend(ref key, _)
+            | Command::Cas(ref key, ..)
+                if state.is_blocked(key)? =>
+            {
+                Ok(Bytes::from("NOT_STORED\r\n"))
+            }
+            Command::Set(key, flags, exp_time, _, data) => {
+                state.store(key, flags, state.deadline(exp_time)?, data)?;
+                Ok(Bytes::from("STORED\r\n"))
+            }
+            Command::Add(key, flags, exp_time, data) => {
+                let exp_time = state.deadline(exp_time)?;
+                // an expired entry counts as absent & is replaced
+                let reply = state.update(key, |item| match item {
+                    Some(_) => Ok((None, "NOT_STORED\r\n")),
+                    None => {
+                        state.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+                        Ok((Some(state.item(flags, exp_time, data)?), "STORED\r\n"))
                     }
-                } else {
-                    Ok(Bytes::from("NOT FOUND"))
+                })?;
+                Ok(Bytes::from(reply))
+            }
+            Command::Replace(key, flags, exp_time, data) => {
+                let exp_time = state.deadline(exp_time)?;
+                // an expired entry counts as absent and must not be brought back
+                let reply = state.update(key, |item| match item {
+                    Some(_) => {
+                        state.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+                        Ok((Some(state.item(flags, exp_time, data)?), "STORED\r\n"))
+                    }
+                    None => Ok((None, "NOT_STORED\r\n")),
+                })?;
+                Ok(Bytes::from(reply))
+            }
+            Command::Append(key, data) => Self::concat(state, key, data, false),
+            Command::Prepend(key, data) => Self::concat(state, key, data, true),
+            Command::Get(..) | Command::Gets(..) | Command::Gat(..) | Command::Gats(..) => {
+                let chunks = self.handle_chunked(state)?;
+                let mut buf = BytesMut::with_capacity(chunks.iter().map(Bytes::len).sum());
+                for chunk in chunks {
+                    buf.extend_from_slice(&chunk);
                 }
+                Ok(buf.freeze())
             }
-            Command::Gets(_) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Delete(key) => {
-                if let Some(_) = cache.remove(&key) {
-                    Ok(Bytes::from("DELETED"))
+            Command::Delete(key, time) => {
+                let removed = match time {
+                    0 => state.remove(&key),
+                    _ => state.remove_blocking(&key, expiry(time)?)?,
+                };
+                if removed.is_some() {
+                    Ok(Bytes::from("DELETED\r\n"))
                 } else {
-                    Ok(Bytes::from("NOT FOUND"))
+                    Ok(Bytes::from("NOT_FOUND\r\n"))
+                }
+            }
+            // incr wraps around at 64 bits
+            Command::Incr(key, delta) => Self::apply_delta(state, key, |n| n.wrapping_add(delta)),
+            // decr never goes below 0
+            Command::Decr(key, delta) => Self::apply_delta(state, key, |n| n.saturating_sub(delta)),
+            Command::Cas(key, flags, exp_time, cas, data) => {
+                let exp_time = state.deadline(exp_time)?;
+                // the check & the store are one step, so no other change to the item lands in
+                // between
+                let reply = state.update(key, |item| match item {
+                    Some(item) if item.cas == cas => {
+                        state.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+                        Ok((Some(state.item(flags, exp_time, data)?), "STORED\r\n"))
+                    }
+                    Some(_) => Ok((None, "EXISTS\r\n")),
+                    None => Ok((None, "NOT_FOUND\r\n")),
+                })?;
+                Ok(Bytes::from(reply))
+            }
+            Command::Touch(key, exp_time) => match state.touch(key, exp_time)? {
+                Some(_) => Ok(Bytes::from("TOUCHED\r\n")),
+                None => Ok(Bytes::from("NOT_FOUND\r\n")),
+            },
+            Command::Mg(key, flags) => Self::meta_get(state, key, flags),
+            Command::Stats => Ok(state.stats()),
+            Command::StatsItems => Ok(state.stats_items()?),
+            Command::Cachedump(..) if !state.admin => Err(CommandError::Unknown),
+            Command::Cachedump(bucket, limit) => Ok(state.cachedump(bucket, limit)?),
+            Command::Version => Ok(Bytes::from(concat!(
+                "VERSION ",
+                env!("CARGO_PKG_VERSION"),
+                "\r\n"
+            ))),
+            Command::Ping => Ok(Bytes::from("PONG\r\n")),
+            Command::Flushall(delay) => {
+                state.flush(delay)?;
+                Ok(Bytes::from("OK\r\n"))
+            }
+            Command::CacheLimit(items) => match state.set_capacity(items) {
+                Some(_) => Ok(Bytes::from("OK\r\n")),
+                None => Err(CommandError::Client(
+                    "the cache has no item limit to change".to_string(),
+                )),
+            },
+            Command::Scan(..) if !state.admin => Err(CommandError::Unknown),
+            // a full page may be followed by more, listed by scanning again after its last key
+            Command::Scan(prefix, after) => {
+                let mut buf = BytesMut::new();
+                for key in state.scan(&prefix, after.as_deref())? {
+                    buf.extend_from_slice(format!("KEY {}\r\n", key).as_bytes());
                 }
+                buf.extend_from_slice(b"END\r\n");
+                Ok(buf.freeze())
+            }
+            Command::BadDataChunk => Err(CommandError::BadDataChunk),
+            Command::BadFormat => Err(CommandError::BadFormat),
+            Command::NoReply(command) => {
+                command.handle(state)?;
+                Ok(Bytes::new())
+            }
+            Command::BadSyntax(msg) => Err(CommandError::Client(msg)),
+            Command::Unknown => Err(CommandError::Unknown),
+        }
+    }
+}
+
+impl Command {
+    /// returns the keys the command operates on
+    fn keys(&self) -> &[String] {
+        match self {
+            Command::Set(key, ..)
+            | Command::Add(key, ..)
+            | Command::Replace(key, ..)
+            | Command::Append(key, _)
+            | Command::Prepend(key, _)
+            | Command::Delete(key, _)
+            | Command::Incr(key, _)
+            | Command::Decr(key, _)
+            | Command::Cas(key, ..)
+            | Command::Touch(key, _)
+            | Command::Mg(key, _) => std::slice::from_ref(key),
+            Command::Get(keys)
+            | Command::Gets(keys)
+            | Command::Gat(_, keys)
+            | Command::Gats(_, keys) => keys,
+            Command::Stats
+            | Command::StatsItems
+            | Command::Cachedump(..)
+            | Command::Version
+            | Command::Ping
+            | Command::Flushall(_)
+            | Command::CacheLimit(_)
+            | Command::Scan(..)
+            | Command::BadDataChunk
+            | Command::BadFormat
+            | Command::BadSyntax(_)
+            | Command::Unknown => &[],
+            Command::NoReply(command) => command.keys(),
+        }
+    }
+
+    /// carries out the command like `handle` but returns the reply in chunks to be written out in
+    /// turn
+    ///
+    /// the values read by the `get` family are chunks of their own sharing the cache's memory, so
+    /// a reply for many large values isn't copied into one buffer first
+    pub fn handle_chunked<C: Store<String, Item>>(
+        self,
+        state: Arc<State<C>>,
+    ) -> Result<Vec<Bytes>, CommandError> {
+        if !self.keys().iter().all(|key| state.is_valid_key(key)) {
+            return Err(CommandError::BadFormat);
+        }
+        match self {
+            Command::Get(keys) => Self::values(state, keys, false, None),
+            Command::Gets(keys) => Self::values(state, keys, true, None),
+            Command::Gat(exp_time, keys) => Self::values(state, keys, false, Some(exp_time)),
+            Command::Gats(exp_time, keys) => Self::values(state, keys, true, Some(exp_time)),
+            command => Ok(vec![command.handle(state)?]),
+        }
+    }
+
+    /// formats a `VALUE <key> <flags> <bytes> [<cas>]` block for every key found, followed by
+    /// `END`, each value a chunk of its own
+    ///
+    /// with `touch`, the expiry of each item found is also reset to it
+    fn values<C: Store<String, Item>>(
+        state: Arc<State<C>>,
+        keys: Vec<String>,
+        with_cas: bool,
+        touch: Option<i64>,
+    ) -> Result<Vec<Bytes>, CommandError> {
+        let mut chunks = vec![];
+        for key in keys {
+            let item = match touch {
+                Some(exp_time) => state.touch(key.clone(), exp_time)?,
+                None => state.get(&key)?,
+            };
+            if let Some((item, _)) = state.fetched(item)? {
+                let header = if with_cas {
+                    format!(
+                        "VALUE {} {} {} {}\r\n",
+                        key,
+                        item.flags,
+                        item.data.len(),
+                        item.cas
+                    )
+                } else {
+                    format!("VALUE {} {} {}\r\n", key, item.flags, item.data.len())
+                };
+                chunks.push(Bytes::from(header));
+                chunks.push(item.data);
+                chunks.push(Bytes::from_static(b"\r\n"));
             }
-            Command::Incr(_) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Decr(_) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Cas(_, _) => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Stats => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Version => Ok(Bytes::from(env!("CARGO_PKG_VERSION"))),
-            Command::Flushall => Ok(Bytes::from("NOT IMPLEMENTED")),
-            Command::Invalid => Ok(Bytes::from("NOT IMPLEMENTED")),
         }
+        chunks.push(Bytes::from_static(b"END\r\n"));
+        Ok(chunks)
+    }
+
+    /// formats an `mg` reply with the metadata asked for by `flags`, in the same order
+    ///
+    /// `VA <bytes> <flags>*` followed by the data if the value was asked for with `v`, `HD
+    /// <flags>*` otherwise & `EN` on a miss
+    fn meta_get<C: Store<String, Item>>(
+        state: Arc<State<C>>,
+        key: String,
+        flags: Vec<char>,
+    ) -> Result<Bytes, CommandError> {
+        let Some((item, prev)) = state.lookup(&key)? else {
+            return Ok(Bytes::from("EN\r\n"));
+        };
+        let now = now()?;
+        let mut meta = String::new();
+        for flag in &flags {
+            let value = match flag {
+                // remaining ttl in seconds, -1 if the item never expires
+                't' if item.exp_time == 0 => "-1".to_string(),
+                't' => item.exp_time.saturating_sub(now).div_ceil(1000).to_string(),
+                's' => item.data.len().to_string(),
+                // whether the item was fetched before this request
+                'h' => u8::from(prev.fetched).to_string(),
+                // seconds since the item was last fetched
+                'l' => (now.saturating_sub(prev.at) / 1000).to_string(),
+                'k' => key.clone(),
+                'f' => item.flags.to_string(),
+                'c' => item.cas.to_string(),
+                _ => continue,
+            };
+            meta.push_str(&format!(" {}{}", flag, value));
+        }
+
+        if !flags.contains(&'v') {
+            return Ok(Bytes::from(format!("HD{}\r\n", meta)));
+        }
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("VA {}{}\r\n", item.data.len(), meta).as_bytes());
+        buf.extend_from_slice(&item.data);
+        buf.extend_from_slice(b"\r\n");
+        Ok(buf.freeze())
+    }
+
+    /// adds `data` to the end of the value stored against `key`, or to its start if `prepend`,
+    /// keeping the item's flags & expiry
+    ///
+    /// the read & the store are one step, so concurrent appends all land & a key deleted in
+    /// between isn't brought back
+    fn concat<C: Store<String, Item>>(
+        state: Arc<State<C>>,
+        key: String,
+        data: Bytes,
+        prepend: bool,
+    ) -> Result<Bytes, CommandError> {
+        let reply = state.update(key, |item| {
+            let Some(item) = item else {
+                return Ok((None, "NOT_STORED\r\n"));
+            };
+            if item.data.len() + data.len() > state.max_value_size {
+                return Ok((None, TOO_LARGE));
+            }
+            let (head, tail) = if prepend {
+                (&data, &item.data)
+            } else {
+                (&item.data, &data)
+            };
+            let mut buf = BytesMut::with_capacity(head.len() + tail.len());
+            buf.extend_from_slice(head);
+            buf.extend_from_slice(tail);
+            let item = state.item(item.flags, item.exp_time, buf.freeze())?;
+            state.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+            Ok((Some(item), "STORED\r\n"))
+        })?;
+        Ok(Bytes::from(reply))
+    }
+
+    /// applies `f` to the numeric value stored against `key` & stores the result back
+    ///
+    /// the read & the store are one step, so no other change to the item lands in between
+    fn apply_delta<C: Store<String, Item>>(
+        state: Arc<State<C>>,
+        key: String,
+        f: impl FnOnce(u64) -> u64,
+    ) -> Result<Bytes, CommandError> {
+        let reply = state.update(key, |item| {
+            let Some(item) = item else {
+                return Ok((None, Bytes::from("NOT_FOUND\r\n")));
+            };
+            let Some(n) = str::from_utf8(&item.data).ok().and_then(|s| s.parse().ok()) else {
+                return Ok((
+                    None,
+                    Bytes::from("CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"),
+                ));
+            };
+            let n = f(n).to_string();
+            let item = state.item(item.flags, item.exp_time, Bytes::from(n.clone()))?;
+            state.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+            Ok((Some(item), Bytes::from(n + "\r\n")))
+        })?;
+        Ok(reply)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::commands::LruCache;
-    use crate::commands::{Command, parse_input};
+    use crate::commands::{Command, CommandError, Item, SCAN_PAGE_SIZE, State, now, parse_input};
+    use atlas::cache::{Action, LruCache, Store, UnboundedCache};
+    use atlas::slab::SlabAllocator;
     use bytes::Bytes;
-    use std::sync::Arc;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier, Mutex};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    #[test]
-    fn test_parse_input() {
-        let cmd = parse_input("set abhi 0 200 rust");
-        assert_eq!(
-            cmd,
-            Command::Set("abhi".to_string(), 0, 200, Bytes::from("rust"))
-        );
+    const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format\r\n";
 
-        let cmd = parse_input("get abhi");
-        assert_eq!(cmd, Command::Get("abhi".to_string()));
+    /// extracts the cas token off the first `VALUE` line of a `gets` reply
+    fn cas_of(out: &Bytes) -> u64 {
+        let line = str::from_utf8(out).unwrap().lines().next().unwrap();
+        line.split(' ').nth(4).unwrap().parse().unwrap()
+    }
 
-        let cmd = parse_input("delete abhi");
-        assert_eq!(cmd, Command::Delete("abhi".to_string()));
+    /// runs commands covering every kind of operation against `store`
+    fn run_suite<C: Store<String, Item>>(store: Arc<State<C>>) {
+        let steps = [
+            ("set abhi 0 0 4\r\nrust\r\n", "STORED\r\n"),
+            ("get abhi", "VALUE abhi 0 4\r\nrust\r\nEND\r\n"),
+            ("append abhi 0 0 2\r\nup\r\n", "STORED\r\n"),
+            ("prepend abhi 0 0 1\r\n_\r\n", "STORED\r\n"),
+            ("get abhi", "VALUE abhi 0 7\r\n_rustup\r\nEND\r\n"),
+            ("replace ash 0 0 2\r\ngo\r\n", "NOT_STORED\r\n"),
+            ("set ash 0 0 1\r\n9\r\n", "STORED\r\n"),
+            ("incr ash 3", "12\r\n"),
+            ("decr ash 20", "0\r\n"),
+            ("touch ash 100", "TOUCHED\r\n"),
+            ("touch lilb 100", "NOT_FOUND\r\n"),
+            ("delete ash", "DELETED\r\n"),
+            ("get ash", "END\r\n"),
+            ("flush_all", "OK\r\n"),
+            ("get abhi", "END\r\n"),
+        ];
+        for (input, expected) in steps {
+            let out = parse_input(input).handle(store.clone()).unwrap();
+            assert_eq!(Bytes::from(expected), out, "{:?}", input);
+        }
+        assert!(store.cache.is_empty());
+    }
 
-        let cmd = parse_input("blah abhi");
-        assert_eq!(cmd, Command::Invalid);
+    #[test]
+    fn test_backends() {
+        run_suite(Arc::new(State::new(5)));
+        run_suite(Arc::new(State::unbounded()));
+        run_suite(Arc::new(State::with_cache(LruCache::new(5))));
+        run_suite(Arc::new(State::with_cache(UnboundedCache::new())));
     }
 
     #[test]
-    fn test_storage() {
-        let store = Arc::new(LruCache::new(5));
-        let out = parse_input("set abhi 0 200 rust")
-            .handle(store.clone())
-            .unwrap();
-        assert_eq!(Bytes::from("STORED"), out);
+    fn test_eviction() {
+        let store = Arc::new(State::new(2));
+        for key in ["abhi", "ash", "lilb"] {
+            let out = parse_input(format!("set {} 0 0 4\r\nrust\r\n", key))
+                .handle(store.clone())
+                .unwrap();
+            assert_eq!(Bytes::from("STORED\r\n"), out);
+        }
 
+        // the lru item made room for the newest one
+        assert_eq!(2, store.cache.len());
         let out = parse_input("get abhi").handle(store.clone()).unwrap();
-        assert_eq!(Bytes::from("rust"), out);
-
-        let out = parse_input("set abhi 0 200 c++")
-            .handle(store.clone())
-            .unwrap();
-        assert_eq!(Bytes::from("STORED"), out);
+        assert_eq!(Bytes::from("END\r\n"), out);
+        let out = parse_input("get ash lilb").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from("VALUE ash 0 4\r\nrust\r\nVALUE lilb 0 4\r\nrust\r\nEND\r\n"),
+            out
+        );
+        let out = parse_input("stats").handle(store.clone()).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains("STAT curr_items 2\r\n"));
+        assert!(String::from_utf8_lossy(&out).contains("STAT evictions 1\r\n"));
+    }
 
-        let out = parse_input("get abhi").handle(store.clone()).unwrap();
-        assert_eq!(Bytes::from("c++"), out);
+    #[test]
+    fn test_hits_misses() {
+        for store in [State::new(5), State::unbounded()] {
+            let store = Arc::new(store);
+            parse_input("set abhi 0 0 4\r\nrust\r\n")
+                .handle(store.clone())
+                .unwrap();
+            parse_input("set ash 0 -1 2\r\ngo\r\n")
+                .handle(store.clone())
+                .unwrap();
+            for input in ["get abhi", "gets abhi", "get lilb", "get ash"] {
+                parse_input(input).handle(store.clone()).unwrap();
+            }
+            // the expired item is a miss
+            assert_eq!(2, store.stats.hits());
+            assert_eq!(2, store.stats.misses());
+        }
+    }
 
-        let out = parse_input("set abhi 0 0 python")
+    #[test]
+    fn test_unbounded() {
+        let store = Arc::new(State::unbounded());
+        for i in 0..100 {
+            parse_input(format!("set key{} 0 0 1\r\n{}\r\n", i, i % 10))
+                .handle(store.clone())
+                .unwrap();
+        }
+        assert_eq!(100, store.cache.len());
+        let out = parse_input("get key0").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE key0 0 1\r\n0\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = std::env::temp_dir().join(format!("atlas-test-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+
+        let store = Arc::new(State::new(5).persist(&path).unwrap());
+        for input in [
+            "set abhi 0 0 4\r\nrust\r\n",
+            "flush_all",
+            "set ash 0 0 2\r\ngo\r\n",
+            "set lilb 0 100 3\r\nzig\r\n",
+            "touch lilb 0",
+        ] {
+            parse_input(input).handle(store.clone()).unwrap();
+        }
+        store.flush_log().unwrap();
+        drop(store);
+
+        let store = Arc::new(State::new(5).persist(&path).unwrap());
+        assert_eq!(2, store.cache.len());
+        let out = parse_input("get abhi ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE ash 0 2\r\ngo\r\nEND\r\n"), out);
+        // the touch outlived the restart too
+        assert_eq!(0, store.cache.peek(&"lilb".to_string()).unwrap().exp_time);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persist_flush_racing_sets() {
+        const KEYS: usize = 20;
+        let dir = std::env::temp_dir().join(format!("atlas-test-flush-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+
+        for _ in 0..10 {
+            let store = Arc::new(State::unbounded().persist(&path).unwrap());
+            let setters: Vec<_> = (0..4)
+                .map(|t| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for i in 0..2000 {
+                            let input = format!("set k{} 0 0 4\r\n{:04}\r\n", i % KEYS, t);
+                            parse_input(&input).handle(store.clone()).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            while !setters.iter().all(|setter| setter.is_finished()) {
+                parse_input("flush_all").handle(store.clone()).unwrap();
+            }
+            for setter in setters {
+                setter.join().unwrap();
+            }
+            store.flush_log().unwrap();
+
+            // the log brings back just what could be read before
+            let restored = Arc::new(State::unbounded().persist(&path).unwrap());
+            for i in 0..KEYS {
+                let input = format!("get k{}", i);
+                assert_eq!(
+                    parse_input(&input).handle(store.clone()).unwrap(),
+                    parse_input(&input).handle(restored.clone()).unwrap()
+                );
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let cmd = parse_input("set abhi 0 200 4\r\nrust\r\n");
+        assert_eq!(
+            cmd,
+            Command::Set("abhi".to_string(), 0, 200, 4, Bytes::from("rust"))
+        );
+
+        let cmd = parse_input("get abhi");
+        assert_eq!(cmd, Command::Get(vec!["abhi".to_string()]));
+
+        let cmd = parse_input("delete abhi");
+        assert_eq!(cmd, Command::Delete("abhi".to_string(), 0));
+
+        let cmd = parse_input("delete abhi 10");
+        assert_eq!(cmd, Command::Delete("abhi".to_string(), 10));
+
+        let cmd = parse_input("blah abhi");
+        assert_eq!(cmd, Command::Unknown);
+    }
+
+    #[test]
+    fn test_noreply() {
+        let store = Arc::new(State::new(5));
+        let cmd = parse_input("set abhi 0 0 4 noreply\r\nrust\r\n");
+        assert_eq!(
+            cmd,
+            Command::NoReply(Box::new(Command::Set(
+                "abhi".to_string(),
+                0,
+                0,
+                4,
+                Bytes::from("rust")
+            )))
+        );
+        assert_eq!(Bytes::new(), cmd.handle(store.clone()).unwrap());
+
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+        let out = parse_input(format!("cas abhi 0 0 2 {} noreply\r\ngo\r\n", cas))
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::new(), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 2\r\ngo\r\nEND\r\n"), out);
+
+        let out = parse_input("delete abhi noreply")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::new(), out);
+        assert_eq!(0, store.cache.len());
+
+        // a key named noreply is still just a key for retrieval commands
+        let cmd = parse_input("get noreply");
+        assert_eq!(cmd, Command::Get(vec!["noreply".to_string()]));
+
+        // errors are replied to regardless
+        let err = parse_input("set abhi 0 0 4 noreply\r\nrusty\r\n")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(Bytes::from("CLIENT_ERROR bad data chunk\r\n"), err.reply());
+    }
+
+    #[test]
+    fn test_meta_get() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 5 100 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("mg abhi t s h").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("HD t100 s4 h0\r\n"), out);
+        // fetched by the previous mg
+        let out = parse_input("mg abhi h l").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("HD h1 l0\r\n"), out);
+
+        let out = parse_input("mg abhi s v k f")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("VA 4 s4 kabhi f5\r\nrust\r\n"), out);
+
+        parse_input("set ash 0 0 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("mg ash t").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("HD t-1\r\n"), out);
+        let out = parse_input("mg ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("HD\r\n"), out);
+
+        // storing anew resets the fetch
+        parse_input("set ash 0 0 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("mg ash h").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("HD h0\r\n"), out);
+
+        let out = parse_input("mg lilb t s").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("EN\r\n"), out);
+
+        let err = parse_input("mg abhi x").handle(store.clone()).unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+    }
+
+    #[test]
+    fn test_reply_terminators() {
+        let store = Arc::new(State::new(5));
+        let version = format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"));
+        let steps = [
+            ("set abhi 0 0 2\r\n10\r\n", "STORED\r\n"),
+            ("replace ash 0 0 2\r\ngo\r\n", "NOT_STORED\r\n"),
+            ("append abhi 0 0 1\r\n0\r\n", "STORED\r\n"),
+            ("incr abhi 1", "101\r\n"),
+            ("decr abhi 1", "100\r\n"),
+            ("incr ash 1", "NOT_FOUND\r\n"),
+            ("cas abhi 0 0 1 1\r\n1\r\n", "EXISTS\r\n"),
+            ("cas ash 0 0 1 1\r\n1\r\n", "NOT_FOUND\r\n"),
+            ("touch abhi 100", "TOUCHED\r\n"),
+            ("touch ash 100", "NOT_FOUND\r\n"),
+            ("get ash", "END\r\n"),
+            ("delete abhi", "DELETED\r\n"),
+            ("delete abhi", "NOT_FOUND\r\n"),
+            ("version", &version),
+            ("ping", "PONG\r\n"),
+            ("flush_all", "OK\r\n"),
+        ];
+        for (input, expected) in steps {
+            let out = parse_input(input).handle(store.clone()).unwrap();
+            assert_eq!(Bytes::from(expected.to_string()), out, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_storage() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("set abhi 0 200 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+
+        let out = parse_input("set abhi 0 200 3\r\nc++\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nc++\r\nEND\r\n"), out);
+
+        let out = parse_input("set abhi 0 0 6\r\npython\r\n")
             .handle(store.clone())
             .unwrap();
-        assert_eq!(Bytes::from("STORED"), out);
+        assert_eq!(Bytes::from("STORED\r\n"), out);
         let out = parse_input("get abhi").handle(store.clone()).unwrap();
-        assert_eq!(Bytes::from("python"), out);
+        assert_eq!(Bytes::from("VALUE abhi 0 6\r\npython\r\nEND\r\n"), out);
 
-        let out = parse_input("set abhi 0 200 java")
+        let out = parse_input("set abhi 0 200 4\r\njava\r\n")
             .handle(store.clone())
             .unwrap();
-        assert_eq!(Bytes::from("STORED"), out);
+        assert_eq!(Bytes::from("STORED\r\n"), out);
         thread::sleep(Duration::from_millis(100));
         let out = parse_input("get abhi").handle(store.clone()).unwrap();
-        assert_eq!(Bytes::from("java"), out);
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\njava\r\nEND\r\n"), out);
     }
 
     #[test]
     fn test_expiry() {
-        let store = Arc::new(LruCache::new(4));
-        let out = parse_input("set abhi 0 200 kotlin")
+        let store = Arc::new(State::new(4));
+        let out = parse_input("set abhi 0 1 6\r\nkotlin\r\n")
             .handle(store.clone())
             .unwrap();
-        assert_eq!(Bytes::from("STORED"), out);
-        thread::sleep(Duration::from_millis(300));
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 6\r\nkotlin\r\nEND\r\n"), out);
+        thread::sleep(Duration::from_millis(1100));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+    }
+
+    #[test]
+    fn test_exp_time_regimes() {
+        let store = Arc::new(State::new(5));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let exp_times = [
+            ("never", "0".to_string(), true),
+            ("relative", "100".to_string(), true),
+            ("absolute", (now + 100).to_string(), true),
+            ("past", (now - 100).to_string(), false),
+            ("negative", "-1".to_string(), false),
+        ];
+        for (key, exp_time, stored) in exp_times {
+            parse_input(format!("set {} 0 {} 4\r\nrust\r\n", key, exp_time))
+                .handle(store.clone())
+                .unwrap();
+            let out = parse_input(format!("get {}", key))
+                .handle(store.clone())
+                .unwrap();
+            assert_eq!(stored, out != "END\r\n", "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_touch() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 1 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+
+        // extends the ttl past the original expiry
+        let out = parse_input("touch abhi 100").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("TOUCHED\r\n"), out);
+        thread::sleep(Duration::from_millis(1100));
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        assert_eq!(cas, cas_of(&out));
+
+        let out = parse_input("touch ash 100").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("NOT_FOUND\r\n"), out);
+
+        // too late to touch once expired
+        let out = parse_input("touch abhi -1").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("TOUCHED\r\n"), out);
+        let out = parse_input("touch abhi 100").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("NOT_FOUND\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+    }
+
+    #[test]
+    fn test_gat() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 1 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+
+        let out = parse_input("gat 100 abhi ash")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+        let out = parse_input("gats 100 abhi").handle(store.clone()).unwrap();
+        assert_eq!(cas, cas_of(&out));
+
+        // outlives the original ttl
+        thread::sleep(Duration::from_millis(1100));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_replace() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("replace abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("replace abhi 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+
+        parse_input("set ash 0 -1 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("replace ash 0 0 4\r\nodin\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_STORED\r\n"), out);
+        let out = parse_input("get ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+    }
+
+    #[test]
+    fn test_add() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("add abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("add abhi 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+
+        parse_input("set ash 0 -1 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("add ash 7 0 4\r\nodin\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE ash 7 4\r\nodin\r\nEND\r\n"), out);
+
+        let out = parse_input("add lilb 0 0 3 noreply\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::new(), out);
+        assert_eq!(3, store.cache.len());
+    }
+
+    #[test]
+    fn test_append() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("append abhi 0 0 4\r\nlang\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_STORED\r\n"), out);
+
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("append abhi 0 0 4\r\nlang\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 8\r\nrustlang\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_prepend() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("prepend abhi 0 0 4\r\nlang\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_STORED\r\n"), out);
+
+        parse_input("set abhi 0 1 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("prepend abhi 0 0 4\r\nlang\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 8\r\nlangrust\r\nEND\r\n"), out);
+
+        // the original expiry is kept
+        thread::sleep(Duration::from_millis(1100));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+    }
+
+    #[test]
+    fn test_append_race() {
+        const THREADS: usize = 8;
+        const APPENDS: usize = 200;
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 0\r\n\r\n")
+            .handle(store.clone())
+            .unwrap();
+
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let command = if i % 2 == 0 { "append" } else { "prepend" };
+                let input = format!("{} abhi 0 0 1\r\n{}\r\n", command, i);
+                thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..APPENDS {
+                        let out = parse_input(&input).handle(store.clone()).unwrap();
+                        assert_eq!(Bytes::from("STORED\r\n"), out);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // no chunk was lost
+        let item = store.get(&"abhi".to_string()).unwrap().unwrap();
+        assert_eq!(THREADS * APPENDS, item.data.len());
+        for i in 0..THREADS {
+            let chunk = i.to_string().as_bytes()[0];
+            assert_eq!(APPENDS, item.data.iter().filter(|&&b| b == chunk).count());
+        }
+        assert!(
+            store
+                .stat_values()
+                .contains(&("bytes", THREADS as u64 * APPENDS as u64))
+        );
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("incr abhi 1").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("NOT_FOUND\r\n"), out);
+
+        parse_input("set abhi 0 0 2\r\n10\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("incr abhi 5").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("15\r\n"), out);
+        let out = parse_input("decr abhi 3").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("12\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 2\r\n12\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_incr_wraps() {
+        let store = Arc::new(State::new(5));
+        let max = u64::MAX.to_string();
+        parse_input(format!("set abhi 0 0 {}\r\n{}\r\n", max.len(), max))
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("incr abhi 2").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("1\r\n"), out);
+    }
+
+    #[test]
+    fn test_decr_clamps() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 1\r\n3\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("decr abhi 5").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("0\r\n"), out);
+    }
+
+    #[test]
+    fn test_incr_non_numeric() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("incr abhi 1").handle(store.clone()).unwrap();
+        assert!(out.starts_with(b"CLIENT_ERROR"));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_gets_cas() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("cas abhi 0 0 4 1\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("NOT_FOUND\r\n"), out);
+
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+        assert!(out.ends_with(b"\r\nrust\r\nEND\r\n"));
+
+        let out = parse_input(format!("cas abhi 0 0 3 {}\r\nzig\r\n", cas))
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        // the token changed with the last store
+        let out = parse_input(format!("cas abhi 0 0 4 {}\r\nodin\r\n", cas))
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("EXISTS\r\n"), out);
         let out = parse_input("get abhi").handle(store.clone()).unwrap();
-        assert_eq!(Bytes::from("NOT FOUND"), out);
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_cas_race() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = ["zig", "odin"]
+            .into_iter()
+            .map(|value| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let cmd = format!("cas abhi 0 0 {} {}\r\n{}\r\n", value.len(), cas, value);
+                thread::spawn(move || {
+                    barrier.wait();
+                    parse_input(&cmd).handle(store).unwrap()
+                })
+            })
+            .collect();
+        let mut outs: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        outs.sort();
+        assert_eq!(
+            vec![Bytes::from("EXISTS\r\n"), Bytes::from("STORED\r\n")],
+            outs
+        );
+    }
+
+    #[test]
+    fn test_incr_race() {
+        const THREADS: usize = 8;
+        const INCRS: usize = 1000;
+        let store = Arc::new(State::new(5));
+        parse_input("set counter 0 0 1\r\n0\r\n")
+            .handle(store.clone())
+            .unwrap();
+
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..INCRS {
+                        parse_input("incr counter 1").handle(store.clone()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // no incr was lost
+        let out = parse_input("get counter").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from(format!(
+                "VALUE counter 0 4\r\n{}\r\nEND\r\n",
+                THREADS * INCRS
+            )),
+            out
+        );
+    }
+
+    #[test]
+    fn test_incr_set_race() {
+        const INCR_THREADS: usize = 4;
+        const SETS: usize = 20_000;
+        let store = Arc::new(State::new(5));
+        parse_input("set counter 0 0 1\r\n0\r\n")
+            .handle(store.clone())
+            .unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let incrs: Vec<_> = (0..INCR_THREADS)
+            .map(|_| {
+                let store = store.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        parse_input("incr counter 1").handle(store.clone()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=SETS {
+            // far beyond what the incrs add up to, so a set they overwrote would show
+            let value = (i * 1_000_000_000).to_string();
+            let input = format!("set counter 0 0 {}\r\n{}\r\n", value.len(), value);
+            let out = parse_input(&input).handle(store.clone()).unwrap();
+            assert_eq!(Bytes::from("STORED\r\n"), out);
+            let item = store.get(&"counter".to_string()).unwrap().unwrap();
+            let n: usize = str::from_utf8(&item.data).unwrap().parse().unwrap();
+            // the incrs since the set all apply to the value set, never to one from before it
+            assert_eq!(i, n / 1_000_000_000, "set {} but read {}", value, n);
+        }
+        done.store(true, Ordering::Relaxed);
+        for incr in incrs {
+            incr.join().unwrap();
+        }
+    }
+
+    /// a cache storing `fresh` right after a key is first peeked, like a set landing between the
+    /// reaper's peek & its remove
+    struct RacingStore {
+        cache: LruCache<String, Item>,
+        fresh: Mutex<Option<(String, Item)>>,
+    }
+
+    impl RacingStore {
+        /// stores the fresh item, if any, like a set from another client landing mid-command
+        fn race(&self) {
+            if let Some((k, fresh)) = self.fresh.lock().unwrap().take() {
+                self.cache.insert(k, fresh);
+            }
+        }
+    }
+
+    impl Store<String, Item> for RacingStore {
+        fn insert(&self, k: String, v: Item) -> Vec<(String, Item)> {
+            self.cache.insert(k, v)
+        }
+
+        fn get(&self, k: &String) -> Option<Item> {
+            let item = self.cache.get(k);
+            self.race();
+            item
+        }
+
+        fn peek(&self, k: &String) -> Option<Item> {
+            let item = self.cache.peek(k);
+            self.race();
+            item
+        }
+
+        fn remove(&self, k: &String) -> Option<Item> {
+            self.cache.remove(k)
+        }
+
+        fn keys(&self) -> Vec<String> {
+            self.cache.keys()
+        }
+
+        fn clear(&self) -> Vec<(String, Item)> {
+            self.cache.clear()
+        }
+
+        fn len(&self) -> usize {
+            self.cache.len()
+        }
+
+        fn with_entry(
+            &self,
+            k: String,
+            f: &mut dyn FnMut(Option<&mut Item>) -> Action<Item>,
+        ) -> Vec<(String, Item)> {
+            self.race();
+            self.cache.with_entry(k, f)
+        }
+    }
+
+    #[test]
+    fn test_reap_racing_set() {
+        let store = Arc::new(State::with_cache(RacingStore {
+            cache: LruCache::new(5),
+            fresh: Mutex::new(None),
+        }));
+        parse_input("set abhi 0 -1 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let fresh = store.item(0, 0, Bytes::from("zig")).unwrap();
+        *store.cache.fresh.lock().unwrap() = Some(("abhi".to_string(), fresh));
+
+        // the expired item was peeked but the fresh one is left alone
+        assert_eq!(0, store.reap().unwrap());
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+        assert_eq!(1, store.cache.len());
+    }
+
+    #[test]
+    fn test_cas_racing_set() {
+        let store = Arc::new(State::with_cache(RacingStore {
+            cache: LruCache::new(5),
+            fresh: Mutex::new(None),
+        }));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("gets abhi").handle(store.clone()).unwrap();
+        let cas = cas_of(&out);
+        let fresh = store.item(0, 0, Bytes::from("zig")).unwrap();
+        *store.cache.fresh.lock().unwrap() = Some(("abhi".to_string(), fresh));
+
+        // a set landing on the item after the token was read makes the cas fail
+        let input = format!("cas abhi 0 0 2 {}\r\ngo\r\n", cas);
+        let out = parse_input(&input).handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("EXISTS\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_flush_all() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        parse_input("set ash 0 0 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("flush_all 0").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("OK\r\n"), out);
+        assert_eq!(0, store.cache.len());
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+        let out = parse_input("get ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+
+        // items stored after the flush are unaffected
+        parse_input("set abhi 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_flush_all_racing_sets() {
+        const KEYS: usize = 50;
+        let store = Arc::new(State::unbounded());
+        for _ in 0..10 {
+            let setters: Vec<_> = (0..4)
+                .map(|_| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        for i in 0..4000 {
+                            let input = format!("set k{} 0 0 4\r\nrust\r\n", i % KEYS);
+                            parse_input(&input).handle(store.clone()).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            while !setters.iter().all(|setter| setter.is_finished()) {
+                parse_input("flush_all 0").handle(store.clone()).unwrap();
+            }
+            for setter in setters {
+                setter.join().unwrap();
+            }
+
+            // the items left are still counted, so deleting them brings the counters back to 0
+            for i in 0..KEYS {
+                parse_input(format!("delete k{}", i))
+                    .handle(store.clone())
+                    .unwrap();
+            }
+            assert_eq!(0, store.cache.len());
+            assert!(store.stat_values().contains(&("curr_items", 0)));
+            assert!(store.stat_values().contains(&("bytes", 0)));
+        }
+    }
+
+    #[test]
+    fn test_delayed_flush_all() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("flush_all 200").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("OK\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+
+        thread::sleep(Duration::from_millis(300));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+        parse_input("set abhi 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 3\r\nzig\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_delete_time() {
+        let store = Arc::new(State::new(5));
+        let set = |input: &str| parse_input(input).handle(store.clone()).unwrap();
+        set("set abhi 0 0 4\r\nrust\r\n");
+        assert_eq!(Bytes::from("DELETED\r\n"), set("delete abhi"));
+        // without a time the key can be stored again right away
+        assert_eq!(Bytes::from("STORED\r\n"), set("set abhi 0 0 3\r\nzig\r\n"));
+        assert_eq!(Bytes::from("DELETED\r\n"), set("delete abhi 0"));
+        assert_eq!(Bytes::from("STORED\r\n"), set("set abhi 0 0 3\r\nzig\r\n"));
+
+        assert_eq!(Bytes::from("DELETED\r\n"), set("delete abhi 1"));
+        assert_eq!(Bytes::from("END\r\n"), set("get abhi"));
+        for input in [
+            "set abhi 0 0 4\r\nrust\r\n",
+            "replace abhi 0 0 4\r\nrust\r\n",
+            "append abhi 0 0 1\r\n!\r\n",
+            "cas abhi 0 0 4 1\r\nrust\r\n",
+        ] {
+            assert_eq!(Bytes::from("NOT_STORED\r\n"), set(input), "{:?}", input);
+        }
+        // other keys are unaffected, as are keys not found when deleted
+        assert_eq!(Bytes::from("NOT_FOUND\r\n"), set("delete ash 1"));
+        assert_eq!(Bytes::from("STORED\r\n"), set("set ash 0 0 2\r\ngo\r\n"));
+
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(Bytes::from("STORED\r\n"), set("set abhi 0 0 3\r\nzig\r\n"));
+        assert!(store.blocked.lock().unwrap().is_empty());
+
+        let err = parse_input("delete abhi soon")
+            .handle(store.clone())
+            .unwrap_err();
+        assert!(matches!(err, CommandError::BadFormat), "{:?}", err);
+    }
+
+    #[test]
+    fn test_stats() {
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        parse_input("set ash 0 0 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        parse_input("set abhi 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        parse_input("get abhi").handle(store.clone()).unwrap();
+        parse_input("get ash").handle(store.clone()).unwrap();
+        parse_input("get lilb").handle(store.clone()).unwrap();
+
+        let out = parse_input("stats").handle(store.clone()).unwrap();
+        let stats: HashMap<_, _> = str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("STAT "))
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, value)| (name.to_string(), value.parse::<usize>().unwrap()))
+            .collect();
+        assert!(out.ends_with(b"END\r\n"));
+        assert_eq!(stats["get_hits"], 2);
+        assert_eq!(stats["get_misses"], 1);
+        assert_eq!(stats["cmd_set"], 3);
+        assert_eq!(stats["curr_items"], 2);
+        assert_eq!(stats["bytes"], 5);
+        assert_eq!(stats["curr_connections"], 0);
+        assert_eq!(stats["total_connections"], 0);
+        assert_eq!(stats["evictions"], 0);
+    }
+
+    #[test]
+    fn test_stats_concurrent_sets() {
+        const THREADS: usize = 8;
+        let store = Arc::new(State::new(5));
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        // every thread sets the key afresh at once
+                        barrier.wait();
+                        parse_input("set abhi 0 0 4\r\nrust\r\n")
+                            .handle(store.clone())
+                            .unwrap();
+                        barrier.wait();
+                        if barrier.wait().is_leader() {
+                            parse_input("delete abhi").handle(store.clone()).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // each first set counted the item once
+        assert!(store.stat_values().contains(&("curr_items", 0)));
+        assert!(store.stat_values().contains(&("bytes", 0)));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert!(store.stat_values().contains(&("curr_items", 1)));
+        assert!(store.stat_values().contains(&("bytes", 4)));
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let store = Arc::new(State::with_cache(LruCache::with_max_bytes(10)));
+        for (key, value) in [("abhi", "rust"), ("ash", "go"), ("lilb", "java")] {
+            let out = parse_input(format!("set {} 0 0 {}\r\n{}\r\n", key, value.len(), value))
+                .handle(store.clone())
+                .unwrap();
+            assert_eq!(Bytes::from("STORED\r\n"), out);
+        }
+
+        // 10 bytes are stored; 3 more evict the lru item only
+        parse_input("set pads 0 0 3\r\nzig\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+        let out = parse_input("get ash").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE ash 0 2\r\ngo\r\nEND\r\n"), out);
+        assert_eq!(9, store.cache.bytes());
+    }
+
+    #[test]
+    fn test_max_key_size() {
+        let store = Arc::new(State::new(5).max_key_size(4));
+        let out = parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+
+        let err = parse_input("set abhij 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+        let err = parse_input("get abhi abhij")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+    }
+
+    #[test]
+    fn test_max_value_size() {
+        let store = Arc::new(State::new(5).max_value_size(4));
+        let out = parse_input("set abhi 0 0 5\r\nrusty\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(
+            Bytes::from("SERVER_ERROR object too large for cache\r\n"),
+            out
+        );
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+
+        // appending must not grow the value past the limit either
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("append abhi 0 0 1\r\ny\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(
+            Bytes::from("SERVER_ERROR object too large for cache\r\n"),
+            out
+        );
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"), out);
+    }
+
+    #[test]
+    fn test_slabs() {
+        let slabs = SlabAllocator::default();
+        let store = Arc::new(State::new(2).slabs(slabs.clone()));
+        for i in 0..10 {
+            let out = parse_input(format!("set key{} 0 0 4\r\nval{}\r\n", i, i))
+                .handle(store.clone())
+                .unwrap();
+            assert_eq!(Bytes::from("STORED\r\n"), out);
+        }
+        let out = parse_input("get key9").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE key9 0 4\r\nval9\r\nEND\r\n"), out);
+        // the chunks of evicted values are handed to the values set after them
+        let stats = slabs.stats();
+        assert_eq!(10, stats.allocated + stats.reused);
+        assert!(stats.reused >= 7, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_cache_limit() {
+        let store = Arc::new(State::new(10));
+        for i in 0..10 {
+            parse_input(format!("set key{} 0 0 4\r\nrust\r\n", i))
+                .handle(store.clone())
+                .unwrap();
+        }
+        parse_input("get key0").handle(store.clone()).unwrap();
+
+        let out = parse_input("cache_limit 4").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("OK\r\n"), out);
+        // the least recently used items are evicted right away & the stats follow
+        let mut keys = store.cache.keys();
+        keys.sort();
+        assert_eq!(vec!["key0", "key7", "key8", "key9"], keys);
+        let stats = store.stat_values();
+        assert!(stats.contains(&("curr_items", 4)));
+        assert!(stats.contains(&("bytes", 16)));
+        assert!(stats.contains(&("evictions", 6)));
+
+        let err = parse_input("cache_limit four")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+        let err = parse_input("cache_limit 4")
+            .handle(Arc::new(State::unbounded()))
+            .unwrap_err();
+        assert_eq!(
+            Bytes::from("CLIENT_ERROR the cache has no item limit to change\r\n"),
+            err.reply()
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let store = Arc::new(State::new(200).admin(true));
+        let run = |input: String| parse_input(input).handle(store.clone());
+        for key in ["user:2", "user:1", "users", "ash", "user:3"] {
+            run(format!("set {} 0 0 4\r\nrust\r\n", key)).unwrap();
+        }
+        // already expired
+        run("set user:0 0 -1 4\r\nrust\r\n".to_string()).unwrap();
+        assert_eq!(
+            Bytes::from("KEY user:1\r\nKEY user:2\r\nKEY user:3\r\nEND\r\n"),
+            run("scan user:".to_string()).unwrap()
+        );
+        assert_eq!(
+            Bytes::from("KEY user:3\r\nKEY users\r\nEND\r\n"),
+            run("scan user user:2".to_string()).unwrap()
+        );
+        assert_eq!(
+            Bytes::from("END\r\n"),
+            run("scan lilb".to_string()).unwrap()
+        );
+
+        // listed a page at a time
+        for i in 0..150 {
+            run(format!("set page:{:03} 0 0 4\r\nrust\r\n", i)).unwrap();
+        }
+        let page = run("scan page:".to_string()).unwrap();
+        let page = String::from_utf8_lossy(&page);
+        assert_eq!(SCAN_PAGE_SIZE, page.matches("KEY").count());
+        assert!(page.ends_with("KEY page:099\r\nEND\r\n"), "{}", page);
+        let page = run("scan page: page:099".to_string()).unwrap();
+        let page = String::from_utf8_lossy(&page);
+        assert_eq!(50, page.matches("KEY").count());
+        assert!(page.starts_with("KEY page:100\r\n"), "{}", page);
+
+        // not answered unless admin commands are enabled
+        let err = parse_input("scan user:")
+            .handle(Arc::new(State::new(5)))
+            .unwrap_err();
+        assert_eq!(Bytes::from("ERROR\r\n"), err.reply());
+    }
+
+    #[test]
+    fn test_handle_chunked() {
+        let store = Arc::new(State::new(20));
+        let keys: Vec<_> = (0..10).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            let input = format!("set {} 0 0 100000\r\n{}\r\n", key, "v".repeat(100000));
+            parse_input(input).handle(store.clone()).unwrap();
+        }
+        let input = format!("get {} missing", keys.join(" "));
+        let chunks = parse_input(&input).handle_chunked(store.clone()).unwrap();
+        assert_eq!(
+            parse_input(&input).handle(store.clone()).unwrap(),
+            chunks.concat()
+        );
+
+        // the values aren't copied, only the few bytes framing them are
+        let values: Vec<_> = keys
+            .iter()
+            .map(|key| store.cache.peek(key).unwrap().data)
+            .collect();
+        let mut framing = 0;
+        for chunk in &chunks {
+            if !values.iter().any(|value| value.as_ptr() == chunk.as_ptr()) {
+                framing += chunk.len();
+            }
+        }
+        assert!(framing < 500, "{} bytes copied", framing);
+
+        // other commands reply in a single chunk
+        assert_eq!(
+            vec![Bytes::from("DELETED\r\n")],
+            parse_input("delete key0").handle_chunked(store).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cachedump() {
+        let store = Arc::new(State::new(200).admin(true));
+        let run = |input: String| parse_input(input).handle(store.clone()).unwrap();
+        run("set abhi 0 0 4\r\nrust\r\n".to_string());
+        run("set ash 0 100 2\r\ngo\r\n".to_string());
+        run(format!("set lilb 0 200 100\r\n{}\r\n", "j".repeat(100)));
+        // already expired
+        run("set zig 0 -1 3\r\nzig\r\n".to_string());
+
+        // the 64 byte chunks' class is the first & the 100 byte ones' the third
+        assert_eq!(
+            Bytes::from(
+                "STAT items:1:number 2\r\nSTAT items:1:bytes 6\r\n\
+                 STAT items:3:number 1\r\nSTAT items:3:bytes 100\r\nEND\r\n"
+            ),
+            run("stats items".to_string())
+        );
+        assert_eq!(
+            Bytes::from("ITEM abhi [4 b; 0 s]\r\nITEM ash [2 b; 100 s]\r\nEND\r\n"),
+            run("stats cachedump 1 0".to_string())
+        );
+        assert_eq!(
+            Bytes::from("ITEM abhi [4 b; 0 s]\r\nEND\r\n"),
+            run("stats cachedump 1 1".to_string())
+        );
+        assert_eq!(
+            Bytes::from("ITEM lilb [100 b; 200 s]\r\nEND\r\n"),
+            run("stats cachedump 3 0".to_string())
+        );
+        assert_eq!(
+            Bytes::from("END\r\n"),
+            run("stats cachedump 2 0".to_string())
+        );
+
+        // the keys are only dumped if admin commands are enabled
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(
+            Bytes::from("STAT items:1:number 1\r\nSTAT items:1:bytes 4\r\nEND\r\n"),
+            parse_input("stats items").handle(store.clone()).unwrap()
+        );
+        let err = parse_input("stats cachedump 1 0")
+            .handle(store)
+            .unwrap_err();
+        assert_eq!(Bytes::from("ERROR\r\n"), err.reply());
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let store = Arc::new(State::new(5).default_ttl(Duration::from_millis(200)));
+        let run = |input: &str| parse_input(input).handle(store.clone()).unwrap();
+        run("set abhi 0 0 4\r\nrust\r\n");
+        run("set ash 0 100 2\r\ngo\r\n");
+        assert_eq!(
+            Bytes::from("VALUE abhi 0 4\r\nrust\r\nEND\r\n"),
+            run("get abhi")
+        );
+        // touching with an exptime of 0 takes the default too
+        run("set lilb 0 100 3\r\nzig\r\n");
+        assert_eq!(Bytes::from("TOUCHED\r\n"), run("touch lilb 0"));
+        run("set pads 0 100 2\r\nc#\r\n");
+        assert_eq!(
+            Bytes::from("VALUE pads 0 2\r\nc#\r\nEND\r\n"),
+            run("gat 0 pads")
+        );
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(Bytes::from("END\r\n"), run("get abhi"));
+        assert_eq!(Bytes::from("END\r\n"), run("get lilb pads"));
+        // an exptime given is kept
+        assert_eq!(
+            Bytes::from("VALUE ash 0 2\r\ngo\r\nEND\r\n"),
+            run("get ash")
+        );
+
+        // without a default, an exptime of 0 still never expires
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(0, store.cache.peek(&"abhi".to_string()).unwrap().exp_time);
+    }
+
+    #[test]
+    fn test_ttl_jitter() {
+        /// sets 200 keys with a ttl of 100s, returning how far from now each one expires in millis
+        fn set_many(store: &Arc<State>) -> Vec<u128> {
+            let start = now().unwrap();
+            for i in 0..200 {
+                parse_input(format!("set key{} 0 100 4\r\nrust\r\n", i))
+                    .handle(store.clone())
+                    .unwrap();
+            }
+            (0..200)
+                .map(|i| store.cache.peek(&format!("key{}", i)).unwrap().exp_time - start)
+                .collect()
+        }
+
+        // within the last 20% of the ttl & never past it
+        let ttls = set_many(&Arc::new(State::new(200).ttl_jitter(20, false)));
+        assert!(
+            ttls.iter().all(|ttl| (80_000..=100_100).contains(ttl)),
+            "{:?}",
+            ttls
+        );
+        let (min, max) = (ttls.iter().min().unwrap(), ttls.iter().max().unwrap());
+        assert!(max - min > 10_000, "spread over {}..{}", min, max);
+
+        // either side of the ttl
+        let ttls = set_many(&Arc::new(State::new(200).ttl_jitter(20, true)));
+        assert!(
+            ttls.iter().all(|ttl| (80_000..=120_100).contains(ttl)),
+            "{:?}",
+            ttls
+        );
+        assert!(ttls.iter().any(|&ttl| ttl > 110_000));
+        assert!(ttls.iter().any(|&ttl| ttl < 90_000));
+
+        // none by default, nor for items that never expire
+        let ttls = set_many(&Arc::new(State::new(200)));
+        assert!(
+            ttls.iter().all(|ttl| (100_000..=100_100).contains(ttl)),
+            "{:?}",
+            ttls
+        );
+        let store = Arc::new(State::new(5).ttl_jitter(50, true));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(0, store.cache.peek(&"abhi".to_string()).unwrap().exp_time);
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let store = Arc::new(State::new(5));
+        let err = Command::Set("ab hi".to_string(), 0, 0, 4, Bytes::from("rust"))
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+
+        let err = parse_input("set ab\thi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(BAD_FORMAT, err.reply());
+        assert_eq!(0, store.cache.len());
+    }
+
+    #[test]
+    fn test_data_block() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("set abhi 0 0 12\r\nrust\r\nis fun\r\n")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from("VALUE abhi 0 12\r\nrust\r\nis fun\r\nEND\r\n"),
+            out
+        );
+
+        let err = parse_input("set abhi 0 0 3\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(Bytes::from("CLIENT_ERROR bad data chunk\r\n"), err.reply());
+        let err = parse_input("set abhi 0 0 4\r\nrust")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(Bytes::from("CLIENT_ERROR bad data chunk\r\n"), err.reply());
+    }
+
+    #[test]
+    fn test_bad_format() {
+        let store = Arc::new(State::new(5));
+        for input in [
+            "set abhi x 0 4\r\nrust\r\n",
+            "set abhi 0 y 4\r\nrust\r\n",
+            "set abhi 0 0 z\r\nrust\r\n",
+            "cas abhi 0 0 4 w\r\nrust\r\n",
+            "incr abhi one",
+            "touch abhi soon",
+            "gat soon abhi",
+            "flush_all later",
+        ] {
+            assert_eq!(Command::BadFormat, parse_input(input), "{:?}", input);
+            let err = parse_input(input).handle(store.clone()).unwrap_err();
+            assert_eq!(BAD_FORMAT, err.reply());
+        }
+        assert_eq!(0, store.cache.len());
+    }
+
+    #[test]
+    fn test_command_error() {
+        let store = Arc::new(State::new(5));
+        for input in [
+            "set abhi 0 0",
+            "frobnicate abhi",
+            "foo bar",
+            "",
+            "add abhi 0 0",
+        ] {
+            let err = parse_input(input).handle(store.clone()).unwrap_err();
+            assert_eq!(Bytes::from("ERROR\r\n"), err.reply());
+        }
+
+        let err = parse_input(b"get ab\xffhi\r\n")
+            .handle(store.clone())
+            .unwrap_err();
+        assert_eq!(
+            Bytes::from("CLIENT_ERROR command line isn't valid utf-8\r\n"),
+            err.reply()
+        );
+
+        let err = CommandError::from(anyhow::anyhow!("out of memory"));
+        assert_eq!(Bytes::from("SERVER_ERROR out of memory\r\n"), err.reply());
+    }
+
+    #[test]
+    fn test_get_framing() {
+        let store = Arc::new(State::new(5));
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("END\r\n"), out);
+
+        parse_input("set abhi 42 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("VALUE abhi 42 4\r\nrust\r\nEND\r\n"), out);
+
+        parse_input("set ash 7 0 2\r\ngo\r\n")
+            .handle(store.clone())
+            .unwrap();
+        let out = parse_input("get abhi ash").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from("VALUE abhi 42 4\r\nrust\r\nVALUE ash 7 2\r\ngo\r\nEND\r\n"),
+            out
+        );
+
+        let out = parse_input("gets ash").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from(format!("VALUE ash 7 2 {}\r\ngo\r\nEND\r\n", cas_of(&out))),
+            out
+        );
+    }
+
+    #[test]
+    fn test_flags_kept() {
+        let store = Arc::new(State::new(5));
+        let run = |input: &str| parse_input(input).handle(store.clone()).unwrap();
+        run("set abhi 42 0 1\r\n1\r\n");
+        // commands rewriting the item keep its flags
+        for input in [
+            "incr abhi 9",
+            "decr abhi 1",
+            "append abhi 0 0 1\r\n0\r\n",
+            "prepend abhi 0 0 1\r\n1\r\n",
+            "touch abhi 100",
+        ] {
+            run(input);
+            assert!(
+                run("get abhi").starts_with(b"VALUE abhi 42 "),
+                "{:?}",
+                input
+            );
+        }
+        assert_eq!(
+            Bytes::from("VALUE abhi 42 3\r\n190\r\nEND\r\n"),
+            run("gat 100 abhi")
+        );
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let cmd = parse_input("get abhi ash lilb");
+        assert_eq!(
+            cmd,
+            Command::Get(vec![
+                "abhi".to_string(),
+                "ash".to_string(),
+                "lilb".to_string()
+            ])
+        );
+
+        let store = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(store.clone())
+            .unwrap();
+        parse_input("set lilb 0 0 4\r\n.net\r\n")
+            .handle(store.clone())
+            .unwrap();
+        // missing keys are left out of the reply
+        let out = parse_input("get pads abhi ash lilb")
+            .handle(store.clone())
+            .unwrap();
+        assert_eq!(
+            Bytes::from("VALUE abhi 0 4\r\nrust\r\nVALUE lilb 0 4\r\n.net\r\nEND\r\n"),
+            out
+        );
     }
 }
@@ -0,0 +1,9 @@
+pub mod commands;
+pub mod metrics;
+pub mod persist;
+pub mod protocol;
+pub mod reader;
+mod server;
+pub mod udp;
+
+pub use server::{Backend, Eviction, OverLimit, Server, handle, runtime};
@@ -1,16 +1,11 @@
-mod commands;
-
-use crate::commands::parse_input;
-use bytes::Bytes;
 use clap::Parser;
-use core::cache::LruCache;
-use log::info;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use log::{error, info};
+use server::{Backend, Eviction, OverLimit, Server, runtime};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// addresses to listen on, separated by commas, e.g. `127.0.0.1,::1`
     #[arg(long, default_value = "127.0.0.1")]
     addr: String,
 
@@ -19,49 +14,202 @@ struct Args {
 
     #[arg(long, default_value = "100")]
     cache_limit: usize,
+
+    /// threads serving requests; defaults to the number of cpus
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    worker_threads: Option<u64>,
+
+    /// where items are stored
+    #[arg(long, value_enum, default_value = "lru")]
+    backend: Backend,
+
+    /// what the cache evicts once full
+    #[arg(long, value_enum, default_value = "lru")]
+    eviction: Eviction,
+
+    /// splits an lru cache into this many segments evicting independently, to cut contention
+    #[arg(long, default_value = "1")]
+    shards: usize,
+
+    /// bounds the cache by the total size of the values instead of `cache_limit`
+    #[arg(long)]
+    max_bytes: Option<usize>,
+
+    /// seconds between sweeps removing expired items
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    reap_interval: u64,
+
+    /// logs every change to this file & restores the cache from it on startup
+    #[arg(long)]
+    persist: Option<std::path::PathBuf>,
+
+    /// seconds between writes of the changes logged with `persist`
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    persist_interval: u64,
+
+    /// saves all the items to this file periodically & on SIGUSR1, restoring them on startup
+    #[arg(long)]
+    snapshot_path: Option<std::path::PathBuf>,
+
+    /// seconds between snapshots saved to `snapshot_path`
+    #[arg(long, default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
+
+    /// longest key accepted, in bytes
+    #[arg(long, default_value = "250")]
+    max_key_size: usize,
+
+    /// largest value stored, in bytes
+    #[arg(long, default_value = "1048576")]
+    max_value_size: usize,
+
+    /// also serves requests over udp on this port
+    #[arg(long)]
+    udp_port: Option<u16>,
+
+    /// serves prometheus metrics over http on this port
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// maximum number of connections served at once
+    #[arg(long, default_value = "1024")]
+    max_connections: usize,
+
+    /// what to do with connections beyond `max_connections`
+    #[arg(long, value_enum, default_value = "wait")]
+    over_limit: OverLimit,
+
+    /// seconds after which a connection that hasn't sent a command is closed
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// leaves nagle's algorithm on for accepted connections instead of setting `TCP_NODELAY`
+    #[arg(long)]
+    no_nodelay: bool,
+
+    /// keeps values in slabs of size classes reused on eviction
+    #[arg(long)]
+    slabs: bool,
+
+    /// answers admin commands listing keys, `scan <prefix>` & `stats cachedump <bucket> <limit>`
+    #[arg(long)]
+    enable_admin: bool,
+
+    /// takes up to this many percent off each item's ttl at random so items stored together don't
+    /// all expire at once
+    #[arg(long, default_value = "0")]
+    ttl_jitter: u32,
+
+    /// lets the ttl jitter add to ttls as well as take off them
+    #[arg(long)]
+    ttl_jitter_extends: bool,
+
+    /// seconds after which items stored with an exptime of 0 expire; they never do without it
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    default_ttl: Option<u64>,
+
+    /// millis after which a command is logged as slow at warn level
+    #[arg(long)]
+    slow_log_threshold: Option<u64>,
+
+    /// seconds open connections get to finish when shutting down
+    #[arg(long, default_value = "10")]
+    shutdown_grace: u64,
+
+    /// also serves clients on a unix domain socket at this path
+    #[cfg(unix)]
+    #[arg(long)]
+    unix_socket: Option<std::path::PathBuf>,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
 
     info!("memcached-rust v{}", env!("CARGO_PKG_VERSION"));
 
-    let listener = TcpListener::bind((args.addr.clone(), args.port)).await?;
-    info!(
-        "{}",
-        format!("server listening on {}:{}", args.addr, args.port)
-    );
-
-    let map: Arc<LruCache<String, (u128, Bytes)>> = Arc::new(LruCache::new(args.cache_limit));
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let map = map.clone();
+    let worker_threads = match args.worker_threads {
+        Some(worker_threads) => worker_threads as usize,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    runtime(worker_threads)?.block_on(serve(args))
+}
 
-        tokio::spawn(async move { handle(stream, map).await });
+/// runs the server configured by `args` until asked to shut down
+async fn serve(args: Args) -> anyhow::Result<()> {
+    let mut server = Server::new(&args.addr, args.port, args.cache_limit)
+        .backend(args.backend)
+        .eviction(args.eviction)
+        .shards(args.shards)
+        .max_key_size(args.max_key_size)
+        .max_value_size(args.max_value_size)
+        .reap_interval(Duration::from_secs(args.reap_interval))
+        .max_connections(args.max_connections, args.over_limit)
+        .nodelay(!args.no_nodelay)
+        .slabs(args.slabs)
+        .admin(args.enable_admin)
+        .ttl_jitter(args.ttl_jitter, args.ttl_jitter_extends)
+        .shutdown_grace(Duration::from_secs(args.shutdown_grace));
+    if let Some(max_bytes) = args.max_bytes {
+        server = server.max_bytes(max_bytes);
+    }
+    if let Some(persist) = args.persist {
+        server = server.persist(persist, Duration::from_secs(args.persist_interval));
+    }
+    if let Some(snapshot_path) = args.snapshot_path {
+        server = server.snapshot(snapshot_path, Duration::from_secs(args.snapshot_interval));
+    }
+    if let Some(udp_port) = args.udp_port {
+        server = server.udp_port(udp_port);
+    }
+    if let Some(metrics_port) = args.metrics_port {
+        server = server.metrics_port(metrics_port);
+    }
+    if let Some(idle_timeout) = args.idle_timeout {
+        server = server.idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    if let Some(default_ttl) = args.default_ttl {
+        server = server.default_ttl(Duration::from_secs(default_ttl));
+    }
+    if let Some(threshold) = args.slow_log_threshold {
+        server = server.slow_log_threshold(Duration::from_millis(threshold));
+    }
+    #[cfg(unix)]
+    if let Some(unix_socket) = args.unix_socket {
+        server = server.unix_socket(unix_socket);
     }
 
-    Ok(())
+    server.run_until(shutdown_signal()).await
 }
 
-async fn handle(
-    mut stream: TcpStream,
-    map: Arc<LruCache<String, (u128, Bytes)>>,
-) -> anyhow::Result<()> {
-    let mut buf = vec![0; 1024];
+/// completes on ctrl-c or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("couldn't listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
 
-    loop {
-        let bytes_read = stream.read(&mut buf).await?;
-        if bytes_read == 0 {
-            return Ok(());
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("couldn't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
-        println!("bytes read: {}, {:?}", bytes_read, &buf[..bytes_read]);
-        let result = parse_input(str::from_utf8(&buf[..bytes_read])?).handle(map.clone())?;
-        stream.write(&result).await?;
-        stream.flush().await?;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        buf.fill(0);
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
@@ -0,0 +1,147 @@
+use crate::commands::State;
+use anyhow::bail;
+use bytes::BytesMut;
+use log::debug;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// largest request head read before giving up on a client
+const MAX_HEAD: usize = 8 * 1024;
+
+/// stats that go down as well as up; the rest are exported as counters
+const GAUGES: &[&str] = &["curr_items", "bytes", "curr_connections", "max_connections"];
+
+/// serves the stats of `map` at `/metrics` over http on `listener`, in the prometheus text format
+///
+/// every connection answers a single request & is then closed
+pub async fn serve(listener: TcpListener, map: Arc<State>) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let map = map.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &map).await {
+                debug!("metrics request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// reads a request off `stream` & replies to it
+async fn respond(mut stream: TcpStream, map: &State) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    // only the request line matters but the whole head is read before replying
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD {
+            bail!("request head too large");
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed mid-request");
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let line: Vec<&str> = head.lines().next().unwrap_or("").split(' ').collect();
+    let (status, body) = match line[..] {
+        ["GET", target, _] if target.split('?').next() == Some("/metrics") => {
+            ("200 OK", render(map))
+        }
+        ["GET", _, _] => ("404 Not Found", "not found\n".to_string()),
+        _ => ("400 Bad Request", "bad request\n".to_string()),
+    };
+    let reply = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// renders the stats of `map` in the prometheus text exposition format
+fn render(map: &State) -> String {
+    let mut out = String::new();
+    for (name, value) in map.stat_values() {
+        let (kind, metric) = if GAUGES.contains(&name) {
+            ("gauge", format!("atlas_{}", name))
+        } else {
+            ("counter", format!("atlas_{}_total", name))
+        };
+        // writing to a string can't fail
+        let _ = writeln!(out, "# TYPE {} {}\n{} {}", metric, kind, metric, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{State, parse_input};
+    use crate::metrics::serve;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// sends `request` to the metrics server at `port`, returning the whole reply
+    async fn request(port: u16, request: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let map = Arc::new(State::new(5));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, map.clone()));
+
+        for input in ["set abhi 0 0 4\r\nrust\r\n", "get abhi", "get ash"] {
+            parse_input(input).handle(map.clone()).unwrap();
+        }
+
+        let reply = request(port, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+
+        let mut types = HashMap::new();
+        let mut values = HashMap::new();
+        for line in body.lines() {
+            match line.strip_prefix("# TYPE ") {
+                Some(line) => {
+                    let (name, kind) = line.split_once(' ').unwrap();
+                    types.insert(name, kind);
+                }
+                None => {
+                    let (name, value) = line.split_once(' ').unwrap();
+                    values.insert(name, value.parse::<u64>().unwrap());
+                }
+            }
+        }
+        assert_eq!(types.len(), values.len());
+        assert_eq!("counter", types["atlas_get_hits_total"]);
+        assert_eq!("gauge", types["atlas_curr_items"]);
+        assert_eq!(1, values["atlas_get_hits_total"]);
+        assert_eq!(1, values["atlas_get_misses_total"]);
+        assert_eq!(1, values["atlas_cmd_set_total"]);
+        assert_eq!(1, values["atlas_curr_items"]);
+        assert_eq!(4, values["atlas_bytes"]);
+        assert_eq!(0, values["atlas_curr_connections"]);
+        assert_eq!("counter", types["atlas_total_connections_total"]);
+        assert_eq!("gauge", types["atlas_max_connections"]);
+        assert_eq!("counter", types["atlas_evictions_total"]);
+        assert_eq!(0, values["atlas_evictions_total"]);
+
+        let reply = request(port, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(reply.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let reply = request(port, "stats\r\n\r\n").await;
+        assert!(reply.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
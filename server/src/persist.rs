@@ -0,0 +1,245 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SET: u8 = 1;
+const DELETE: u8 = 2;
+const CLEAR: u8 = 3;
+
+/// A change to the cache, as written to the log.
+///
+/// Every record starts with a 1 byte tag followed by, all big endian:
+/// - set: a 4 byte key length, the key, 4 byte flags, an 8 byte absolute expiry time in millis, a
+///   4 byte data length & the data
+/// - delete: a 4 byte key length & the key
+/// - clear: nothing
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    Set {
+        key: String,
+        flags: u32,
+        exp_time: u128,
+        data: Bytes,
+    },
+    Delete(String),
+    Clear,
+}
+
+impl Record {
+    /// appends the record to `buf`
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Record::Set {
+                key,
+                flags,
+                exp_time,
+                data,
+            } => {
+                buf.put_u8(SET);
+                buf.put_u32(key.len() as u32);
+                buf.put_slice(key.as_bytes());
+                buf.put_u32(*flags);
+                buf.put_u64(*exp_time as u64);
+                buf.put_u32(data.len() as u32);
+                buf.put_slice(data);
+            }
+            Record::Delete(key) => {
+                buf.put_u8(DELETE);
+                buf.put_u32(key.len() as u32);
+                buf.put_slice(key.as_bytes());
+            }
+            Record::Clear => buf.put_u8(CLEAR),
+        }
+    }
+
+    /// splits the first record off `buf`, `None` if it is incomplete or corrupt
+    pub fn decode(buf: &mut &[u8]) -> Option<Record> {
+        let tag = take(buf, 1)?[0];
+        match tag {
+            SET => {
+                let key = take_key(buf)?;
+                let flags = take(buf, 4)?.get_u32();
+                let exp_time = take(buf, 8)?.get_u64() as u128;
+                let len = take(buf, 4)?.get_u32() as usize;
+                let data = Bytes::copy_from_slice(take(buf, len)?);
+                Some(Record::Set {
+                    key,
+                    flags,
+                    exp_time,
+                    data,
+                })
+            }
+            DELETE => Some(Record::Delete(take_key(buf)?)),
+            CLEAR => Some(Record::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// splits `n` bytes off `buf` if there are as many
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Some(head)
+}
+
+/// splits a length prefixed key off `buf`
+fn take_key(buf: &mut &[u8]) -> Option<String> {
+    let len = take(buf, 4)?.get_u32() as usize;
+    String::from_utf8(take(buf, len)?.to_vec()).ok()
+}
+
+/// reads all the records in the log at `path`; a missing log has none
+///
+/// a record cut short by a crash mid-write ends the log
+pub fn read(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = &contents[..];
+    let mut records = vec![];
+    while !buf.is_empty() {
+        match Record::decode(&mut buf) {
+            Some(record) => records.push(record),
+            None => {
+                warn!(
+                    "ignoring {} bytes at the end of {}",
+                    buf.len(),
+                    path.display()
+                );
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// replaces the file at `path` with one holding just `records`
+///
+/// the records are written aside first so a crash midway leaves the old file intact
+pub fn write(path: &Path, records: impl IntoIterator<Item = Record>) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    for record in records {
+        record.encode(&mut buf);
+    }
+
+    let mut tmp = PathBuf::from(path);
+    tmp.as_mut_os_string().push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// An append-only log of changes to the cache.
+///
+/// Records are buffered in memory & only written out by `flush`, so the cost of syncing the file
+/// is paid once per batch.
+pub struct Log {
+    file: Mutex<File>,
+    pending: Mutex<BytesMut>,
+}
+
+impl Log {
+    /// starts a new log at `path` holding just `records`, replacing the one there
+    pub fn create(path: &Path, records: impl IntoIterator<Item = Record>) -> anyhow::Result<Self> {
+        write(path, records)?;
+        Ok(Self {
+            file: Mutex::new(OpenOptions::new().append(true).open(path)?),
+            pending: Mutex::new(BytesMut::new()),
+        })
+    }
+
+    /// buffers `record` until the next flush
+    pub fn append(&self, record: Record) {
+        record.encode(&mut self.pending.lock().unwrap());
+    }
+
+    /// writes out the buffered records & syncs the file
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let pending = self.pending.lock().unwrap().split();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        file.write_all(&pending)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::persist::{Log, Record, read};
+    use bytes::{Bytes, BytesMut};
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::Set {
+                key: "abhi".to_string(),
+                flags: 5,
+                exp_time: 1_700_000_000_000,
+                data: Bytes::from("ru\r\nst"),
+            },
+            Record::Delete("ash".to_string()),
+            Record::Clear,
+        ]
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut buf = BytesMut::new();
+        for record in records() {
+            record.encode(&mut buf);
+        }
+        let mut buf = &buf[..];
+        let decoded: Vec<_> = std::iter::from_fn(|| Record::decode(&mut buf)).collect();
+        assert_eq!(records(), decoded);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut buf = BytesMut::new();
+        records()[0].encode(&mut buf);
+        for len in 0..buf.len() {
+            assert_eq!(None, Record::decode(&mut &buf[..len]));
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        let dir = std::env::temp_dir().join(format!("atlas-test-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        assert!(read(&path).unwrap().is_empty());
+
+        let log = Log::create(&path, records().into_iter().take(1)).unwrap();
+        log.append(Record::Delete("ash".to_string()));
+        // nothing is written until flushed
+        assert_eq!(1, read(&path).unwrap().len());
+        log.append(Record::Clear);
+        log.flush().unwrap();
+        assert_eq!(records(), read(&path).unwrap());
+
+        // a partly written record is dropped
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend_from_slice(&[1, 0, 0, 0, 4, b'a']);
+        std::fs::write(&path, contents).unwrap();
+        assert_eq!(records(), read(&path).unwrap());
+
+        // starting afresh replaces the log
+        Log::create(&path, []).unwrap();
+        assert!(read(&path).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
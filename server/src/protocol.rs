@@ -0,0 +1,165 @@
+use bytes::Bytes;
+
+/// commands followed by a data block of the length given in their command line
+const STORAGE_COMMANDS: [&str; 6] = ["set", "add", "replace", "append", "prepend", "cas"];
+
+/// commands taking a trailing `noreply` to suppress their reply
+const NOREPLY_COMMANDS: [&str; 11] = [
+    "set",
+    "add",
+    "replace",
+    "append",
+    "prepend",
+    "cas",
+    "delete",
+    "incr",
+    "decr",
+    "touch",
+    "flush_all",
+];
+
+/// The tokens of a command line.
+///
+/// Only the shape of the line is checked here; whether the arguments make sense for the command
+/// is left to the caller.
+#[derive(Debug, PartialEq)]
+pub struct ParsedCommand<'a> {
+    /// the name of the command, like `get`
+    pub keyword: &'a str,
+    /// the tokens after the keyword, less a trailing `noreply`
+    pub args: Vec<&'a str>,
+    /// the line ended with `noreply` & the command takes one
+    pub noreply: bool,
+    /// the length of the data block following the line, for storage commands whose byte count
+    /// parses
+    pub data_len: Option<usize>,
+}
+
+/// splits a command `line`, without its `\r\n`, into its tokens
+///
+/// tokens are separated by runs of spaces, so other whitespace like a tab ends up in a token, &
+/// the line must be valid utf-8
+pub fn tokenize(line: &[u8]) -> Result<ParsedCommand<'_>, String> {
+    let line = str::from_utf8(line).map_err(|_| "command line isn't valid utf-8".to_string())?;
+    let mut tokens = line.split(' ').filter(|token| !token.is_empty());
+    let keyword = tokens.next().unwrap_or("");
+    let mut args: Vec<_> = tokens.collect();
+    let noreply = NOREPLY_COMMANDS.contains(&keyword) && args.last() == Some(&"noreply");
+    if noreply {
+        args.pop();
+    }
+    let data_len = if STORAGE_COMMANDS.contains(&keyword) {
+        args.get(3).and_then(|len| len.parse().ok())
+    } else {
+        None
+    };
+    Ok(ParsedCommand {
+        keyword,
+        args,
+        noreply,
+        data_len,
+    })
+}
+
+/// reads the data block of `len` bytes out of `data`, the bytes following the command line,
+/// provided it ends right after with `\r\n`
+pub fn read_data_block(len: usize, data: &[u8]) -> Option<Bytes> {
+    if data.len() == len + 2 && data.ends_with(b"\r\n") {
+        Some(Bytes::copy_from_slice(&data[..len]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{ParsedCommand, read_data_block, tokenize};
+    use bytes::Bytes;
+
+    fn parsed<'a>(keyword: &'a str, args: &[&'a str], noreply: bool) -> ParsedCommand<'a> {
+        ParsedCommand {
+            keyword,
+            args: args.to_vec(),
+            noreply,
+            data_len: None,
+        }
+    }
+
+    #[test]
+    fn test_storage() {
+        let command = tokenize(b"set abhi 5 100 4").unwrap();
+        assert_eq!("set", command.keyword);
+        assert_eq!(vec!["abhi", "5", "100", "4"], command.args);
+        assert_eq!(Some(4), command.data_len);
+        assert!(!command.noreply);
+
+        let command = tokenize(b"cas abhi 0 0 2 17 noreply").unwrap();
+        assert_eq!(vec!["abhi", "0", "0", "2", "17"], command.args);
+        assert_eq!(Some(2), command.data_len);
+        assert!(command.noreply);
+
+        // a byte count that doesn't parse leaves no data block to read
+        assert_eq!(None, tokenize(b"set abhi 0 0 four").unwrap().data_len);
+        assert_eq!(None, tokenize(b"set abhi 0 0").unwrap().data_len);
+    }
+
+    #[test]
+    fn test_retrieval() {
+        assert_eq!(
+            parsed("get", &["abhi"], false),
+            tokenize(b"get abhi").unwrap()
+        );
+        assert_eq!(
+            parsed("gets", &["abhi", "ash", "lilb"], false),
+            tokenize(b"gets abhi ash lilb").unwrap()
+        );
+        assert_eq!(
+            parsed("gat", &["100", "abhi", "ash"], false),
+            tokenize(b"gat 100 abhi ash").unwrap()
+        );
+        // a key named noreply is just a key
+        assert_eq!(
+            parsed("get", &["abhi", "noreply"], false),
+            tokenize(b"get abhi noreply").unwrap()
+        );
+        assert_eq!(
+            parsed("mg", &["abhi", "t", "v"], false),
+            tokenize(b"mg abhi t v").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_noreply() {
+        for (line, keyword, args) in [
+            (&b"delete abhi noreply"[..], "delete", &["abhi"][..]),
+            (b"incr abhi 5 noreply", "incr", &["abhi", "5"]),
+            (b"touch abhi 100 noreply", "touch", &["abhi", "100"]),
+            (b"flush_all noreply", "flush_all", &[]),
+            (b"flush_all 10 noreply", "flush_all", &["10"]),
+        ] {
+            assert_eq!(parsed(keyword, args, true), tokenize(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_whitespace() {
+        assert_eq!(
+            parsed("get", &["abhi", "a\tsh"], false),
+            tokenize(b"  get  abhi a\tsh ").unwrap()
+        );
+        assert_eq!(parsed("version", &[], false), tokenize(b"version").unwrap());
+        assert_eq!(parsed("", &[], false), tokenize(b"").unwrap());
+        assert!(tokenize(b"get \xff").is_err());
+    }
+
+    #[test]
+    fn test_read_data_block() {
+        assert_eq!(
+            Some(Bytes::from("ru\r\n")),
+            read_data_block(4, b"ru\r\n\r\n")
+        );
+        assert_eq!(None, read_data_block(4, b"rust"));
+        assert_eq!(None, read_data_block(4, b"rustc\r\n"));
+        assert_eq!(None, read_data_block(4, b"rustxx"));
+    }
+}
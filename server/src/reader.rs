@@ -0,0 +1,299 @@
+use crate::commands::{CommandError, DEFAULT_MAX_VALUE_SIZE, TOO_LARGE};
+use crate::protocol::tokenize;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// longest command line accepted, `\r\n` aside; a client sending a longer one is disconnected
+/// rather than buffered for without end
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// A unit of input framed off the stream.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// a command line, followed by its data block for storage commands
+    Command(Bytes),
+    /// a command line answered with the reply without being run, like one declaring a data
+    /// block too long to ever be read
+    Refused(Bytes),
+}
+
+/// Reads complete commands off a stream.
+///
+/// Bytes are accumulated across reads until a whole `\r\n` terminated command line, plus the
+/// `<bytes>\r\n` data block for storage commands, is available, so commands split across several
+/// reads or several commands arriving in one read are framed correctly.
+///
+/// A data block longer than the largest value taken is refused as soon as its command line is in
+/// & skipped as it arrives, so a client can't make the reader hold more than that.
+pub struct CommandReader<R> {
+    inner: R,
+    buf: BytesMut,
+    max_value_size: usize,
+    /// bytes of a refused data block still to be skipped
+    skip: usize,
+}
+
+impl<R: AsyncRead + Unpin> CommandReader<R> {
+    /// creates a new `CommandReader` over `inner`
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::with_capacity(1024),
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            skip: 0,
+        }
+    }
+
+    /// refuses data blocks longer than `max_value_size` bytes without buffering them
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    /// returns the next command along with its data block, or `None` once the stream is closed
+    ///
+    /// fails if the command line runs past `MAX_LINE_LEN` bytes
+    pub async fn read_command(&mut self) -> anyhow::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.buffered_command() {
+                return Ok(Some(frame));
+            }
+            if self.buf.len() > MAX_LINE_LEN + 1
+                && !self.buf[..MAX_LINE_LEN + 2]
+                    .windows(2)
+                    .any(|w| w == b"\r\n")
+            {
+                anyhow::bail!("command line longer than {} bytes", MAX_LINE_LEN);
+            }
+
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// splits the first complete command off the buffer, if there is one, without reading more
+    ///
+    /// a storage command declaring a data block too long to frame is refused as soon as its line
+    /// is in, like one whose length doesn't parse, & one declaring a block too large to store is
+    /// refused the same way with its block skipped
+    pub fn buffered_command(&mut self) -> Option<Frame> {
+        if self.skip > 0 {
+            let skipped = self.skip.min(self.buf.len());
+            self.buf.advance(skipped);
+            self.skip -= skipped;
+            if self.skip > 0 {
+                return None;
+            }
+        }
+
+        let end = self.buf.windows(2).position(|w| w == b"\r\n")?;
+        let mut len = end + 2;
+        let data = tokenize(&self.buf[..end])
+            .ok()
+            .and_then(|line| Some((line.data_len?, line.noreply)));
+        if let Some((data_len, noreply)) = data {
+            if data_len > self.max_value_size {
+                self.buf.advance(len);
+                self.skip = data_len.saturating_add(2);
+                let reply = if noreply {
+                    Bytes::new()
+                } else {
+                    Bytes::from(TOO_LARGE)
+                };
+                return Some(Frame::Refused(reply));
+            }
+            match data_len
+                .checked_add(2)
+                .and_then(|block| len.checked_add(block))
+            {
+                Some(total) => len = total,
+                None => {
+                    self.buf.advance(len);
+                    return Some(Frame::Refused(CommandError::BadFormat.reply()));
+                }
+            }
+        }
+
+        if self.buf.len() < len {
+            return None;
+        }
+        Some(Frame::Command(self.buf.split_to(len).freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{State, parse_input};
+    use crate::reader::{CommandReader, Frame, MAX_LINE_LEN};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_split_reads() {
+        let (client, server) = tokio::io::duplex(64);
+        let value = "a".repeat(4096);
+        let command = format!("set abhi 0 0 {}\r\n{}\r\n", value.len(), value);
+
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            for chunk in command.as_bytes().chunks(100) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let store = Arc::new(State::new(5));
+        let mut reader = CommandReader::new(server);
+        let Frame::Command(command) = reader.read_command().await.unwrap().unwrap() else {
+            panic!("refused");
+        };
+        let out = parse_input(&command).handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+        writer.await.unwrap();
+
+        let out = parse_input("get abhi").handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from(format!(
+                "VALUE abhi 0 {}\r\n{}\r\nEND\r\n",
+                value.len(),
+                value
+            )),
+            out
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_commands() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"set abhi 0 0 4\r\nrust\r\nget abhi\r\nver")
+            .await
+            .unwrap();
+
+        let mut reader = CommandReader::new(server);
+        assert_eq!(
+            Some(Frame::Command(Bytes::from("set abhi 0 0 4\r\nrust\r\n"))),
+            reader.read_command().await.unwrap()
+        );
+        // already buffered by the first read
+        assert_eq!(
+            Some(Frame::Command(Bytes::from("get abhi\r\n"))),
+            reader.buffered_command()
+        );
+        assert_eq!(None, reader.buffered_command());
+
+        client.write_all(b"sion\r\n").await.unwrap();
+        drop(client);
+        assert_eq!(
+            Some(Frame::Command(Bytes::from("version\r\n"))),
+            reader.read_command().await.unwrap()
+        );
+        assert_eq!(None, reader.read_command().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_data_block() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        // the data block contains spaces & a \r\n of its own
+        client
+            .write_all(b"set abhi 0 0 16\r\nrust\r\nis fun too\r\n")
+            .await
+            .unwrap();
+        client.write_all(b"get abhi\r\n").await.unwrap();
+
+        let store = Arc::new(State::new(5));
+        let mut reader = CommandReader::new(server);
+        let Frame::Command(command) = reader.read_command().await.unwrap().unwrap() else {
+            panic!("refused");
+        };
+        let out = parse_input(&command).handle(store.clone()).unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), out);
+
+        let Frame::Command(command) = reader.read_command().await.unwrap().unwrap() else {
+            panic!("refused");
+        };
+        let out = parse_input(&command).handle(store.clone()).unwrap();
+        assert_eq!(
+            Bytes::from("VALUE abhi 0 16\r\nrust\r\nis fun too\r\nEND\r\n"),
+            out
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_data_len() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"set abhi 0 0 18446744073709551615\r\nversion\r\n")
+            .await
+            .unwrap();
+
+        // with no limit on values, so the length overflows instead of being too large
+        let mut reader = CommandReader::new(server).max_value_size(usize::MAX);
+        assert_eq!(
+            Some(Frame::Refused(Bytes::from(
+                "CLIENT_ERROR bad command line format\r\n"
+            ))),
+            reader.read_command().await.unwrap()
+        );
+        // the connection is still usable
+        assert_eq!(
+            Some(Frame::Command(Bytes::from("version\r\n"))),
+            reader.read_command().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_line_too_long() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move {
+            // never ends the line; fails once the reader gives up on it
+            let chunk = [b'a'; 1024];
+            while client.write_all(&chunk).await.is_ok() {}
+        });
+
+        let mut reader = CommandReader::new(server);
+        let err = reader.read_command().await.unwrap_err();
+        assert_eq!(
+            format!("command line longer than {} bytes", MAX_LINE_LEN),
+            err.to_string()
+        );
+        drop(reader);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_data_block_too_large() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = CommandReader::new(server).max_value_size(4);
+        client.write_all(b"set abhi 0 0 4096\r\n").await.unwrap();
+        // refused before any of the block is sent
+        assert_eq!(
+            Some(Frame::Refused(Bytes::from(
+                "SERVER_ERROR object too large for cache\r\n"
+            ))),
+            reader.read_command().await.unwrap()
+        );
+
+        let writer = tokio::spawn(async move {
+            // far more than the duplex holds, so it only goes through if the reader drains it
+            client.write_all(&[b'a'; 4096]).await.unwrap();
+            client
+                .write_all(b"\r\nset ash 0 0 9 noreply\r\n123456789\r\nget ash\r\n")
+                .await
+                .unwrap();
+        });
+        // with noreply, refused without a reply
+        assert_eq!(
+            Some(Frame::Refused(Bytes::new())),
+            reader.read_command().await.unwrap()
+        );
+        assert_eq!(
+            Some(Frame::Command(Bytes::from("get ash\r\n"))),
+            reader.read_command().await.unwrap()
+        );
+        assert!(reader.buf.capacity() < 4096);
+        writer.await.unwrap();
+    }
+}
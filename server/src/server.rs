@@ -0,0 +1,1881 @@
+use crate::commands::{DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_VALUE_SIZE, DynStore, State, parse_input};
+use crate::reader::{CommandReader, Frame};
+use crate::{metrics, udp};
+use atlas::cache::{EvictingCache, LruCache, ShardedLruCache, UnboundedCache};
+use atlas::eviction::{EvictionPolicy, Lfu, Random};
+use atlas::slab::SlabAllocator;
+use bytes::Bytes;
+use clap::ValueEnum;
+use log::{debug, error, info, trace, warn};
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Runtime;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Semaphore, oneshot};
+use tokio::task::JoinSet;
+
+/// most bytes of replies gathered before being written to a connection
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// what to do with a connection accepted while the maximum number of connections are open
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OverLimit {
+    /// stop accepting until a connection closes
+    Wait,
+    /// reply with an error & close the new connection
+    Reject,
+}
+
+/// where the server stores items
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// evicts items as per the eviction policy once the cache is full
+    Lru,
+    /// never evicts; the cache limit & max bytes are ignored
+    Unbounded,
+}
+
+/// what a full cache evicts
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    /// the least recently used item
+    Lru,
+    /// the least frequently used item
+    Lfu,
+    /// an arbitrary item
+    Random,
+}
+
+/// A memcached server.
+///
+/// Settings beyond the address & the cache size default to those of the `server` binary and can
+/// be changed before calling `run` or `run_until`.
+pub struct Server {
+    addrs: Vec<String>,
+    port: u16,
+    listeners: Vec<TcpListener>,
+    cache_limit: usize,
+    max_bytes: Option<usize>,
+    backend: Backend,
+    eviction: Eviction,
+    shards: usize,
+    max_key_size: usize,
+    max_value_size: usize,
+    reap_interval: Duration,
+    persist: Option<PathBuf>,
+    persist_interval: Duration,
+    snapshot: Option<PathBuf>,
+    snapshot_interval: Duration,
+    udp_port: Option<u16>,
+    metrics_port: Option<u16>,
+    max_connections: usize,
+    over_limit: OverLimit,
+    idle_timeout: Option<Duration>,
+    nodelay: bool,
+    slabs: bool,
+    admin: bool,
+    ttl_jitter: u32,
+    ttl_jitter_extends: bool,
+    default_ttl: Option<Duration>,
+    slow_log_threshold: Option<Duration>,
+    shutdown_grace: Duration,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+}
+
+impl Server {
+    /// creates a `Server` listening on `addr`:`port` with a cache of `cache_limit` items
+    ///
+    /// `addr` may list several addresses separated by commas, ipv4 & ipv6 alike, to listen on
+    /// each of them, with ipv6 ones optionally in brackets as in `127.0.0.1,[::1]`
+    pub fn new(addr: &str, port: u16, cache_limit: usize) -> Self {
+        let addrs = addr
+            .split(',')
+            .map(|addr| addr.trim().trim_start_matches('[').trim_end_matches(']'))
+            .map(str::to_string)
+            .collect();
+        Self {
+            addrs,
+            port,
+            listeners: vec![],
+            cache_limit,
+            max_bytes: None,
+            backend: Backend::Lru,
+            eviction: Eviction::Lru,
+            shards: 1,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            reap_interval: Duration::from_secs(10),
+            persist: None,
+            persist_interval: Duration::from_secs(1),
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(300),
+            udp_port: None,
+            metrics_port: None,
+            max_connections: 1024,
+            over_limit: OverLimit::Wait,
+            idle_timeout: None,
+            nodelay: true,
+            slabs: false,
+            admin: false,
+            ttl_jitter: 0,
+            ttl_jitter_extends: false,
+            default_ttl: None,
+            slow_log_threshold: None,
+            shutdown_grace: Duration::from_secs(10),
+            #[cfg(unix)]
+            unix_socket: None,
+        }
+    }
+
+    /// bounds the cache by `max_bytes` of data instead of a number of items
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// sets where items are stored
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// sets what the cache evicts once full
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// splits an lru cache into `shards` segments, each with its own list & lock & an even share
+    /// of the cache size, so connections touching different keys don't contend
+    ///
+    /// the least recently used item of a segment is evicted rather than that of the whole cache;
+    /// only applies to lru eviction
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    /// rejects keys longer than `max_key_size` bytes
+    pub fn max_key_size(mut self, max_key_size: usize) -> Self {
+        self.max_key_size = max_key_size;
+        self
+    }
+
+    /// refuses to store values larger than `max_value_size` bytes
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    /// sets the time between sweeps removing expired items
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    /// logs every change to the file at `path`, writing out the changes every `flush_interval`
+    ///
+    /// the log is replayed on startup so items outlive a restart
+    pub fn persist(mut self, path: impl Into<PathBuf>, flush_interval: Duration) -> Self {
+        self.persist = Some(path.into());
+        self.persist_interval = flush_interval;
+        self
+    }
+
+    /// saves all the items to a snapshot at `path` every `interval`, on SIGUSR1 & on shutdown
+    ///
+    /// the snapshot is loaded on startup, before replaying any log
+    pub fn snapshot(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.snapshot = Some(path.into());
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// also serves requests over udp on `udp_port`
+    pub fn udp_port(mut self, udp_port: u16) -> Self {
+        self.udp_port = Some(udp_port);
+        self
+    }
+
+    /// serves the stats in the prometheus format at `/metrics` over http on `metrics_port`
+    pub fn metrics_port(mut self, metrics_port: u16) -> Self {
+        self.metrics_port = Some(metrics_port);
+        self
+    }
+
+    /// limits the number of connections served at once, handling the rest as per `over_limit`
+    pub fn max_connections(mut self, max_connections: usize, over_limit: OverLimit) -> Self {
+        self.max_connections = max_connections;
+        self.over_limit = over_limit;
+        self
+    }
+
+    /// closes connections that don't send a command for `idle_timeout`
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// sets `TCP_NODELAY` on accepted connections so small replies aren't held back by nagle's
+    /// algorithm; on by default
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// keeps values in slabs of size classes reused on eviction instead of allocating each one
+    pub fn slabs(mut self, slabs: bool) -> Self {
+        self.slabs = slabs;
+        self
+    }
+
+    /// answers admin commands that aren't part of memcached, like `scan`, meant for debugging
+    pub fn admin(mut self, admin: bool) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// takes up to `percent` percent off the ttl of each item stored at random, or also adds up to
+    /// as much if `extends`, so items stored together don't all expire at once
+    pub fn ttl_jitter(mut self, percent: u32, extends: bool) -> Self {
+        self.ttl_jitter = percent;
+        self.ttl_jitter_extends = extends;
+        self
+    }
+
+    /// expires the items stored with an exptime of 0 after `default_ttl` instead of never, so
+    /// nothing stays in the cache for good
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = Some(default_ttl);
+        self
+    }
+
+    /// logs the commands taking longer than `threshold` to run at warn level, with their keyword
+    pub fn slow_log_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_log_threshold = Some(threshold);
+        self
+    }
+
+    /// sets how long a shutdown waits for open connections to finish before closing them
+    pub fn shutdown_grace(mut self, shutdown_grace: Duration) -> Self {
+        self.shutdown_grace = shutdown_grace;
+        self
+    }
+
+    /// also serves clients on a unix domain socket at `path`
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// binds the tcp listeners ahead of running, returning the address the first one is bound to
+    ///
+    /// with port 0 every address gets its own port, as found by `local_addrs`
+    pub async fn bind(&mut self) -> anyhow::Result<SocketAddr> {
+        if self.listeners.is_empty() {
+            for addr in &self.addrs {
+                let listener = TcpListener::bind((addr.as_str(), self.port)).await?;
+                info!("server listening on {}", listener.local_addr()?);
+                self.listeners.push(listener);
+            }
+        }
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// returns the addresses the tcp listeners are bound to, in the order given; empty until bound
+    pub fn local_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// builds the store items are kept in
+    fn store(&self) -> DynStore {
+        match (self.backend, self.eviction, self.max_bytes) {
+            (Backend::Unbounded, ..) => Box::new(UnboundedCache::new()),
+            (Backend::Lru, Eviction::Lru, Some(max_bytes)) if self.shards > 1 => {
+                Box::new(ShardedLruCache::with_max_bytes(max_bytes, self.shards))
+            }
+            (Backend::Lru, Eviction::Lru, None) if self.shards > 1 => {
+                Box::new(ShardedLruCache::new(self.cache_limit, self.shards))
+            }
+            (Backend::Lru, Eviction::Lru, Some(max_bytes)) => {
+                Box::new(LruCache::with_max_bytes(max_bytes))
+            }
+            (Backend::Lru, Eviction::Lru, None) => Box::new(LruCache::new(self.cache_limit)),
+            (Backend::Lru, Eviction::Lfu, max_bytes) => {
+                evicting(self.cache_limit, max_bytes, Lfu::new())
+            }
+            (Backend::Lru, Eviction::Random, max_bytes) => {
+                evicting(self.cache_limit, max_bytes, Random::new())
+            }
+        }
+    }
+
+    /// serves clients until the process exits
+    pub async fn run(self) -> anyhow::Result<()> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// serves clients until `shutdown` completes
+    ///
+    /// new connections are refused from then on while open ones get up to the shutdown grace
+    /// period to finish
+    pub async fn run_until(mut self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        self.bind().await?;
+        let listeners = Listeners {
+            tcp: std::mem::take(&mut self.listeners),
+            nodelay: self.nodelay,
+            #[cfg(unix)]
+            unix: match &self.unix_socket {
+                Some(path) => Some(bind_unix(path)?),
+                None => None,
+            },
+        };
+
+        let mut state = State::with_cache(self.store())
+            .max_key_size(self.max_key_size)
+            .max_value_size(self.max_value_size)
+            .admin(self.admin)
+            .ttl_jitter(self.ttl_jitter, self.ttl_jitter_extends);
+        if let Some(default_ttl) = self.default_ttl {
+            state = state.default_ttl(default_ttl);
+        }
+        if let Some(threshold) = self.slow_log_threshold {
+            state = state.slow_log_threshold(threshold);
+        }
+        if self.slabs {
+            state = state.slabs(SlabAllocator::default());
+        }
+        if let Some(path) = &self.snapshot {
+            state.restore(path)?;
+            info!("loaded {} items from {}", state.cache.len(), path.display());
+        }
+        if let Some(path) = &self.persist {
+            state = state.persist(path)?;
+            info!(
+                "restored {} items from {}",
+                state.cache.len(),
+                path.display()
+            );
+        }
+        let state = Arc::new(state);
+        let reaper = tokio::spawn(reap(state.clone(), self.reap_interval));
+        let flusher = tokio::spawn(flush_log(state.clone(), self.persist_interval));
+        let snapshotter = match &self.snapshot {
+            Some(path) => {
+                let (map, path) = (state.clone(), path.clone());
+                let interval = self.snapshot_interval;
+                let (stop, stopped) = oneshot::channel();
+                let task = tokio::spawn(async move {
+                    if let Err(e) = snapshot(map, path, interval, stopped).await {
+                        error!("snapshots stopped: {}", e);
+                    }
+                });
+                Some((stop, task))
+            }
+            None => None,
+        };
+
+        // the udp & metrics servers listen on every address too
+        let mut servers = vec![];
+        for addr in &self.addrs {
+            if let Some(udp_port) = self.udp_port {
+                let socket = UdpSocket::bind((addr.as_str(), udp_port)).await?;
+                info!("server listening on udp {}", socket.local_addr()?);
+                let map = state.clone();
+                servers.push(tokio::spawn(async move {
+                    if let Err(e) = udp::serve(socket, map).await {
+                        error!("udp server failed: {}", e);
+                    }
+                }));
+            }
+            if let Some(metrics_port) = self.metrics_port {
+                let listener = TcpListener::bind((addr.as_str(), metrics_port)).await?;
+                info!("serving metrics on {}", listener.local_addr()?);
+                let map = state.clone();
+                servers.push(tokio::spawn(async move {
+                    if let Err(e) = metrics::serve(listener, map).await {
+                        error!("metrics server failed: {}", e);
+                    }
+                }));
+            }
+        }
+
+        let mut tasks = JoinSet::new();
+        tokio::select! {
+            _ = serve(
+                listeners,
+                state.clone(),
+                self.max_connections,
+                self.over_limit,
+                self.idle_timeout,
+                &mut tasks,
+            ) => {}
+            _ = shutdown => info!("shutting down"),
+        }
+
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.shutdown_grace, drain)
+            .await
+            .is_err()
+        {
+            warn!("closing {} connections still open", tasks.len());
+            tasks.shutdown().await;
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_socket {
+            let _ = std::fs::remove_file(path);
+        }
+
+        reaper.abort();
+        for server in servers {
+            server.abort();
+        }
+        flusher.abort();
+        state.flush_log()?;
+        if let Some((stop, task)) = snapshotter {
+            // a save already underway is let finish so it can't land after the final one
+            let _ = stop.send(());
+            let _ = task.await;
+            let path = self.snapshot.as_ref().unwrap();
+            info!(
+                "saved {} items to {}",
+                state.snapshot(path)?,
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// builds a store evicting as per `policy` once it holds `cache_limit` items or `max_bytes`
+fn evicting<P>(cache_limit: usize, max_bytes: Option<usize>, policy: P) -> DynStore
+where
+    P: EvictionPolicy<String> + Send + 'static,
+{
+    match max_bytes {
+        Some(max_bytes) => Box::new(EvictingCache::with_max_bytes(max_bytes, policy)),
+        None => Box::new(EvictingCache::new(cache_limit, policy)),
+    }
+}
+
+/// binds a unix domain socket at `path`, replacing a stale socket file left by an unclean exit
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("server listening on {}", path.display());
+    Ok(listener)
+}
+
+/// a connection accepted on any of the listeners
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Connection for S {}
+
+/// the sockets clients connect to
+struct Listeners {
+    tcp: Vec<TcpListener>,
+    nodelay: bool,
+    #[cfg(unix)]
+    unix: Option<UnixListener>,
+}
+
+impl Listeners {
+    /// accepts the next connection on any of the sockets along with a description of the peer
+    async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, String)> {
+        std::future::poll_fn(|cx| {
+            for tcp in &self.tcp {
+                if let Poll::Ready(accepted) = tcp.poll_accept(cx) {
+                    return Poll::Ready(accepted.map(|(stream, peer)| {
+                        self.tune(&stream);
+                        (Box::new(stream) as Box<dyn Connection>, peer.to_string())
+                    }));
+                }
+            }
+            #[cfg(unix)]
+            if let Some(unix) = &self.unix
+                && let Poll::Ready(accepted) = unix.poll_accept(cx)
+            {
+                return Poll::Ready(accepted.map(|(stream, _)| {
+                    (
+                        Box::new(stream) as Box<dyn Connection>,
+                        "unix socket client".to_string(),
+                    )
+                }));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// applies the socket options to a tcp connection just accepted
+    fn tune(&self, stream: &TcpStream) {
+        if let Err(e) = stream.set_nodelay(self.nodelay) {
+            warn!("couldn't set TCP_NODELAY: {}", e);
+        }
+    }
+}
+
+/// accepts connections on `listeners`, serving up to `max_connections` of them at once on `tasks`
+async fn serve(
+    listeners: Listeners,
+    map: Arc<State>,
+    max_connections: usize,
+    over_limit: OverLimit,
+    idle_timeout: Option<Duration>,
+    tasks: &mut JoinSet<()>,
+) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    map.stats.set_max_connections(max_connections);
+    // numbers the connections so the log lines of clients sharing an address can be told apart
+    let mut accepted = 0u64;
+    loop {
+        // drop the finished connections so the set doesn't grow forever
+        while tasks.try_join_next().is_some() {}
+
+        let waited = match over_limit {
+            OverLimit::Wait => Some(connections.clone().acquire_owned().await.unwrap()),
+            OverLimit::Reject => None,
+        };
+        let Ok((mut stream, peer)) = listeners.accept().await else {
+            break;
+        };
+        accepted += 1;
+        let peer = format!("#{} {}", accepted, peer);
+        let Some(permit) = waited.or_else(|| connections.clone().try_acquire_owned().ok()) else {
+            debug!("rejecting {}; too many open connections", peer);
+            tokio::spawn(async move {
+                let _ = stream
+                    .write_all(b"SERVER_ERROR too many open connections\r\n")
+                    .await;
+            });
+            continue;
+        };
+
+        let map = map.clone();
+        tasks.spawn(async move {
+            // hold the permit until the connection is done
+            let _permit = permit;
+            map.stats.connection_opened();
+            debug!("{} connected", peer);
+            match handle(stream, map.clone(), idle_timeout, &peer).await {
+                Ok(()) => debug!("{} disconnected", peer),
+                Err(e) => error!("connection with {} failed: {}", peer, e),
+            }
+            map.stats.connection_closed();
+        });
+    }
+}
+
+/// builds the runtime the server runs on, with `worker_threads` threads serving requests
+pub fn runtime(worker_threads: usize) -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+}
+
+/// removes expired items from the cache every `interval` so unread keys don't hold on to memory
+async fn reap(map: Arc<State>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        // walks every key, so it's kept off the workers serving requests
+        let map = map.clone();
+        match tokio::task::spawn_blocking(move || map.reap()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(n)) => debug!("reaped {} expired items", n),
+            Ok(Err(e)) => error!("couldn't reap expired items: {}", e),
+            Err(e) => error!("couldn't reap expired items: {}", e),
+        }
+    }
+}
+
+/// writes out the changes logged to `map` every `interval`, if persisted
+async fn flush_log(map: Arc<State>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let map = map.clone();
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || map.flush_log()).await {
+            error!("couldn't write the log: {}", e);
+        }
+    }
+}
+
+/// saves all the items in `map` to `path` every `interval` &, on unix, on SIGUSR1, until `stop`
+async fn snapshot(
+    map: Arc<State>,
+    path: PathBuf,
+    interval: Duration,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    #[cfg(unix)]
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = usr1.recv() => info!("snapshot requested"),
+            _ = &mut stop => return Ok(()),
+        }
+        #[cfg(not(unix))]
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop => return Ok(()),
+        }
+
+        let (map, path) = (map.clone(), path.clone());
+        match tokio::task::spawn_blocking(move || map.snapshot(&path)).await? {
+            Ok(n) => debug!("saved {} items to a snapshot", n),
+            Err(e) => error!("couldn't save a snapshot: {}", e),
+        }
+    }
+}
+
+/// serves commands from `stream` until the client disconnects or sends nothing for `idle_timeout`
+///
+/// the log lines about the connection start with `peer` & those about a request with its number
+/// on the connection too
+pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    map: Arc<State>,
+    idle_timeout: Option<Duration>,
+    peer: &str,
+) -> anyhow::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = CommandReader::new(reader).max_value_size(map.max_value_size);
+    // small replies are gathered up to be written together while values larger than the buffer
+    // are written straight from the cache's memory, each waiting for the client to take the last
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER, writer);
+    let mut requests = 0u64;
+
+    // the count is logged however the connection ends
+    let served = async {
+        loop {
+            let command = match idle_timeout {
+                Some(idle_timeout) => {
+                    match tokio::time::timeout(idle_timeout, reader.read_command()).await {
+                        Ok(command) => command?,
+                        Err(_) => {
+                            debug!("{} closing idle connection", peer);
+                            break;
+                        }
+                    }
+                }
+                None => reader.read_command().await?,
+            };
+            let Some(frame) = command else {
+                break;
+            };
+
+            // runs every pipelined command already read before writing all the replies at once
+            let mut frames = vec![frame];
+            frames.extend(std::iter::from_fn(|| reader.buffered_command()));
+            let first = requests + 1;
+            requests += frames.len() as u64;
+            let replies = execute_blocking(frames, map.clone(), peer.to_string(), first).await?;
+            if replies.iter().all(Bytes::is_empty) {
+                // noreply
+                continue;
+            }
+            for reply in replies {
+                writer.write_all(&reply).await?;
+            }
+            writer.flush().await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    debug!("{} served {} requests", peer, requests);
+    served
+}
+
+/// runs the commands `frames` hold, numbered from request `first` on `peer`, returning their
+/// replies & those of the refused ones in chunks
+///
+/// the cache's locks are blocking ones, so the commands run on tokio's blocking pool where
+/// waiting on a contended lock can't hold up a runtime worker & with it every other task on it.
+/// that costs a hop to another thread for every batch of pipelined commands rather than every
+/// command, which is cheaper than making every cache operation async
+async fn execute_blocking(
+    frames: Vec<Frame>,
+    map: Arc<State>,
+    peer: String,
+    first: u64,
+) -> anyhow::Result<Vec<Bytes>> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let mut replies = vec![];
+        for (request, frame) in (first..).zip(frames) {
+            let request = format!("{} request {}", peer, request);
+            match frame {
+                Frame::Command(command) => replies.extend(execute(&command, map.clone(), &request)),
+                Frame::Refused(reply) => {
+                    debug!(
+                        "{} refused: {}",
+                        request,
+                        String::from_utf8_lossy(&reply).trim_end()
+                    );
+                    replies.push(reply);
+                }
+            }
+        }
+        replies
+    })
+    .await?)
+}
+
+/// runs a single `command`, returning its reply in chunks or the error line if it failed
+///
+/// the command itself, data & all, is only logged at trace level
+fn execute(command: &[u8], map: Arc<State>, request: &str) -> Vec<Bytes> {
+    trace!(
+        "{}: {} bytes: {:?}",
+        request,
+        command.len(),
+        String::from_utf8_lossy(command)
+    );
+    let threshold = map.slow_log_threshold;
+    let start = Instant::now();
+    let reply = match parse_input(command).handle_chunked(map) {
+        Ok(reply) => reply,
+        Err(e) => {
+            debug!("{} failed: {}", request, e);
+            vec![e.reply()]
+        }
+    };
+    let elapsed = start.elapsed();
+    if threshold.is_some_and(|threshold| elapsed > threshold) {
+        let keyword = command
+            .split(u8::is_ascii_whitespace)
+            .next()
+            .unwrap_or_default();
+        warn!(
+            "{}: slow {} took {:?}",
+            request,
+            String::from_utf8_lossy(keyword),
+            elapsed
+        );
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{DynStore, Item, State, parse_input};
+    use crate::server::{Backend, Eviction, OverLimit, Server, handle, reap, runtime};
+    use atlas::cache::{Action, LruCache, Store};
+    use atlas::client::{Client, DeleteResult, GetResponse, StoreResult};
+    use atlas::cluster_client::ClusterClient;
+    use atlas::compression::{Codec, Compression};
+    use bytes::Bytes;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio::task::JoinSet;
+
+    /// serves a single connection on a free port, returning the port
+    async fn spawn_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(stream, Arc::new(State::new(5)), None, "test").await
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(stream, Arc::new(State::new(5)), None, "test").await
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"set abhi 0 0 4\r\nrust\r\nget abhi\r\n")
+            .await
+            .unwrap();
+        let expected = b"STORED\r\nVALUE abhi 0 4\r\nrust\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+
+        // a clean close ends the connection without an error
+        drop(client);
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let port = spawn_server().await;
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(b"set a 0 0 4\r\nrust\r\nset b 0 0 2\r\ngo\r\nget a\r\nget b\r\n")
+            .await
+            .unwrap();
+
+        let expected =
+            b"STORED\r\nSTORED\r\nVALUE a 0 4\r\nrust\r\nEND\r\nVALUE b 0 2\r\ngo\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+    }
+
+    #[tokio::test]
+    async fn test_noreply() {
+        let port = spawn_server().await;
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(
+                b"set abhi 0 0 4 noreply\r\nrust\r\n\
+                  set ash 0 0 2 noreply\r\ngo\r\n\
+                  set lilb 0 0 4 noreply\r\njava\r\n\
+                  get abhi ash lilb\r\n",
+            )
+            .await
+            .unwrap();
+
+        // the first bytes back are those of the get
+        let expected =
+            b"VALUE abhi 0 4\r\nrust\r\nVALUE ash 0 2\r\ngo\r\nVALUE lilb 0 4\r\njava\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_command() {
+        let port = spawn_server().await;
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"set abhi 0\r\n").await.unwrap();
+        let mut buf = vec![0; 7];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ERROR\r\n", &buf[..]);
+
+        // the connection is still usable
+        client
+            .write_all(b"set abhi 0 0 4\r\nrust\r\nget abhi\r\n")
+            .await
+            .unwrap();
+        let expected = b"STORED\r\nVALUE abhi 0 4\r\nrust\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+    }
+
+    #[tokio::test]
+    async fn test_backend() {
+        for (backend, kept) in [(Backend::Lru, 2), (Backend::Unbounded, 3)] {
+            let mut server = Server::new("127.0.0.1", 0, 2).backend(backend);
+            let addr = server.bind().await.unwrap();
+            tokio::spawn(server.run());
+
+            let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+            for key in ["abhi", "ash", "lilb"] {
+                client.set(key, 0, 0, b"rust").await.unwrap();
+            }
+            let mut found = 0;
+            for key in ["abhi", "ash", "lilb"] {
+                if client.get(key).await.unwrap().is_some() {
+                    found += 1;
+                }
+            }
+            assert_eq!(kept, found, "{:?}", backend);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_no_starvation() {
+        // connections pipelining thousands of sets keep the cache busy & its locks contended
+        let requests: Vec<Vec<u8>> = (0..4)
+            .map(|c| {
+                (0..20000)
+                    .flat_map(|i| format!("set key{}-{} 0 0 4\r\nrust\r\n", c, i).into_bytes())
+                    .collect()
+            })
+            .collect();
+
+        // ticks every millisecond until stopped, returning the longest it went without ticking
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let heartbeat = tokio::spawn(async move {
+            let mut longest = Duration::ZERO;
+            let mut last = Instant::now();
+            while stopped.try_recv().is_err() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                longest = longest.max(last.elapsed());
+                last = Instant::now();
+            }
+            longest
+        });
+
+        let map = Arc::new(State::new(100));
+        let mut connections = JoinSet::new();
+        for requests in requests {
+            let (mut client, server) = tokio::io::duplex(1 << 20);
+            tokio::spawn(handle(server, map.clone(), None, "test"));
+            connections.spawn(async move {
+                client.write_all(&requests).await.unwrap();
+                let mut replies = vec![0; 20000 * 8];
+                client.read_exact(&mut replies).await.unwrap();
+                assert!(replies.chunks(8).all(|reply| reply == b"STORED\r\n"));
+            });
+        }
+        while let Some(connection) = connections.join_next().await {
+            connection.unwrap();
+        }
+
+        stop.send(()).unwrap();
+        let longest = heartbeat.await.unwrap();
+        assert!(
+            longest < Duration::from_millis(50),
+            "heartbeat stalled for {:?}",
+            longest
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gets() {
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        client.set("abhi", 42, 0, b"rust").await.unwrap();
+        let GetResponse::Hit { value, flags, cas } = client.gets("abhi").await.unwrap() else {
+            panic!("abhi is stored");
+        };
+        assert_eq!((Bytes::from("rust"), 42), (value, flags));
+
+        // storing anew changes the cas token
+        client.set("abhi", 0, 0, b"go").await.unwrap();
+        let GetResponse::Hit { cas: next, .. } = client.gets("abhi").await.unwrap() else {
+            panic!("abhi is stored");
+        };
+        assert!(cas.is_some() && next.is_some() && cas != next);
+        assert_eq!(GetResponse::Miss, client.gets("ash").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let value = "rust ".repeat(10_000).into_bytes();
+        let mut plain = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let mut client = Client::new("127.0.0.1", addr.port())
+                .await
+                .unwrap()
+                .compression(Compression::new(codec));
+            client.set("abhi", 42, 0, &value).await.unwrap();
+            assert_eq!(
+                Some(Bytes::from(value.clone())),
+                client.get("abhi").await.unwrap()
+            );
+            assert!(matches!(
+                client.gets("abhi").await.unwrap(),
+                GetResponse::Hit { flags: 42, .. }
+            ));
+
+            // the server holds the compressed value
+            let GetResponse::Hit {
+                value: stored,
+                flags,
+                ..
+            } = plain.gets("abhi").await.unwrap()
+            else {
+                panic!("abhi isn't stored");
+            };
+            assert_eq!(42 | codec.flag(), flags);
+            assert!(stored.len() < value.len() / 10, "{:?}", codec);
+            let stats = plain.command(b"stats\r\n").await.unwrap();
+            let stats = String::from_utf8_lossy(&stats);
+            assert!(
+                stats.contains(&format!("STAT bytes {}\r\n", stored.len())),
+                "{}",
+                stats
+            );
+
+            // small values are stored as they are
+            client.set("ash", 0, 0, b"go").await.unwrap();
+            assert_eq!(Some(Bytes::from("go")), plain.get("ash").await.unwrap());
+            plain.delete("ash").await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct User {
+            name: String,
+            langs: Vec<String>,
+            bio: String,
+        }
+
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        let user = User {
+            name: "abhi".to_string(),
+            langs: vec!["rust".to_string(), "go".to_string()],
+            bio: "writes caches\r\nEND\r\n".to_string(),
+        };
+        assert_eq!(
+            StoreResult::Stored,
+            client.set_json("abhi", &user, 0).await.unwrap()
+        );
+        assert_eq!(Some(user), client.get_json("abhi").await.unwrap());
+        assert_eq!(None, client.get_json::<User>("ash").await.unwrap());
+
+        // a value that isn't the json of the type asked for is an error
+        client.set("ash", 0, 0, b"rust").await.unwrap();
+        assert!(client.get_json::<User>("ash").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shards() {
+        let mut server = Server::new("127.0.0.1", 0, 8).shards(4);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        let keys: Vec<_> = (0..32).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            client.set(key, 0, 0, b"rust").await.unwrap();
+        }
+        // the segments evict on their own but never hold more than the cache limit between them
+        let mut found = 0;
+        for key in &keys {
+            if client.get(key).await.unwrap().is_some() {
+                found += 1;
+            }
+        }
+        assert!((1..=8).contains(&found), "{} kept", found);
+        assert!(client.get("key31").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        // abhi is used more often but ash more recently
+        for (eviction, evicted) in [(Eviction::Lru, "abhi"), (Eviction::Lfu, "ash")] {
+            let mut server = Server::new("127.0.0.1", 0, 2).eviction(eviction);
+            let addr = server.bind().await.unwrap();
+            tokio::spawn(server.run());
+
+            let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+            client.set("abhi", 0, 0, b"rust").await.unwrap();
+            client.set("ash", 0, 0, b"go").await.unwrap();
+            for key in ["abhi", "abhi", "ash"] {
+                client.get(key).await.unwrap();
+            }
+            client.set("lilb", 0, 0, b"java").await.unwrap();
+            assert_eq!(None, client.get(evicted).await.unwrap(), "{:?}", eviction);
+            assert!(client.get("lilb").await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject_over_limit() {
+        let mut server = Server::new("127.0.0.1", 0, 5).max_connections(1, OverLimit::Reject);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let mut first = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        assert_eq!(None, first.get("abhi").await.unwrap());
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut buf = vec![];
+        tokio::time::timeout(Duration::from_secs(1), second.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"SERVER_ERROR too many open connections\r\n", &buf[..]);
+
+        // the first connection is unaffected & frees its slot when closed
+        assert_eq!(None, first.get("abhi").await.unwrap());
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        assert_eq!(None, third.get("abhi").await.unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("atlas-{}.sock", std::process::id()));
+        // left over from a previous run
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let mut server = Server::new("127.0.0.1", 0, 5).unix_socket(&path);
+        server.bind().await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = rx.await;
+        }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut client = Client::from_stream(stream);
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("rust")), client.get("abhi").await.unwrap());
+
+        drop(client);
+        tx.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_addrs() {
+        let mut server = Server::new("127.0.0.1, [::1]", 0, 5);
+        let first = server.bind().await.unwrap();
+        let addrs = server.local_addrs().unwrap();
+        assert_eq!(2, addrs.len());
+        assert_eq!(first, addrs[0]);
+        assert!(addrs[0].is_ipv4());
+        assert!(addrs[1].is_ipv6());
+        tokio::spawn(server.run());
+
+        // both listeners feed the same cache
+        let mut v4 = Client::new("127.0.0.1", addrs[0].port()).await.unwrap();
+        let mut v6 = Client::new("::1", addrs[1].port()).await.unwrap();
+        assert_eq!(
+            StoreResult::Stored,
+            v4.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("rust")), v6.get("abhi").await.unwrap());
+        assert_eq!(
+            StoreResult::Stored,
+            v6.set("ash", 0, 0, b"go").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("go")), v4.get("ash").await.unwrap());
+    }
+
+    /// runs `server` until `f` is done with a client connected to it
+    async fn run_with_client<F, Fut>(mut server: Server, f: F)
+    where
+        F: FnOnce(Client<TcpStream>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let addr = server.bind().await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = rx.await;
+        }));
+
+        f(Client::new("127.0.0.1", addr.port()).await.unwrap()).await;
+        tx.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist() {
+        let dir = std::env::temp_dir().join(format!("atlas-test-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let server = || Server::new("127.0.0.1", 0, 5).persist(&path, Duration::from_secs(60));
+
+        run_with_client(server(), |mut client| async move {
+            for key in ["abhi", "ash", "lilb"] {
+                client.set(key, 0, 0, b"rust").await.unwrap();
+            }
+            client.set("abhi", 3, 0, b"go").await.unwrap();
+            client.delete("ash").await.unwrap();
+            // an absolute time long past
+            client.set("lilb", 0, 2592001, b"rust").await.unwrap();
+            client.set("nish", 0, 0, b"zig").await.unwrap();
+        })
+        .await;
+
+        // the changes were written out on shutdown even though the flush interval hadn't passed
+        run_with_client(server(), |mut client| async move {
+            assert_eq!(Some(Bytes::from("go")), client.get("abhi").await.unwrap());
+            assert_eq!(None, client.get("ash").await.unwrap());
+            assert_eq!(None, client.get("lilb").await.unwrap());
+            assert_eq!(Some(Bytes::from("zig")), client.get("nish").await.unwrap());
+        })
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let dir = std::env::temp_dir().join(format!("atlas-test-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot");
+        let server = |interval| Server::new("127.0.0.1", 0, 5).snapshot(&path, interval);
+
+        let saved = path.clone();
+        run_with_client(server(Duration::from_millis(50)), |mut client| async move {
+            client.set("abhi", 5, 100, b"rust").await.unwrap();
+            client.set("ash", 0, 0, b"go").await.unwrap();
+            client.set("lilb", 0, 1, b"zig").await.unwrap();
+            // saved on the interval while running
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(3, crate::persist::read(&saved).unwrap().len());
+            // lilb expires before the snapshot taken on shutdown
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+        })
+        .await;
+        assert_eq!(2, crate::persist::read(&path).unwrap().len());
+
+        let mut server = server(Duration::from_secs(60));
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"mg abhi t f v\r\nmg ash t v\r\nmg lilb v\r\n")
+            .await
+            .unwrap();
+        let expected = b"VA 4 t99 f5\r\nrust\r\nVA 2 t-1\r\ngo\r\nEN\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&buf)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_curr_connections() {
+        let mut server = Server::new("127.0.0.1", 0, 5).max_connections(10, OverLimit::Reject);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+        let mut clients = vec![];
+        for _ in 0..4 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        /// gets the connection stats as seen by `client`
+        async fn connections(client: &mut TcpStream) -> Vec<String> {
+            client.write_all(b"stats\r\n").await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = client.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..n])
+                .lines()
+                .filter(|line| line.contains("_connections "))
+                .map(str::to_string)
+                .collect()
+        }
+        assert_eq!(
+            vec![
+                "STAT curr_connections 4",
+                "STAT total_connections 4",
+                "STAT max_connections 10"
+            ],
+            connections(&mut clients[0]).await
+        );
+
+        clients.truncate(1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the total counts the closed ones too
+        assert_eq!(
+            vec![
+                "STAT curr_connections 1",
+                "STAT total_connections 4",
+                "STAT max_connections 10"
+            ],
+            connections(&mut clients[0]).await
+        );
+    }
+
+    #[test]
+    fn test_runtime() {
+        let rt = runtime(3).unwrap();
+        assert_eq!(3, rt.metrics().num_workers());
+
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let version = rt.block_on(async {
+            let addr = server.bind().await.unwrap();
+            tokio::spawn(server.run());
+            let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+            client.version().await.unwrap()
+        });
+        assert_eq!(env!("CARGO_PKG_VERSION"), version);
+    }
+
+    #[tokio::test]
+    async fn test_large_multi_get() {
+        // far smaller than the reply, so writing it has to wait on the client reading
+        let (mut client, server) = tokio::io::duplex(4096);
+        let state = Arc::new(State::new(50));
+        let server = tokio::spawn(handle(server, state.clone(), None, "test"));
+
+        let mut expected = vec![];
+        let mut get = b"get".to_vec();
+        for i in 0..32 {
+            let key = format!("key{}", i);
+            let value: Vec<u8> = (0..200_000).map(|j| (i + j) as u8).collect();
+            client
+                .write_all(format!("set {} 0 0 {}\r\n", key, value.len()).as_bytes())
+                .await
+                .unwrap();
+            client.write_all(&value).await.unwrap();
+            client.write_all(b"\r\n").await.unwrap();
+            let mut stored = [0; 8];
+            client.read_exact(&mut stored).await.unwrap();
+            assert_eq!(b"STORED\r\n", &stored);
+
+            expected.extend_from_slice(format!("VALUE {} 0 {}\r\n", key, value.len()).as_bytes());
+            expected.extend_from_slice(&value);
+            expected.extend_from_slice(b"\r\n");
+            get.extend_from_slice(format!(" {}", key).as_bytes());
+        }
+        expected.extend_from_slice(b"END\r\n");
+        get.extend_from_slice(b"\r\n");
+
+        client.write_all(&get).await.unwrap();
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(expected == buf);
+
+        // the reply is written straight from the cached values, so no buffer holds more than one
+        let chunks = parse_input(&get).handle_chunked(state.clone()).unwrap();
+        assert_eq!(200_000, chunks.iter().map(Bytes::len).max().unwrap());
+        for i in 0..32 {
+            let cached = state.cache.peek(&format!("key{}", i)).unwrap();
+            assert!(
+                chunks
+                    .iter()
+                    .any(|chunk| chunk.as_ptr() == cached.data.as_ptr())
+            );
+        }
+
+        drop(client);
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_value_too_large() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let state = State::new(5).max_value_size(1024);
+        let server = tokio::spawn(handle(server, Arc::new(state), None, "test"));
+
+        // answered on the command line alone, without the 2 GB block ever being sent
+        client
+            .write_all(b"set abhi 0 0 2000000000\r\n")
+            .await
+            .unwrap();
+        let expected = b"SERVER_ERROR object too large for cache\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+
+        drop(client);
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_duplex() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(handle(server, Arc::new(State::new(5)), None, "test"));
+
+        client
+            .write_all(b"set abhi 0 0 4\r\nrust\r\nget abhi\r\n")
+            .await
+            .unwrap();
+        let expected = b"STORED\r\nVALUE abhi 0 4\r\nrust\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+
+        drop(client);
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    /// keeps every log record so tests can check what was logged
+    struct CaptureLogger(Mutex<Vec<(Level, String)>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let line = record.args().to_string();
+            self.0.lock().unwrap().push((record.level(), line));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(vec![]));
+
+    #[tokio::test]
+    async fn test_logging() {
+        // other tests log too, so only the lines about this connection are looked at
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(handle(server, Arc::new(State::new(5)), None, "#7 logged"));
+        client
+            .write_all(b"set abhi 0 0 6\r\nsecret\r\nbogus\r\n")
+            .await
+            .unwrap();
+        let expected = b"STORED\r\nERROR\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let logs: Vec<_> = LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, line)| line.starts_with("#7 logged"))
+            .cloned()
+            .collect();
+        assert!(logs.contains(&(
+            Level::Trace,
+            "#7 logged request 1: 24 bytes: \"set abhi 0 0 6\\r\\nsecret\\r\\n\"".to_string()
+        )));
+        assert!(logs.iter().any(|(level, line)| *level == Level::Debug
+            && line.starts_with("#7 logged request 2 failed")));
+        // the data only shows up at trace level
+        for (level, line) in &logs {
+            assert!(
+                *level == Level::Trace || !line.contains("secret"),
+                "{}",
+                line
+            );
+        }
+    }
+
+    /// a cache taking `delay` to get the key "slow"
+    struct SlowStore {
+        cache: LruCache<String, Item>,
+        delay: Duration,
+    }
+
+    impl Store<String, Item> for SlowStore {
+        fn insert(&self, k: String, v: Item) -> Vec<(String, Item)> {
+            self.cache.insert(k, v)
+        }
+
+        fn get(&self, k: &String) -> Option<Item> {
+            if k == "slow" {
+                std::thread::sleep(self.delay);
+            }
+            self.cache.get(k)
+        }
+
+        fn peek(&self, k: &String) -> Option<Item> {
+            self.cache.peek(k)
+        }
+
+        fn remove(&self, k: &String) -> Option<Item> {
+            self.cache.remove(k)
+        }
+
+        fn keys(&self) -> Vec<String> {
+            self.cache.keys()
+        }
+
+        fn clear(&self) -> Vec<(String, Item)> {
+            self.cache.clear()
+        }
+
+        fn len(&self) -> usize {
+            self.cache.len()
+        }
+
+        fn with_entry(
+            &self,
+            k: String,
+            f: &mut dyn FnMut(Option<&mut Item>) -> Action<Item>,
+        ) -> Vec<(String, Item)> {
+            self.cache.with_entry(k, f)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_log() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+
+        let cache = SlowStore {
+            cache: LruCache::new(5),
+            delay: Duration::from_millis(200),
+        };
+        let state = State::with_cache(Box::new(cache) as DynStore)
+            .slow_log_threshold(Duration::from_millis(100));
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(handle(server, Arc::new(state), None, "#8 slow"));
+        client
+            .write_all(b"set slow 0 0 4\r\nrust\r\nget abhi\r\nget slow\r\n")
+            .await
+            .unwrap();
+        let expected = b"STORED\r\nEND\r\nVALUE slow 0 4\r\nrust\r\nEND\r\n";
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&expected[..], &buf[..]);
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let logs: Vec<_> = LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, line)| line.starts_with("#8 slow"))
+            .cloned()
+            .collect();
+        // only the get of the slow key is logged, with the command's keyword but not its key
+        let slow: Vec<_> = logs
+            .iter()
+            .filter(|(level, _)| *level == Level::Warn)
+            .collect();
+        assert_eq!(1, slow.len(), "{:?}", logs);
+        assert!(slow[0].1.starts_with("#8 slow request 3: slow get took "));
+        assert!(logs.contains(&(Level::Debug, "#8 slow served 3 requests".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let idle_timeout = Some(Duration::from_millis(100));
+            handle(stream, Arc::new(State::new(5)), idle_timeout, "test").await
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = vec![];
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf.is_empty());
+        // an idle close isn't an error
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_run_until() {
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let addr = server.bind().await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = rx.await;
+        }));
+
+        let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("rust")), client.get("abhi").await.unwrap());
+
+        drop(client);
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // no longer accepting
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_mid_request() {
+        let mut server = Server::new("127.0.0.1", 0, 5);
+        let addr = server.bind().await.unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = rx.await;
+        }));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"set abhi 0 0 4\r\nru").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+
+        // the request started before the shutdown is still served
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"st\r\n").await.unwrap();
+        let mut buf = vec![0; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"STORED\r\n", &buf[..]);
+        assert!(!running.is_finished());
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_value() {
+        let port = spawn_server().await;
+        // larger than a typical socket buffer so the reply can't go out in a single write
+        let value = "a".repeat(256 * 1024);
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, value.as_bytes()).await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from(value)), client.get("abhi").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_binary_value() {
+        let port = spawn_server().await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        let value = b"\x00rust\xff\r\n\xfe\x00";
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, value).await.unwrap()
+        );
+        assert_eq!(
+            Some(Bytes::from_static(value)),
+            client.get("abhi").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reap() {
+        let map = Arc::new(State::new(5));
+        parse_input("set abhi 0 -1 4\r\nrust\r\n")
+            .handle(map.clone())
+            .unwrap();
+        parse_input("set lilb 0 0 4\r\n.net\r\n")
+            .handle(map.clone())
+            .unwrap();
+        let reaper = tokio::spawn(reap(map.clone(), Duration::from_millis(10)));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // removed without ever being read
+        assert!(map.cache.peek(&"abhi".to_string()).is_none());
+        assert!(map.cache.peek(&"lilb".to_string()).is_some());
+        reaper.abort();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_version() {
+        let ports = [spawn_server().await, spawn_server().await];
+        let cluster: Vec<_> = ports.iter().map(|&port| ("127.0.0.1", port)).collect();
+        let client = ClusterClient::new(&cluster, 1);
+        assert_eq!(env!("CARGO_PKG_VERSION"), client.version().await.unwrap());
+
+        let versions = client.versions().await.unwrap();
+        assert_eq!(2, versions.len());
+        for port in ports {
+            assert_eq!(
+                env!("CARGO_PKG_VERSION"),
+                versions[&("127.0.0.1".to_string(), port)]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let port = spawn_server().await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        client.ping().await.unwrap();
+
+        let port = spawn_server().await;
+        let cluster = ClusterClient::new(&[("127.0.0.1", port)], 1);
+        assert_eq!(
+            Some(&true),
+            cluster
+                .ping_all()
+                .await
+                .get(&("127.0.0.1".to_string(), port))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnect() {
+        /// runs a server on `port` until told to stop, cutting off open connections at once
+        async fn start(port: u16) -> (u16, oneshot::Sender<()>) {
+            let mut server = Server::new("127.0.0.1", port, 5).shutdown_grace(Duration::ZERO);
+            let port = server.bind().await.unwrap().port();
+            let (stop, stopped) = oneshot::channel();
+            tokio::spawn(server.run_until(async {
+                let _ = stopped.await;
+            }));
+            (port, stop)
+        }
+
+        let (port, stop) = start(0).await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("rust")), client.get("abhi").await.unwrap());
+
+        // restarted with an empty cache
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, stop) = start(port).await;
+        assert_eq!(None, client.get("abhi").await.unwrap());
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+
+        // & again, with a set being the first request to find the connection closed
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, mut stop) = start(port).await;
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("ash", 0, 0, b"go").await.unwrap()
+        );
+        assert_eq!(Some(Bytes::from("go")), client.get("ash").await.unwrap());
+
+        // & with each of the other requests being the first to find it closed
+        for request in 0..4 {
+            stop.send(()).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stop = start(port).await.1;
+            match request {
+                0 => assert_eq!(DeleteResult::NotFound, client.delete("ash").await.unwrap()),
+                1 => assert_eq!(env!("CARGO_PKG_VERSION"), client.version().await.unwrap()),
+                2 => client.ping().await.unwrap(),
+                _ => assert_eq!(
+                    Bytes::from("PONG\r\n"),
+                    client.command(b"ping\r\n").await.unwrap()
+                ),
+            }
+        }
+
+        // a client over a stream it didn't open can't connect again
+        let (port, stop) = start(0).await;
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut client = Client::from_stream(stream);
+        client.get("abhi").await.unwrap();
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _restarted = start(port).await;
+        assert!(client.get("abhi").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_command() {
+        let port = spawn_server().await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        let reply = client.command(b"version\r\n").await.unwrap();
+        assert_eq!(
+            format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+            String::from_utf8_lossy(&reply)
+        );
+
+        let reply = client.command(b"set abhi 0 0 4\r\nrust\r\n").await.unwrap();
+        assert_eq!(Bytes::from("STORED\r\n"), reply);
+        let reply = client.command(b"gets abhi\r\n").await.unwrap();
+        assert_eq!(
+            Some(Bytes::from("rust")),
+            GetResponse::parse(&reply).unwrap().into_value()
+        );
+        let reply = client.command(b"frobnicate\r\n").await.unwrap();
+        assert_eq!(Bytes::from("ERROR\r\n"), reply);
+
+        // a meta get's value is read whole, leaving nothing behind for the next reply
+        let reply = client.command(b"mg abhi v f\r\n").await.unwrap();
+        assert_eq!(Bytes::from("VA 4 f0\r\nrust\r\n"), reply);
+        let reply = client.command(b"mg abhi\r\n").await.unwrap();
+        assert_eq!(Bytes::from("HD\r\n"), reply);
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let mut server = Server::new("127.0.0.1", 0, 5).admin(true);
+        let addr = server.bind().await.unwrap();
+        tokio::spawn(server.run());
+
+        let mut client = Client::new("127.0.0.1", addr.port()).await.unwrap();
+        for key in ["abhi", "ash", "lilb"] {
+            client.set(key, 0, 0, b"rust").await.unwrap();
+        }
+        assert_eq!(
+            Bytes::from("KEY abhi\r\nKEY ash\r\nEND\r\n"),
+            client.command(b"scan a\r\n").await.unwrap()
+        );
+        // the reply was read whole, leaving nothing behind for the next one
+        assert_eq!(
+            Bytes::from("VALUE lilb 0 4\r\nrust\r\nEND\r\n"),
+            client.command(b"get lilb\r\n").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_delete() {
+        let port = spawn_server().await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), client.version().await.unwrap());
+        assert_eq!(
+            StoreResult::Stored,
+            client.set("abhi", 0, 0, b"rust").await.unwrap()
+        );
+        assert_eq!(DeleteResult::Deleted, client.delete("abhi").await.unwrap());
+        assert_eq!(None, client.get("abhi").await.unwrap());
+        assert_eq!(DeleteResult::NotFound, client.delete("abhi").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_client_values() {
+        let port = spawn_server().await;
+        let mut client = Client::new("127.0.0.1", port).await.unwrap();
+        assert_eq!(None, client.get("abhi").await.unwrap());
+
+        // values that look like protocol replies are still values
+        for value in [&b"NOT_FOUND\r\n"[..], b"\x00\x01\r\nEND\r\n", b""] {
+            assert_eq!(
+                StoreResult::Stored,
+                client.set("abhi", 0, 0, value).await.unwrap()
+            );
+            assert_eq!(
+                Some(Bytes::copy_from_slice(value)),
+                client.get("abhi").await.unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::commands::{State, parse_input};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, warn};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// size of the frame header in front of every datagram
+const HEADER_LEN: usize = 8;
+
+/// largest payload sent in one response datagram, keeping it under a typical mtu
+const MAX_PAYLOAD: usize = 1400;
+
+/// serves requests arriving as datagrams on `socket`
+///
+/// every datagram starts with an 8 byte header holding a request id, a sequence number, the total
+/// number of datagrams in the message & 2 reserved bytes, all big endian. Requests must fit in a
+/// single datagram; replies are split across as many as needed.
+pub async fn serve(socket: UdpSocket, map: Arc<State>) -> anyhow::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf).await?;
+        let Some((request_id, request)) = parse_request(&buf[..size]) else {
+            debug!("dropping malformed datagram from {}", peer);
+            continue;
+        };
+
+        // on the blocking pool like tcp commands, so a contended cache lock can't stall a worker
+        let (request, map) = (Bytes::copy_from_slice(request), map.clone());
+        let reply = tokio::task::spawn_blocking(move || match parse_input(request).handle(map) {
+            Ok(reply) => reply,
+            Err(e) => {
+                debug!("request from {} failed: {}", peer, e);
+                e.reply()
+            }
+        })
+        .await;
+        // one request going wrong mustn't stop the others from being served
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("request from {} failed: {}", peer, e);
+                continue;
+            }
+        };
+        if reply.is_empty() {
+            // noreply
+            continue;
+        }
+        for datagram in frame(request_id, &reply) {
+            if let Err(e) = socket.send_to(&datagram, peer).await {
+                warn!("couldn't reply to {}: {}", peer, e);
+                break;
+            }
+        }
+    }
+}
+
+/// splits a datagram into its request id & the request, if it is a complete single datagram
+/// request
+fn parse_request(datagram: &[u8]) -> Option<(u16, &[u8])> {
+    if datagram.len() < HEADER_LEN {
+        return None;
+    }
+    let request_id = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let sequence = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let total = u16::from_be_bytes([datagram[4], datagram[5]]);
+    if sequence != 0 || total != 1 {
+        return None;
+    }
+    Some((request_id, &datagram[HEADER_LEN..]))
+}
+
+/// splits `reply` into datagrams, each prefixed with a header for `request_id`
+fn frame(request_id: u16, reply: &[u8]) -> Vec<Bytes> {
+    let chunks: Vec<&[u8]> = if reply.is_empty() {
+        vec![reply]
+    } else {
+        reply.chunks(MAX_PAYLOAD).collect()
+    };
+    let total = chunks.len() as u16;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(sequence, chunk)| {
+            let mut datagram = BytesMut::with_capacity(HEADER_LEN + chunk.len());
+            datagram.put_u16(request_id);
+            datagram.put_u16(sequence as u16);
+            datagram.put_u16(total);
+            datagram.put_u16(0);
+            datagram.extend_from_slice(chunk);
+            datagram.freeze()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::{State, parse_input};
+    use crate::udp::{HEADER_LEN, MAX_PAYLOAD, frame, serve};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_frame() {
+        let reply = vec![b'a'; MAX_PAYLOAD * 2 + 1];
+        let datagrams = frame(7, &reply);
+        assert_eq!(3, datagrams.len());
+        for (i, datagram) in datagrams.iter().enumerate() {
+            assert_eq!(&[0, 7, 0, i as u8, 0, 3, 0, 0], &datagram[..HEADER_LEN]);
+        }
+        assert_eq!(1, datagrams[2].len() - HEADER_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let map = Arc::new(State::new(5));
+        parse_input("set abhi 0 0 4\r\nrust\r\n")
+            .handle(map.clone())
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(serve(socket, map));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0];
+        request.extend_from_slice(b"get abhi\r\n");
+        client.send_to(&request, addr).await.unwrap();
+
+        let mut buf = vec![0; 2048];
+        let size = client.recv(&mut buf).await.unwrap();
+        // same request id, first & only datagram
+        assert_eq!(&[0x12, 0x34, 0, 0, 0, 1, 0, 0], &buf[..HEADER_LEN]);
+        assert_eq!(b"VALUE abhi 0 4\r\nrust\r\nEND\r\n", &buf[HEADER_LEN..size]);
+        server.abort();
+    }
+}